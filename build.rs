@@ -1,4 +1,21 @@
+use std::process::Command;
+
 fn main() {
+    // Short commit hash, reported as part of the firmware version
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    // Refs move here on `git gc` and fetches
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+
     #[cfg(feature = "esp32")]
     embuild::espidf::sysenv::output();
 }
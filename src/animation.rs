@@ -0,0 +1,286 @@
+use crate::history::History;
+use smart_leds::RGB8;
+use std::time::Duration;
+
+/// How long the strip takes to slide along one position for a new sample
+const SCROLL_TIME: Duration = Duration::from_millis(600);
+const BREATHE_PERIOD: Duration = Duration::from_millis(3000);
+const ALERT_PERIOD: Duration = Duration::from_millis(1000);
+const ALERT_COLOUR: RGB8 = RGB8::new(255, 0, 0);
+/// How long the strip sparkles for after an outage ends
+const SPARKLE_TIME: Duration = Duration::from_millis(2500);
+/// How long each twinkle lasts
+const SPARKLE_STEP: Duration = Duration::from_millis(100);
+const SPARKLE_COLOUR: RGB8 = RGB8::new(255, 255, 255);
+
+/// Animations on top of the history, selected as the light's effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// No animation: the strip only changes when a sample arrives
+    Static,
+    /// The newest LED slowly pulses
+    Breathe,
+    /// New samples slide in, pushing the older ones one position along,
+    /// rather than jumping into place
+    Scroll,
+    /// An ongoing outage pulses red
+    Alert,
+    /// The strip sparkles when an outage ends
+    Sparkle,
+    /// All of the above
+    Animated,
+}
+
+impl Effect {
+    pub const ALL: [Effect; 6] = [
+        Effect::Static,
+        Effect::Breathe,
+        Effect::Scroll,
+        Effect::Alert,
+        Effect::Sparkle,
+        Effect::Animated,
+    ];
+
+    /// Name used for the Home Assistant effect list
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Static => "static",
+            Effect::Breathe => "breathe",
+            Effect::Scroll => "scroll",
+            Effect::Alert => "alert",
+            Effect::Sparkle => "sparkle",
+            Effect::Animated => "animated",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|e| e.name() == name)
+    }
+
+    fn breathes(self) -> bool {
+        matches!(self, Effect::Breathe | Effect::Animated)
+    }
+
+    fn scrolls(self) -> bool {
+        matches!(self, Effect::Scroll | Effect::Animated)
+    }
+
+    fn alerts(self) -> bool {
+        matches!(self, Effect::Alert | Effect::Animated)
+    }
+
+    fn sparkles(self) -> bool {
+        matches!(self, Effect::Sparkle | Effect::Animated)
+    }
+}
+
+/// 0 at the start of each period, rising smoothly to 1 halfway and back
+fn wave(now: Duration, period: Duration) -> f32 {
+    let phase = (now.as_millis() % period.as_millis()) as f32 / period.as_millis() as f32;
+    let triangle = 1.0 - (2.0 * phase - 1.0).abs();
+    triangle * triangle * (3.0 - 2.0 * triangle)
+}
+
+/// `from` to `to`, `t` of the way (0-1)
+fn blend(from: RGB8, to: RGB8, t: f32) -> RGB8 {
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    RGB8::new(lerp(from.r, to.r), lerp(from.g, to.g), lerp(from.b, to.b))
+}
+
+/// A cheap, well mixed hash, to pick which LEDs twinkle
+fn hash(a: u32, b: u32) -> u32 {
+    let mut x = a.wrapping_mul(0x9e37_79b9) ^ b.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x
+}
+
+/// Turns the strip colours, which change once a sample, into frames. All
+/// effects are worked out from the time given, so they run at the same
+/// speed whatever the frame rate.
+#[derive(Debug, Clone, Default)]
+pub struct Animator {
+    /// The colours to show
+    target: Vec<RGB8>,
+    /// The colours before the newest sample arrived, to scroll from
+    previous: Vec<RGB8>,
+    /// When the newest sample arrived
+    shifted_at: Option<Duration>,
+    /// When the newest sample was taken, to spot new ones
+    latest_at: Option<Duration>,
+    /// Number of samples lost in a row, up to the newest
+    outage: usize,
+    /// When the last outage ended
+    recovered_at: Option<Duration>,
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the colours to show (see `rgb::strip_pixels`) for `history`
+    pub fn update(&mut self, now: Duration, pixels: Vec<RGB8>, history: &History) {
+        let latest = history.latest();
+        if latest.map(|s| s.at) != self.latest_at {
+            self.latest_at = latest.map(|s| s.at);
+            self.previous = std::mem::take(&mut self.target);
+            self.shifted_at = Some(now);
+            if self.outage > 0 && latest.is_some_and(|s| s.rtt.is_some()) {
+                self.recovered_at = Some(now);
+            }
+        }
+        self.outage = history.iter().take_while(|s| s.rtt.is_none()).count();
+        self.target = pixels;
+    }
+
+    /// The colours to show at `now`
+    pub fn frame(&self, now: Duration, effect: Effect) -> Vec<RGB8> {
+        let mut pixels = self.target.clone();
+        let since = |at: Option<Duration>| at.map(|at| now.saturating_sub(at));
+
+        if let Some(elapsed) =
+            since(self.shifted_at).filter(|e| effect.scrolls() && *e < SCROLL_TIME)
+        {
+            // Move the previous colours along by a growing fraction of a
+            // pixel, easing in and out, with the newest entering at the
+            // start; once there the target takes over
+            let t = elapsed.as_secs_f32() / SCROLL_TIME.as_secs_f32();
+            let offset = t * t * (3.0 - 2.0 * t);
+            let entering = pixels.first().copied().unwrap_or_default();
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let here = self.previous.get(i).copied().unwrap_or(*pixel);
+                let behind = match i {
+                    0 => entering,
+                    _ => self.previous.get(i - 1).copied().unwrap_or(here),
+                };
+                *pixel = blend(here, behind, offset);
+            }
+        }
+        if effect.alerts() {
+            let t = wave(now, ALERT_PERIOD);
+            for pixel in pixels.iter_mut().take(self.outage) {
+                *pixel = blend(*pixel, ALERT_COLOUR, t);
+            }
+        }
+        if effect.breathes() && self.outage == 0 {
+            if let Some(pixel) = pixels.first_mut() {
+                *pixel = blend(
+                    RGB8::default(),
+                    *pixel,
+                    0.3 + 0.7 * wave(now, BREATHE_PERIOD),
+                );
+            }
+        }
+        if let Some(elapsed) =
+            since(self.recovered_at).filter(|e| effect.sparkles() && *e < SPARKLE_TIME)
+        {
+            let fade = 1.0 - elapsed.as_secs_f32() / SPARKLE_TIME.as_secs_f32();
+            let step = (now.as_millis() / SPARKLE_STEP.as_millis()) as u32;
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                if hash(i as u32, step) % 5 == 0 {
+                    *pixel = blend(*pixel, SPARKLE_COLOUR, fade);
+                }
+            }
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod test_animation {
+    use super::*;
+
+    const GREEN: RGB8 = RGB8::new(0, 200, 0);
+    const BLUE: RGB8 = RGB8::new(0, 0, 200);
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn static_frames_are_the_pixels() {
+        let mut history = History::new(3);
+        history.push(ms(0), Some(ms(5)));
+        let mut animator = Animator::new();
+        animator.update(ms(0), vec![GREEN; 3], &history);
+        for now in [0, 100, 1234] {
+            assert_eq!(animator.frame(ms(now), Effect::Static), vec![GREEN; 3]);
+        }
+        assert_eq!(Effect::from_name("animated"), Some(Effect::Animated));
+        assert_eq!(Effect::from_name("disco"), None);
+    }
+
+    #[test]
+    fn scrolls_new_samples_in() {
+        const RED: RGB8 = RGB8::new(200, 0, 0);
+        let mut history = History::new(3);
+        let mut animator = Animator::new();
+        history.push(ms(0), Some(ms(5)));
+        animator.update(ms(0), vec![GREEN, BLUE, BLUE], &history);
+        history.push(ms(1000), Some(ms(5)));
+        animator.update(ms(1000), vec![RED; 3], &history);
+        // Same sample again: nothing to scroll
+        animator.update(ms(1100), vec![RED; 3], &history);
+
+        // The old colours move along, rather than fading into the new ones
+        let halfway = ms(1000) + SCROLL_TIME / 2;
+        assert_eq!(
+            animator.frame(halfway, Effect::Scroll),
+            vec![RGB8::new(100, 100, 0), RGB8::new(0, 100, 100), BLUE]
+        );
+        // Easing in
+        let quarter = animator.frame(ms(1000) + SCROLL_TIME / 4, Effect::Scroll);
+        assert_eq!(quarter[1], RGB8::new(0, 31, 169));
+        assert_eq!(
+            animator.frame(ms(1000) + SCROLL_TIME, Effect::Scroll),
+            vec![RED; 3]
+        );
+        assert_eq!(animator.frame(halfway, Effect::Static), vec![RED; 3]);
+    }
+
+    #[test]
+    fn breathes_newest() {
+        let mut history = History::new(2);
+        history.push(ms(0), Some(ms(5)));
+        let mut animator = Animator::new();
+        animator.update(ms(0), vec![GREEN; 2], &history);
+        let dim = animator.frame(ms(0), Effect::Breathe);
+        assert_eq!(dim, vec![RGB8::new(0, 60, 0), GREEN]);
+        let bright = animator.frame(BREATHE_PERIOD / 2, Effect::Breathe);
+        assert_eq!(bright, vec![GREEN; 2]);
+    }
+
+    #[test]
+    fn alerts_during_outage_then_sparkles() {
+        let mut history = History::new(4);
+        let mut animator = Animator::new();
+        history.push(ms(0), Some(ms(5)));
+        history.push(ms(1000), None);
+        history.push(ms(2000), None);
+        animator.update(ms(2000), vec![BLUE, BLUE, GREEN, GREEN], &history);
+        let peak = ms(2000) + ALERT_PERIOD / 2;
+        assert_eq!(
+            animator.frame(peak, Effect::Alert),
+            vec![ALERT_COLOUR, ALERT_COLOUR, GREEN, GREEN]
+        );
+
+        history.push(ms(3000), Some(ms(5)));
+        let pixels = vec![GREEN; 20];
+        animator.update(ms(3000), pixels.clone(), &history);
+        assert_eq!(animator.frame(peak, Effect::Alert), pixels);
+        let sparkling = animator.frame(ms(3000), Effect::Sparkle);
+        let lit = sparkling.iter().filter(|p| **p == SPARKLE_COLOUR).count();
+        assert!(lit > 0 && lit < 20, "{} sparkling", lit);
+        // Twinkles move around, then die away
+        assert_ne!(
+            animator.frame(ms(3000) + SPARKLE_STEP, Effect::Sparkle),
+            sparkling
+        );
+        assert_eq!(
+            animator.frame(ms(3000) + SPARKLE_TIME, Effect::Sparkle),
+            pixels
+        );
+    }
+}
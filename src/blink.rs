@@ -0,0 +1,218 @@
+use smart_leds::RGB8;
+use std::fmt;
+use std::time::Duration;
+
+/// How long a blink code is shown before carrying on (or restarting), so
+/// it can be read and counted
+pub const HOLD_TIME: Duration = Duration::from_secs(20);
+const BLINK_ON: Duration = Duration::from_millis(400);
+const BLINK_OFF: Duration = Duration::from_millis(400);
+/// Gap between repeats of the code
+const PAUSE: Duration = Duration::from_millis(2000);
+
+const AMBER: RGB8 = RGB8::new(120, 60, 0);
+const PURPLE: RGB8 = RGB8::new(80, 0, 120);
+const RED: RGB8 = RGB8::new(120, 0, 0);
+
+/// Something which stopped the device from starting up properly, ordered by
+/// how far startup got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BootFailure {
+    /// None of the known networks could be found
+    SsidNotFound,
+    /// A network was found, but rejected our password
+    WifiAuth,
+    /// We associated with the network, but didn't get an address
+    DhcpTimeout,
+    /// The broker rejected our username or password
+    MqttAuth,
+    /// The LED strip couldn't be set up; usually can't be shown, but is
+    /// still logged with its code
+    LedDriver,
+}
+
+/// A colour, blinked a number of times and then paused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlinkCode {
+    pub colour: RGB8,
+    pub blinks: u8,
+}
+
+impl BootFailure {
+    /// Amber for WiFi problems, purple for MQTT and red for hardware
+    pub fn blink_code(self) -> BlinkCode {
+        let (colour, blinks) = match self {
+            BootFailure::SsidNotFound => (AMBER, 1),
+            BootFailure::WifiAuth => (AMBER, 2),
+            BootFailure::DhcpTimeout => (AMBER, 3),
+            BootFailure::MqttAuth => (PURPLE, 1),
+            BootFailure::LedDriver => (RED, 1),
+        };
+        BlinkCode { colour, blinks }
+    }
+
+    /// Number used in the log, the tens being the colour and the units the
+    /// number of blinks, eg 12 for two amber blinks
+    pub fn code(self) -> u8 {
+        let group = match self {
+            BootFailure::SsidNotFound | BootFailure::WifiAuth | BootFailure::DhcpTimeout => 1,
+            BootFailure::MqttAuth => 2,
+            BootFailure::LedDriver => 3,
+        };
+        group * 10 + self.blink_code().blinks
+    }
+
+    /// Classify a `wifi_err_reason_t` from a station disconnect event
+    pub fn from_wifi_reason(reason: u16) -> Option<Self> {
+        match reason {
+            // NO_AP_FOUND
+            201 => Some(BootFailure::SsidNotFound),
+            // AUTH_EXPIRE, 4WAY_HANDSHAKE_TIMEOUT, AUTH_FAIL, HANDSHAKE_TIMEOUT,
+            // NO_AP_FOUND_W_COMPATIBLE_SECURITY, NO_AP_FOUND_IN_AUTHMODE_THRESHOLD
+            2 | 15 | 202 | 204 | 210 | 211 => Some(BootFailure::WifiAuth),
+            _ => None,
+        }
+    }
+
+    /// Classify an MQTT CONNACK return code
+    pub fn from_mqtt_return_code(code: u32) -> Option<Self> {
+        match code {
+            // Bad username or password, not authorised
+            4 | 5 => Some(BootFailure::MqttAuth),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BootFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            BootFailure::SsidNotFound => "WiFi network not found",
+            BootFailure::WifiAuth => "WiFi authentication failed",
+            BootFailure::DhcpTimeout => "no address from DHCP",
+            BootFailure::MqttAuth => "MQTT broker rejected our credentials",
+            BootFailure::LedDriver => "LED driver failed",
+        };
+        write!(f, "error {}: {}", self.code(), description)
+    }
+}
+
+impl std::error::Error for BootFailure {}
+
+impl BlinkCode {
+    /// Length of one repeat, blinks plus pause
+    pub fn period(&self) -> Duration {
+        (BLINK_ON + BLINK_OFF) * self.blinks as u32 + PAUSE
+    }
+
+    /// Whether the strip is lit `elapsed` after the code started
+    pub fn is_lit(&self, elapsed: Duration) -> bool {
+        let position = elapsed.as_millis() % self.period().as_millis();
+        let blink = (BLINK_ON + BLINK_OFF).as_millis();
+        position < blink * self.blinks as u128 && position % blink < BLINK_ON.as_millis()
+    }
+
+    /// The whole strip, `elapsed` after the code started
+    pub fn pixels(&self, led_count: usize, elapsed: Duration) -> Vec<RGB8> {
+        let colour = if self.is_lit(elapsed) {
+            self.colour
+        } else {
+            RGB8::default()
+        };
+        vec![colour; led_count]
+    }
+}
+
+impl fmt::Display for BlinkCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let colour = [(AMBER, "amber"), (PURPLE, "purple"), (RED, "red")]
+            .iter()
+            .find(|(colour, _)| *colour == self.colour)
+            .map_or("coloured", |(_, name)| name);
+        let plural = if self.blinks == 1 { "" } else { "s" };
+        write!(f, "{} {} blink{}", self.blinks, colour, plural)
+    }
+}
+
+#[cfg(test)]
+mod test_blink {
+    use super::*;
+
+    const ALL: [BootFailure; 5] = [
+        BootFailure::SsidNotFound,
+        BootFailure::WifiAuth,
+        BootFailure::DhcpTimeout,
+        BootFailure::MqttAuth,
+        BootFailure::LedDriver,
+    ];
+
+    #[test]
+    fn later_failures_order_after_earlier_ones() {
+        assert!(Some(BootFailure::DhcpTimeout) > Some(BootFailure::WifiAuth));
+        assert!(Some(BootFailure::SsidNotFound) > None);
+    }
+
+    #[test]
+    fn codes_are_distinct() {
+        for (i, a) in ALL.iter().enumerate() {
+            for b in &ALL[i + 1..] {
+                assert_ne!(a.blink_code(), b.blink_code());
+                assert_ne!(a.code(), b.code());
+            }
+        }
+        assert_eq!(BootFailure::WifiAuth.code(), 12);
+        assert_eq!(
+            BootFailure::DhcpTimeout.to_string(),
+            "error 13: no address from DHCP"
+        );
+        assert_eq!(
+            BootFailure::DhcpTimeout.blink_code().to_string(),
+            "3 amber blinks"
+        );
+        assert_eq!(
+            BootFailure::MqttAuth.blink_code().to_string(),
+            "1 purple blink"
+        );
+    }
+
+    #[test]
+    fn blinks_then_pauses() {
+        let code = BootFailure::WifiAuth.blink_code();
+        assert_eq!(code.period(), Duration::from_millis(3600));
+        let lit: Vec<bool> = (0..9)
+            .map(|n| code.is_lit(Duration::from_millis(n * 400 + 200)))
+            .collect();
+        assert_eq!(
+            lit,
+            [true, false, true, false, false, false, false, false, false]
+        );
+        // And repeats
+        assert!(code.is_lit(Duration::from_millis(3600 + 200)));
+        assert_eq!(
+            code.pixels(3, Duration::ZERO),
+            vec![RGB8::new(120, 60, 0); 3]
+        );
+        assert_eq!(
+            code.pixels(3, Duration::from_millis(500)),
+            vec![RGB8::default(); 3]
+        );
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(
+            BootFailure::from_wifi_reason(201),
+            Some(BootFailure::SsidNotFound)
+        );
+        assert_eq!(
+            BootFailure::from_wifi_reason(15),
+            Some(BootFailure::WifiAuth)
+        );
+        assert_eq!(BootFailure::from_wifi_reason(8), None);
+        assert_eq!(
+            BootFailure::from_mqtt_return_code(5),
+            Some(BootFailure::MqttAuth)
+        );
+        assert_eq!(BootFailure::from_mqtt_return_code(3), None);
+    }
+}
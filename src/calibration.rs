@@ -0,0 +1,185 @@
+use crate::config::{Config, ConfigKey};
+use crate::history::History;
+use std::time::Duration;
+
+/// How often the thresholds are adjusted
+pub const INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Fewest RTTs in the window before we trust the percentiles
+pub const MIN_SAMPLES: usize = 60;
+/// Pings at or under this percentile of recent RTTs are perfectly healthy
+pub const MIN_PERCENTILE: u8 = 50;
+/// Pings over this percentile of recent RTTs are a problem
+pub const MAX_PERCENTILE: u8 = 95;
+/// Fraction of the way to the new percentiles each adjustment moves, so one
+/// bad evening doesn't redefine normal
+const RATE: f64 = 0.25;
+/// Losses aren't in the percentiles, so while at least this share of the
+/// retained samples are lost calibration stops, and starts over (jumping
+/// straight to the percentiles) once pings get through again
+pub const RESET_LOSS_PERCENT: f32 = 50.0;
+
+/// Learns `min_healthy_duration` and `max_healthy_duration` from the RTTs
+/// in the history's baseline window, when `Config::auto_calibrate` is on.
+/// The first adjustment after turning it on jumps straight to the
+/// percentiles, as the configured values are probably guesses; after that
+/// they move slowly.
+#[derive(Debug, Clone, Default)]
+pub struct Calibrator {
+    /// When we last adjusted, as time since boot
+    last: Option<Duration>,
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adjust `config` if it is time to, returning whether the thresholds
+    /// changed (so they can be published)
+    pub fn update(&mut self, now: Duration, history: &History, config: &mut Config) -> bool {
+        if !config.auto_calibrate {
+            self.last = None;
+            return false;
+        }
+        if history.loss_percent() >= RESET_LOSS_PERCENT {
+            self.last = None;
+            return false;
+        }
+        if self
+            .last
+            .is_some_and(|last| now.saturating_sub(last) < INTERVAL)
+            || history.baseline_len() < MIN_SAMPLES
+        {
+            return false;
+        }
+        let (Some(min_target), Some(max_target)) = (
+            history.percentile(MIN_PERCENTILE),
+            history.percentile(MAX_PERCENTILE),
+        ) else {
+            return false;
+        };
+        let rate = if self.last.is_some() { RATE } else { 1.0 };
+        self.last = Some(now);
+
+        let (low, high) = ConfigKey::MinHealthyDuration
+            .range()
+            .expect("healthy durations have a range");
+        let step = |current: Duration, target: Duration| {
+            let current = current.as_millis() as f64;
+            let ms = current + (target.as_secs_f64() * 1000.0 - current) * rate;
+            (ms.round() as u64).clamp(low, high)
+        };
+        let min = step(config.min_healthy_duration, min_target);
+        // Keep a gap, so there is a ramp to colour
+        let max = step(config.max_healthy_duration, max_target).max(min + 1);
+        let (min, max) = (min.min(high - 1), max.min(high));
+
+        let (min, max) = (Duration::from_millis(min), Duration::from_millis(max));
+        let changed = min != config.min_healthy_duration || max != config.max_healthy_duration;
+        config.min_healthy_duration = min;
+        config.max_healthy_duration = max;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test_calibration {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// 100 pings a minute apart: 40ms mostly, with every tenth at 200ms
+    fn satellite() -> History {
+        let mut history = History::new(24);
+        for n in 0..100 {
+            let rtt = if n % 10 == 9 { 200 } else { 40 };
+            history.push(Duration::from_secs(n * 60), Some(ms(rtt)));
+        }
+        history
+    }
+
+    #[test]
+    fn does_nothing_when_off_or_unsure() {
+        let mut calibrator = Calibrator::new();
+        let mut config = Config::default();
+        let history = satellite();
+        assert!(!calibrator.update(Duration::ZERO, &history, &mut config));
+        assert_eq!(
+            config.min_healthy_duration,
+            Config::default().min_healthy_duration
+        );
+
+        config.auto_calibrate = true;
+        let mut short = History::new(24);
+        short.push(Duration::ZERO, Some(ms(40)));
+        assert!(!calibrator.update(Duration::ZERO, &short, &mut config));
+    }
+
+    #[test]
+    fn jumps_then_adapts_slowly() {
+        let mut calibrator = Calibrator::new();
+        let mut config = Config {
+            auto_calibrate: true,
+            ..Default::default()
+        };
+        let mut history = satellite();
+        assert!(calibrator.update(Duration::ZERO, &history, &mut config));
+        assert_eq!(
+            (config.min_healthy_duration, config.max_healthy_duration),
+            (ms(40), ms(200))
+        );
+
+        // The link gets faster, but we only follow every so often, and
+        // part of the way
+        for n in 100..300 {
+            history.push(Duration::from_secs(n * 60), Some(ms(20)));
+        }
+        assert!(!calibrator.update(INTERVAL / 2, &history, &mut config));
+        assert!(calibrator.update(INTERVAL, &history, &mut config));
+        assert_eq!(
+            (config.min_healthy_duration, config.max_healthy_duration),
+            (ms(35), ms(160))
+        );
+    }
+
+    #[test]
+    fn keeps_a_ramp() {
+        let mut calibrator = Calibrator::new();
+        let mut config = Config {
+            auto_calibrate: true,
+            ..Default::default()
+        };
+        let mut history = History::new(24);
+        for n in 0..100 {
+            history.push(Duration::from_secs(n * 60), Some(ms(5)));
+        }
+        calibrator.update(Duration::ZERO, &history, &mut config);
+        assert_eq!(
+            (config.min_healthy_duration, config.max_healthy_duration),
+            (ms(5), ms(6))
+        );
+    }
+
+    #[test]
+    fn starts_over_when_losses_take_over() {
+        let mut calibrator = Calibrator::new();
+        let mut config = Config {
+            auto_calibrate: true,
+            ..Default::default()
+        };
+        let mut history = satellite();
+        assert!(calibrator.update(Duration::ZERO, &history, &mut config));
+        for n in 100..124 {
+            history.push(Duration::from_secs(n * 60), None);
+        }
+        assert!(!calibrator.update(INTERVAL, &history, &mut config));
+        // Pings get through again, and much faster: straight there
+        for n in 124..400 {
+            history.push(Duration::from_secs(n * 60), Some(ms(20)));
+        }
+        assert!(calibrator.update(INTERVAL * 3 / 2, &history, &mut config));
+        assert_eq!(config.min_healthy_duration, ms(20));
+    }
+}
@@ -0,0 +1,374 @@
+use smart_leds::RGB8;
+use std::fmt;
+use std::time::Duration;
+
+/// Most stops we accept, to bound the work per pixel
+pub const MAX_STOPS: usize = 16;
+
+/// A colour in OKLab, where equal distances look like equal differences
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oklab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+impl From<RGB8> for Oklab {
+    fn from(rgb: RGB8) -> Self {
+        let (r, g, b) = (
+            srgb_to_linear(rgb.r),
+            srgb_to_linear(rgb.g),
+            srgb_to_linear(rgb.b),
+        );
+        let l = (0.4122215 * r + 0.5363325 * g + 0.05144599 * b).cbrt();
+        let m = (0.2119035 * r + 0.6806995 * g + 0.107397 * b).cbrt();
+        let s = (0.08830246 * r + 0.2817188 * g + 0.6299787 * b).cbrt();
+        Oklab {
+            l: 0.2104543 * l + 0.7936178 * m - 0.004072047 * s,
+            a: 1.977998 * l - 2.428592 * m + 0.4505937 * s,
+            b: 0.02590404 * l + 0.7827718 * m - 0.8086758 * s,
+        }
+    }
+}
+
+impl From<Oklab> for RGB8 {
+    /// Colours outside sRGB are clipped
+    fn from(lab: Oklab) -> Self {
+        let l = (lab.l + 0.3963378 * lab.a + 0.2158038 * lab.b).powi(3);
+        let m = (lab.l - 0.1055613 * lab.a - 0.06385417 * lab.b).powi(3);
+        let s = (lab.l - 0.08948418 * lab.a - 1.291486 * lab.b).powi(3);
+        RGB8::new(
+            linear_to_srgb(4.076742 * l - 3.307712 * m + 0.2309699 * s),
+            linear_to_srgb(-1.268438 * l + 2.609757 * m - 0.3413194 * s),
+            linear_to_srgb(-0.004196086 * l - 0.7034186 * m + 1.707615 * s),
+        )
+    }
+}
+
+/// Mix two colours in OKLCH (lightness, chroma and hue), taking the short
+/// way round the hue circle, so eg green to red passes through a clear
+/// yellow rather than a muddy brown. `t` is 0 for `from` and 1 for `to`.
+pub fn mix(from: RGB8, to: RGB8, t: f32) -> RGB8 {
+    let (from, to) = (Oklab::from(from), Oklab::from(to));
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+    let chroma = |c: &Oklab| c.a.hypot(c.b);
+    let (c1, c2) = (chroma(&from), chroma(&to));
+    // Greys have no meaningful hue, so use the other colour's
+    let h1 = if c1 < 1e-4 {
+        to.b.atan2(to.a)
+    } else {
+        from.b.atan2(from.a)
+    };
+    let h2 = if c2 < 1e-4 { h1 } else { to.b.atan2(to.a) };
+    let mut dh = h2 - h1;
+    if dh > std::f32::consts::PI {
+        dh -= std::f32::consts::TAU;
+    } else if dh < -std::f32::consts::PI {
+        dh += std::f32::consts::TAU;
+    }
+    let (c, h) = (lerp(c1, c2), h1 + dh * t);
+    Oklab {
+        l: lerp(from.l, to.l),
+        a: c * h.cos(),
+        b: c * h.sin(),
+    }
+    .into()
+}
+
+/// "#rrggbb" (the # is optional)
+pub fn parse_hex_colour(s: &str) -> Option<RGB8> {
+    let hex = s.trim().strip_prefix('#').unwrap_or(s.trim());
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(RGB8::new(channel(0)?, channel(2)?, channel(4)?))
+}
+
+pub fn to_hex_colour(colour: RGB8) -> String {
+    format!("#{:02x}{:02x}{:02x}", colour.r, colour.g, colour.b)
+}
+
+/// The colour for one latency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColourStop {
+    pub latency: Duration,
+    pub colour: RGB8,
+}
+
+impl ColourStop {
+    pub fn new(latency: Duration, colour: RGB8) -> Self {
+        Self { latency, colour }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColourError {
+    Empty,
+    TooMany(usize),
+    /// Stops must be in order of latency
+    Unordered,
+    InvalidColour(String),
+    /// Not in the form "<latency>:<colour>"
+    Syntax(String),
+}
+
+impl fmt::Display for ColourError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColourError::Empty => write!(f, "at least one colour stop is needed"),
+            ColourError::TooMany(n) => {
+                write!(f, "{} colour stops given, at most {}", n, MAX_STOPS)
+            }
+            ColourError::Unordered => write!(f, "colour stops must be in order of latency"),
+            ColourError::InvalidColour(s) => write!(f, "invalid colour {:?}", s),
+            ColourError::Syntax(s) => write!(f, "invalid colour stop {:?}", s),
+        }
+    }
+}
+
+impl std::error::Error for ColourError {}
+
+/// Check a list of stops can be used with `colour_at`
+pub fn validate_stops(stops: &[ColourStop]) -> Result<(), ColourError> {
+    if stops.is_empty() {
+        return Err(ColourError::Empty);
+    }
+    if stops.len() > MAX_STOPS {
+        return Err(ColourError::TooMany(stops.len()));
+    }
+    if stops.windows(2).any(|w| w[0].latency > w[1].latency) {
+        return Err(ColourError::Unordered);
+    }
+    Ok(())
+}
+
+/// Parse a comma separated list of stops with latencies in ms, eg
+/// "10:#00ff00,100:#ff0000"; an empty list means the default stops
+pub fn parse_stops(s: &str) -> Result<Option<Vec<ColourStop>>, ColourError> {
+    let stops = s
+        .split(',')
+        .filter(|stop| !stop.trim().is_empty())
+        .map(|stop| {
+            let syntax = || ColourError::Syntax(stop.to_string());
+            let (latency, colour) = stop.split_once(':').ok_or_else(syntax)?;
+            let latency = latency.trim().parse::<u64>().map_err(|_| syntax())?;
+            let colour = parse_hex_colour(colour)
+                .ok_or_else(|| ColourError::InvalidColour(colour.to_string()))?;
+            Ok(ColourStop::new(Duration::from_millis(latency), colour))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if stops.is_empty() {
+        return Ok(None);
+    }
+    validate_stops(&stops)?;
+    Ok(Some(stops))
+}
+
+pub fn format_stops(stops: &[ColourStop]) -> String {
+    stops
+        .iter()
+        .map(|stop| {
+            format!(
+                "{}:{}",
+                stop.latency.as_millis(),
+                to_hex_colour(stop.colour)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// How latencies are spread between colour stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    /// Evenly by time
+    Linear,
+    /// Evenly by ratio, so 5ms to 50ms looks as big a change as 50ms to
+    /// 500ms
+    Log,
+    /// Logarithmically, by how many times slower than usual the ping is:
+    /// a ping as fast as the recent median is coloured as if it took the
+    /// min healthy duration, one twice as slow as twice that, and so on
+    Baseline,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 3] = [Scale::Linear, Scale::Log, Scale::Baseline];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scale::Linear => "linear",
+            Scale::Log => "log",
+            Scale::Baseline => "baseline",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.name() == name)
+    }
+}
+
+/// The colour for `latency`, interpolating between the stops either side.
+/// Latencies outside the stops get the nearest end colour, and two stops
+/// at the same latency make a sharp step (the first applies at exactly
+/// that latency, the second above it).
+pub fn colour_at(stops: &[ColourStop], latency: Duration) -> RGB8 {
+    interpolate(stops, latency, |d| d.as_secs_f32())
+}
+
+/// Like `colour_at`, but interpolating by the logarithm of the latency
+pub fn colour_at_log(stops: &[ColourStop], latency: Duration) -> RGB8 {
+    // Zero would be minus infinity
+    interpolate(stops, latency, |d| {
+        d.max(Duration::from_micros(1)).as_secs_f32().ln()
+    })
+}
+
+fn interpolate(
+    stops: &[ColourStop],
+    latency: Duration,
+    position: impl Fn(Duration) -> f32,
+) -> RGB8 {
+    let Some(first) = stops.first() else {
+        return RGB8::default();
+    };
+    if latency <= first.latency {
+        return first.colour;
+    }
+    for pair in stops.windows(2) {
+        let (from, to) = (&pair[0], &pair[1]);
+        if latency <= to.latency {
+            let start = position(from.latency);
+            let t = (position(latency) - start) / (position(to.latency) - start);
+            return mix(from.colour, to.colour, t);
+        }
+    }
+    stops[stops.len() - 1].colour
+}
+
+#[cfg(test)]
+mod test_colour {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn oklab_round_trips() {
+        for colour in [
+            RGB8::new(0, 0, 0),
+            RGB8::new(255, 255, 255),
+            RGB8::new(255, 0, 0),
+            RGB8::new(12, 200, 99),
+        ] {
+            assert_eq!(RGB8::from(Oklab::from(colour)), colour);
+        }
+        let white = Oklab::from(RGB8::new(255, 255, 255));
+        assert!((white.l - 1.0).abs() < 0.001 && white.a.abs() < 0.001);
+    }
+
+    #[test]
+    fn mixes_through_bright_hues() {
+        let (green, red) = (RGB8::new(0, 255, 0), RGB8::new(255, 0, 0));
+        assert_eq!(mix(green, red, 0.0), green);
+        assert_eq!(mix(green, red, 1.0), red);
+        // Halfway is a clear yellow-orange, not brown
+        let middle = mix(green, red, 0.5);
+        assert!(middle.r > 200 && middle.g > 120 && middle.b < 50);
+    }
+
+    #[test]
+    fn interpolates_between_stops() {
+        let stops = [
+            ColourStop::new(ms(10), RGB8::new(0, 255, 0)),
+            ColourStop::new(ms(100), RGB8::new(255, 0, 0)),
+            ColourStop::new(ms(100), RGB8::new(255, 0, 255)),
+        ];
+        assert_eq!(validate_stops(&stops), Ok(()));
+        assert_eq!(colour_at(&stops, ms(0)), RGB8::new(0, 255, 0));
+        assert_eq!(colour_at(&stops, ms(100)), RGB8::new(255, 0, 0));
+        assert_eq!(colour_at(&stops, ms(101)), RGB8::new(255, 0, 255));
+        assert_eq!(
+            colour_at(&stops, ms(55)),
+            mix(RGB8::new(0, 255, 0), RGB8::new(255, 0, 0), 0.5)
+        );
+        assert_eq!(colour_at(&[], ms(5)), RGB8::default());
+    }
+
+    #[test]
+    fn log_scale_spreads_by_ratio() {
+        let stops = [
+            ColourStop::new(ms(10), RGB8::new(0, 255, 0)),
+            ColourStop::new(ms(1000), RGB8::new(255, 0, 0)),
+        ];
+        let middle = mix(RGB8::new(0, 255, 0), RGB8::new(255, 0, 0), 0.5);
+        assert_eq!(colour_at_log(&stops, ms(100)), middle);
+        assert_ne!(colour_at(&stops, ms(100)), middle);
+        assert_eq!(colour_at_log(&stops, ms(0)), RGB8::new(0, 255, 0));
+        let stops = [
+            ColourStop::new(Duration::ZERO, RGB8::new(0, 255, 0)),
+            ColourStop::new(ms(1000), RGB8::new(255, 0, 0)),
+        ];
+        assert_eq!(colour_at_log(&stops, ms(2000)), RGB8::new(255, 0, 0));
+        assert_eq!(Scale::from_name("log"), Some(Scale::Log));
+        assert_eq!(Scale::from_name("cubic"), None);
+    }
+
+    #[test]
+    fn rejects_bad_stops() {
+        let stop = |n| ColourStop::new(ms(n), RGB8::default());
+        assert_eq!(validate_stops(&[]), Err(ColourError::Empty));
+        assert_eq!(
+            validate_stops(&[stop(20), stop(10)]),
+            Err(ColourError::Unordered)
+        );
+        assert_eq!(
+            validate_stops(&[stop(1); MAX_STOPS + 1]),
+            Err(ColourError::TooMany(MAX_STOPS + 1))
+        );
+        assert_eq!(parse_hex_colour("#00ff7F"), Some(RGB8::new(0, 255, 127)));
+        assert_eq!(parse_hex_colour("00ff7f"), Some(RGB8::new(0, 255, 127)));
+        assert_eq!(parse_hex_colour("#0f7"), None);
+        assert_eq!(to_hex_colour(RGB8::new(0, 255, 127)), "#00ff7f");
+    }
+
+    #[test]
+    fn stops_round_trip_as_text() {
+        let stops = parse_stops("10:#00ff00, 100:#ff0000,100:ff00ff")
+            .expect("valid")
+            .expect("not empty");
+        assert_eq!(stops.len(), 3);
+        assert_eq!(stops[2], ColourStop::new(ms(100), RGB8::new(255, 0, 255)));
+        assert_eq!(format_stops(&stops), "10:#00ff00,100:#ff0000,100:#ff00ff");
+        assert_eq!(parse_stops(" "), Ok(None));
+        assert_eq!(
+            parse_stops("10"),
+            Err(ColourError::Syntax("10".to_string()))
+        );
+        assert_eq!(
+            parse_stops("100:#ff0000,10:#00ff00"),
+            Err(ColourError::Unordered)
+        );
+    }
+}
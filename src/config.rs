@@ -1,3 +1,15 @@
+use crate::animation::Effect;
+use crate::colour::{self, ColourStop, Scale};
+use crate::dial::DialSpan;
+use crate::layout::{self, Layout};
+use crate::matrix::Wiring;
+use crate::power::{DEFAULT_POWER_BUDGET, MAX_POWER_BUDGET};
+use crate::render::Mode;
+use crate::rollup::Aggregation;
+use crate::status::{self, Indicator};
+use smart_leds::RGB8;
+use std::fmt;
+use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -12,16 +24,61 @@ pub struct Config {
     pub led_brightness: u8,
     /// Whether LEDs are enabled
     pub led_enabled: bool,
+    /// Whether to flicker between adjacent output levels, so dim settings
+    /// keep their colours
+    pub led_dithering: bool,
+    /// What the strip shows
+    pub led_mode: Mode,
+    /// Animation on top of the history
+    pub led_effect: Effect,
+    /// Colour for the whole strip instead of `led_mode`, eg for scenes
+    pub led_colour: Option<RGB8>,
+    /// How long the bar graph's peak is held for
+    pub bar_peak_hold: Duration,
+    /// How much time once round the dial covers
+    pub dial_span: DialSpan,
+    /// Local time zone, as minutes ahead of UTC
+    pub utc_offset: i32,
+    /// Total time the log time axis covers
+    pub log_span: Duration,
+    /// How the log time axis combines the pings behind each LED
+    pub log_aggregation: Aggregation,
     /// Host to ping (as a string, will be resolved to IP)
     pub ping_host: String,
     /// Total duration the LED strip represents
     pub led_strip_duration: Duration,
     /// Number of LEDs in the strip
     pub led_count: u32,
+    /// Current the supply can give the strip, in mA, or 0 for no limit;
+    /// brighter frames are dimmed to fit (see `power::limit`)
+    pub power_budget: u32,
+    /// Colours for ping times, or None for the default green-yellow-red
+    /// ramp between the healthy durations (see `rgb::default_stops`)
+    pub colour_stops: Option<Vec<ColourStop>>,
+    /// How the strip is wired, to map logical pixels to physical ones
+    pub layout: Layout,
+    /// Columns of an LED matrix, with `led_count / matrix_width` rows, or 0
+    /// for a strip
+    pub matrix_width: u32,
+    /// The order the matrix's pixels are wired in
+    pub matrix_wiring: Wiring,
+    /// How often a matrix scrolls the latest RTT across, or zero for never
+    pub text_interval: Duration,
+    /// Indicators shown on the last pixels of the strip, in place of the
+    /// history
+    pub status_pixels: Vec<Indicator>,
+    /// How ping times are spread between the colour stops
+    pub colour_scale: Scale,
+    /// How far back the median for `Scale::Baseline` and auto-calibration
+    /// look
+    pub baseline_window: Duration,
+    /// Whether the healthy durations are learnt from recent RTTs (see
+    /// `calibration::Calibrator`)
+    pub auto_calibrate: bool,
 }
 
 impl Config {
-    /// Create a new Config with the given values
+    /// Create a new Config with the given values, and dithering on
     pub fn new(
         min_healthy_duration: Duration,
         max_healthy_duration: Duration,
@@ -36,12 +93,56 @@ impl Config {
             max_healthy_duration,
             led_brightness,
             led_enabled,
+            led_dithering: true,
+            led_mode: Mode::History,
+            led_effect: Effect::Static,
+            led_colour: None,
+            bar_peak_hold: Duration::from_secs(5 * 60),
+            dial_span: DialSpan::Hour,
+            utc_offset: 0,
+            log_span: Duration::from_secs(24 * 60 * 60),
+            log_aggregation: Aggregation::Worst,
             ping_host,
             led_strip_duration,
             led_count,
+            power_budget: DEFAULT_POWER_BUDGET,
+            colour_stops: None,
+            layout: Layout::default(),
+            matrix_width: 0,
+            matrix_wiring: Wiring::Serpentine,
+            text_interval: Duration::from_secs(60),
+            status_pixels: Vec::new(),
+            colour_scale: Scale::Linear,
+            baseline_window: Duration::from_secs(24 * 60 * 60),
+            auto_calibrate: false,
         }
     }
 
+    /// LEDs the layout leaves on the strip, after the offset and skipped
+    /// pixels
+    pub fn shown_led_count(&self) -> u32 {
+        self.layout.mapping(self.led_count as usize).pixels() as u32
+    }
+
+    /// Whether the strip is drawn as a matrix in the current mode
+    pub fn shows_matrix(&self) -> bool {
+        matches!(self.led_mode, Mode::Sparkline | Mode::Heatmap)
+            && self.matrix_width > 0
+            && self.shown_led_count() >= self.matrix_width
+    }
+
+    /// LEDs left for the history once the status pixels are taken, always
+    /// at least one. A matrix keeps every row, with the status pixels drawn
+    /// over its last pixels.
+    pub fn history_led_count(&self) -> u32 {
+        if self.shows_matrix() {
+            return self.shown_led_count();
+        }
+        self.shown_led_count()
+            .saturating_sub(self.status_pixels.len() as u32)
+            .max(1)
+    }
+
     /// Create a new Config wrapped in Arc<Mutex<>> for shared access
     pub fn new_shared(
         min_healthy_duration: Duration,
@@ -71,9 +172,484 @@ impl Default for Config {
             max_healthy_duration: Duration::from_millis(50),
             led_brightness: 127,
             led_enabled: true,
+            led_dithering: true,
+            led_mode: Mode::History,
+            led_effect: Effect::Static,
+            led_colour: None,
+            bar_peak_hold: Duration::from_secs(5 * 60),
+            dial_span: DialSpan::Hour,
+            utc_offset: 0,
+            log_span: Duration::from_secs(24 * 60 * 60),
+            log_aggregation: Aggregation::Worst,
             ping_host: String::new(), // Will be set to gateway by default
             led_strip_duration: Duration::from_secs(30 * 60),
             led_count: 24,
+            power_budget: DEFAULT_POWER_BUDGET,
+            colour_stops: None,
+            layout: Layout::default(),
+            matrix_width: 0,
+            matrix_wiring: Wiring::Serpentine,
+            text_interval: Duration::from_secs(60),
+            status_pixels: Vec::new(),
+            colour_scale: Scale::Linear,
+            baseline_window: Duration::from_secs(24 * 60 * 60),
+            auto_calibrate: false,
+        }
+    }
+}
+
+/// Largest strip we will drive
+pub const MAX_LED_COUNT: u32 = 300;
+/// Range of time zones, in minutes ahead of UTC
+pub const MIN_UTC_OFFSET: i32 = -12 * 60;
+pub const MAX_UTC_OFFSET: i32 = 14 * 60;
+
+/// A setting which can be changed at runtime (eg over MQTT or HTTP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigKey {
+    MinHealthyDuration,
+    MaxHealthyDuration,
+    LedBrightness,
+    LedEnabled,
+    LedDithering,
+    LedMode,
+    LedEffect,
+    LedColour,
+    LedOffset,
+    LedReverse,
+    LedRotation,
+    LedSkip,
+    LedSegments,
+    MatrixWidth,
+    MatrixWiring,
+    TextInterval,
+    StatusPixels,
+    BarPeakHold,
+    DialSpan,
+    UtcOffset,
+    LogSpan,
+    LogAggregation,
+    PingHost,
+    LedStripDuration,
+    LedCount,
+    PowerBudget,
+    ColourScale,
+    ColourStops,
+    BaselineWindow,
+    AutoCalibrate,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 30] = [
+        ConfigKey::MinHealthyDuration,
+        ConfigKey::MaxHealthyDuration,
+        ConfigKey::LedBrightness,
+        ConfigKey::LedEnabled,
+        ConfigKey::LedDithering,
+        ConfigKey::LedMode,
+        ConfigKey::LedEffect,
+        ConfigKey::LedColour,
+        ConfigKey::LedOffset,
+        ConfigKey::LedReverse,
+        ConfigKey::LedRotation,
+        ConfigKey::LedSkip,
+        ConfigKey::LedSegments,
+        ConfigKey::MatrixWidth,
+        ConfigKey::MatrixWiring,
+        ConfigKey::TextInterval,
+        ConfigKey::StatusPixels,
+        ConfigKey::BarPeakHold,
+        ConfigKey::DialSpan,
+        ConfigKey::UtcOffset,
+        ConfigKey::LogSpan,
+        ConfigKey::LogAggregation,
+        ConfigKey::PingHost,
+        ConfigKey::LedStripDuration,
+        ConfigKey::LedCount,
+        ConfigKey::PowerBudget,
+        ConfigKey::ColourScale,
+        ConfigKey::ColourStops,
+        ConfigKey::BaselineWindow,
+        ConfigKey::AutoCalibrate,
+    ];
+
+    /// Name used for MQTT topics and JSON fields
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigKey::MinHealthyDuration => "min_healthy_duration",
+            ConfigKey::MaxHealthyDuration => "max_healthy_duration",
+            ConfigKey::LedBrightness => "led_brightness",
+            ConfigKey::LedEnabled => "led_enabled",
+            ConfigKey::LedDithering => "led_dithering",
+            ConfigKey::LedMode => "led_mode",
+            ConfigKey::LedEffect => "led_effect",
+            ConfigKey::LedColour => "led_colour",
+            ConfigKey::LedOffset => "led_offset",
+            ConfigKey::LedReverse => "led_reverse",
+            ConfigKey::LedRotation => "led_rotation",
+            ConfigKey::LedSkip => "led_skip",
+            ConfigKey::LedSegments => "led_segments",
+            ConfigKey::MatrixWidth => "matrix_width",
+            ConfigKey::MatrixWiring => "matrix_wiring",
+            ConfigKey::TextInterval => "text_interval",
+            ConfigKey::StatusPixels => "status_pixels",
+            ConfigKey::BarPeakHold => "bar_peak_hold",
+            ConfigKey::DialSpan => "dial_span",
+            ConfigKey::UtcOffset => "utc_offset",
+            ConfigKey::LogSpan => "log_span",
+            ConfigKey::LogAggregation => "log_aggregation",
+            ConfigKey::PingHost => "ping_host",
+            ConfigKey::LedStripDuration => "led_strip_duration",
+            ConfigKey::LedCount => "led_count",
+            ConfigKey::PowerBudget => "power_budget",
+            ConfigKey::ColourScale => "colour_scale",
+            ConfigKey::ColourStops => "colour_stops",
+            ConfigKey::BaselineWindow => "baseline_window",
+            ConfigKey::AutoCalibrate => "auto_calibrate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|k| k.name() == name)
+    }
+
+    /// Inclusive range of values accepted for numeric settings
+    pub fn range(&self) -> Option<(u64, u64)> {
+        match self {
+            ConfigKey::MinHealthyDuration | ConfigKey::MaxHealthyDuration => Some((1, 1000)),
+            ConfigKey::LedBrightness => Some((0, 255)),
+            ConfigKey::LedStripDuration => Some((60, 7200)),
+            ConfigKey::LedCount => Some((1, MAX_LED_COUNT as u64)),
+            ConfigKey::PowerBudget => Some((0, MAX_POWER_BUDGET as u64)),
+            ConfigKey::MatrixWidth => Some((0, MAX_LED_COUNT as u64)),
+            ConfigKey::BaselineWindow => Some((1, 72)),
+            ConfigKey::LogSpan => Some((1, 7 * 24)),
+            ConfigKey::BarPeakHold | ConfigKey::TextInterval => Some((0, 3600)),
+            ConfigKey::LedOffset | ConfigKey::LedRotation => {
+                Some((0, layout::MAX_INDEX as u64 - 1))
+            }
+            ConfigKey::LedEnabled
+            | ConfigKey::LedDithering
+            | ConfigKey::LedReverse
+            | ConfigKey::LedSkip
+            | ConfigKey::LedSegments
+            | ConfigKey::MatrixWiring
+            | ConfigKey::StatusPixels
+            | ConfigKey::AutoCalibrate
+            | ConfigKey::LedMode
+            | ConfigKey::LedEffect
+            | ConfigKey::LedColour
+            | ConfigKey::DialSpan
+            | ConfigKey::UtcOffset
+            | ConfigKey::LogAggregation
+            | ConfigKey::PingHost
+            | ConfigKey::ColourScale
+            | ConfigKey::ColourStops => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownKey(String),
+    InvalidValue(ConfigKey, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting {:?}", key),
+            ConfigError::InvalidValue(key, value) => match key.range() {
+                Some((min, max)) => write!(
+                    f,
+                    "invalid {} {:?}, expected {}-{}",
+                    key.name(),
+                    value,
+                    min,
+                    max
+                ),
+                None => write!(f, "invalid {} {:?}", key.name(), value),
+            },
         }
     }
 }
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// The current value of a setting, formatted the same way `set` parses it
+    pub fn get(&self, key: ConfigKey) -> String {
+        match key {
+            ConfigKey::MinHealthyDuration => self.min_healthy_duration.as_millis().to_string(),
+            ConfigKey::MaxHealthyDuration => self.max_healthy_duration.as_millis().to_string(),
+            ConfigKey::LedBrightness => self.led_brightness.to_string(),
+            ConfigKey::LedEnabled => self.led_enabled.to_string(),
+            ConfigKey::LedDithering => self.led_dithering.to_string(),
+            ConfigKey::LedMode => self.led_mode.name().to_string(),
+            ConfigKey::LedEffect => self.led_effect.name().to_string(),
+            ConfigKey::LedColour => self
+                .led_colour
+                .map(colour::to_hex_colour)
+                .unwrap_or_default(),
+            ConfigKey::LedOffset => self.layout.offset.to_string(),
+            ConfigKey::LedReverse => self.layout.reverse.to_string(),
+            ConfigKey::LedRotation => self.layout.rotation.to_string(),
+            ConfigKey::LedSkip => layout::format_skipped(&self.layout.skipped),
+            ConfigKey::LedSegments => layout::format_segments(&self.layout.segments),
+            ConfigKey::MatrixWidth => self.matrix_width.to_string(),
+            ConfigKey::MatrixWiring => self.matrix_wiring.name().to_string(),
+            ConfigKey::TextInterval => self.text_interval.as_secs().to_string(),
+            ConfigKey::StatusPixels => status::format_indicators(&self.status_pixels),
+            ConfigKey::BarPeakHold => self.bar_peak_hold.as_secs().to_string(),
+            ConfigKey::DialSpan => self.dial_span.name().to_string(),
+            ConfigKey::UtcOffset => self.utc_offset.to_string(),
+            ConfigKey::LogSpan => (self.log_span.as_secs() / 3600).to_string(),
+            ConfigKey::LogAggregation => self.log_aggregation.name().to_string(),
+            ConfigKey::PingHost => self.ping_host.clone(),
+            ConfigKey::LedStripDuration => self.led_strip_duration.as_secs().to_string(),
+            ConfigKey::LedCount => self.led_count.to_string(),
+            ConfigKey::PowerBudget => self.power_budget.to_string(),
+            ConfigKey::ColourScale => self.colour_scale.name().to_string(),
+            ConfigKey::ColourStops => self
+                .colour_stops
+                .as_deref()
+                .map(colour::format_stops)
+                .unwrap_or_default(),
+            ConfigKey::BaselineWindow => (self.baseline_window.as_secs() / 3600).to_string(),
+            ConfigKey::AutoCalibrate => self.auto_calibrate.to_string(),
+        }
+    }
+
+    /// Validate and apply a new value for a setting. Durations are given in
+    /// the units shown to users (ms for healthy thresholds, s for the strip,
+    /// peak hold and text interval, hours for the baseline and log span,
+    /// minutes for the UTC offset).
+    pub fn set(&mut self, key: ConfigKey, value: &str) -> Result<(), ConfigError> {
+        let value = value.trim();
+        let invalid = || ConfigError::InvalidValue(key, value.to_string());
+        let boolean = || -> Result<bool, ConfigError> {
+            match value {
+                "true" | "ON" | "1" => Ok(true),
+                "false" | "OFF" | "0" => Ok(false),
+                _ => Err(invalid()),
+            }
+        };
+        let number = || -> Result<u64, ConfigError> {
+            let n = value.parse::<u64>().map_err(|_| invalid())?;
+            match key.range() {
+                Some((min, max)) if n < min || n > max => Err(invalid()),
+                _ => Ok(n),
+            }
+        };
+        let (layout, led_count) = (self.layout.clone(), self.led_count);
+        match key {
+            ConfigKey::MinHealthyDuration => {
+                self.min_healthy_duration = Duration::from_millis(number()?)
+            }
+            ConfigKey::MaxHealthyDuration => {
+                self.max_healthy_duration = Duration::from_millis(number()?)
+            }
+            ConfigKey::LedBrightness => self.led_brightness = number()? as u8,
+            ConfigKey::LedEnabled => self.led_enabled = boolean()?,
+            ConfigKey::LedDithering => self.led_dithering = boolean()?,
+            ConfigKey::LedMode => self.led_mode = Mode::from_name(value).ok_or_else(invalid)?,
+            ConfigKey::LedEffect => {
+                self.led_effect = Effect::from_name(value).ok_or_else(invalid)?
+            }
+            ConfigKey::LedColour => {
+                self.led_colour = match value {
+                    "" => None,
+                    _ => Some(colour::parse_hex_colour(value).ok_or_else(invalid)?),
+                }
+            }
+            ConfigKey::LedOffset => self.layout.offset = number()? as usize,
+            ConfigKey::LedReverse => self.layout.reverse = boolean()?,
+            ConfigKey::LedRotation => self.layout.rotation = number()? as usize,
+            ConfigKey::LedSkip => {
+                self.layout.skipped = layout::parse_skipped(value).map_err(|_| invalid())?
+            }
+            ConfigKey::LedSegments => {
+                self.layout.segments = layout::parse_segments(value).map_err(|_| invalid())?
+            }
+            ConfigKey::MatrixWidth => self.matrix_width = number()? as u32,
+            ConfigKey::TextInterval => self.text_interval = Duration::from_secs(number()?),
+            ConfigKey::StatusPixels => {
+                self.status_pixels = status::parse_indicators(value).ok_or_else(invalid)?
+            }
+            ConfigKey::MatrixWiring => {
+                self.matrix_wiring = Wiring::from_name(value).ok_or_else(invalid)?
+            }
+            ConfigKey::PingHost => {
+                value.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+                self.ping_host = value.to_string();
+            }
+            ConfigKey::LedStripDuration => self.led_strip_duration = Duration::from_secs(number()?),
+            ConfigKey::BarPeakHold => self.bar_peak_hold = Duration::from_secs(number()?),
+            ConfigKey::DialSpan => {
+                self.dial_span = DialSpan::from_name(value).ok_or_else(invalid)?
+            }
+            ConfigKey::UtcOffset => {
+                self.utc_offset = value
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|minutes| (MIN_UTC_OFFSET..=MAX_UTC_OFFSET).contains(minutes))
+                    .ok_or_else(invalid)?
+            }
+            ConfigKey::LogSpan => self.log_span = Duration::from_secs(number()? * 3600),
+            ConfigKey::LogAggregation => {
+                self.log_aggregation = Aggregation::from_name(value).ok_or_else(invalid)?
+            }
+            ConfigKey::LedCount => self.led_count = number()? as u32,
+            ConfigKey::PowerBudget => self.power_budget = number()? as u32,
+            ConfigKey::ColourStops => {
+                self.colour_stops = colour::parse_stops(value).map_err(|_| invalid())?
+            }
+            ConfigKey::ColourScale => {
+                self.colour_scale = Scale::from_name(value).ok_or_else(invalid)?
+            }
+            ConfigKey::BaselineWindow => {
+                self.baseline_window = Duration::from_secs(number()? * 3600)
+            }
+            ConfigKey::AutoCalibrate => self.auto_calibrate = boolean()?,
+        }
+        // The layout has to leave at least one LED on the strip
+        if self.layout.mapping(self.led_count as usize).is_empty() {
+            self.layout = layout;
+            self.led_count = led_count;
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Apply several settings at once; if any of them is invalid, none are
+    /// applied
+    pub fn set_all<K: AsRef<str>, V: AsRef<str>>(
+        &mut self,
+        values: &[(K, V)],
+    ) -> Result<(), ConfigError> {
+        let mut updated = self.clone();
+        for (name, value) in values {
+            let name = name.as_ref();
+            let key = ConfigKey::from_name(name)
+                .ok_or_else(|| ConfigError::UnknownKey(name.to_string()))?;
+            updated.set(key, value.as_ref())?;
+        }
+        *self = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+
+    #[test]
+    fn get_and_set_round_trip() {
+        let mut cfg = Config {
+            ping_host: "192.168.0.1".to_string(),
+            layout: Layout {
+                skipped: vec![5, 6],
+                segments: layout::parse_segments("front:0-11,back:23-12").expect("valid"),
+                ..Default::default()
+            },
+            status_pixels: vec![Indicator::Mqtt, Indicator::Wifi],
+            colour_stops: colour::parse_stops("10:#00ff00,100:#ff0000").expect("valid"),
+            ..Default::default()
+        };
+        let original = cfg.clone();
+        for key in ConfigKey::ALL {
+            let value = original.get(key);
+            cfg.set(key, &value).expect("current value should be valid");
+            assert_eq!(cfg.get(key), value);
+        }
+    }
+
+    #[test]
+    fn set_parses_user_units() {
+        let mut cfg = Config::default();
+        cfg.set(ConfigKey::MaxHealthyDuration, " 250\n")
+            .expect("valid");
+        assert_eq!(cfg.max_healthy_duration, Duration::from_millis(250));
+        cfg.set(ConfigKey::LedStripDuration, "3600").expect("valid");
+        assert_eq!(cfg.led_strip_duration, Duration::from_secs(3600));
+        cfg.set(ConfigKey::LedEnabled, "OFF").expect("valid");
+        assert!(!cfg.led_enabled);
+    }
+
+    #[test]
+    fn set_rejects_invalid_values() {
+        let mut cfg = Config::default();
+        for (key, value) in [
+            (ConfigKey::MinHealthyDuration, "0"),
+            (ConfigKey::MaxHealthyDuration, "fast"),
+            (ConfigKey::LedBrightness, "256"),
+            (ConfigKey::LedEnabled, "maybe"),
+            (ConfigKey::LedDithering, "sometimes"),
+            (ConfigKey::LedMode, "disco"),
+            (ConfigKey::LedEffect, "disco"),
+            (ConfigKey::LedColour, "red"),
+            (ConfigKey::LedOffset, "1024"),
+            (ConfigKey::LedReverse, "backwards"),
+            (ConfigKey::LedRotation, "-1"),
+            (ConfigKey::LedSkip, "1,two"),
+            (ConfigKey::LedSegments, "front"),
+            (ConfigKey::MatrixWidth, "301"),
+            (ConfigKey::MatrixWiring, "zigzag"),
+            (ConfigKey::TextInterval, "3601"),
+            (ConfigKey::StatusPixels, "battery"),
+            (ConfigKey::BarPeakHold, "3601"),
+            (ConfigKey::DialSpan, "1w"),
+            (ConfigKey::UtcOffset, "-721"),
+            (ConfigKey::LogSpan, "169"),
+            (ConfigKey::LogAggregation, "best"),
+            (ConfigKey::PingHost, "example.com"),
+            (ConfigKey::LedStripDuration, "59"),
+            (ConfigKey::LedCount, "301"),
+            (ConfigKey::PowerBudget, "10001"),
+            (ConfigKey::ColourScale, "cubic"),
+            (ConfigKey::ColourStops, "10:green"),
+            (ConfigKey::BaselineWindow, "0"),
+            (ConfigKey::AutoCalibrate, "auto"),
+        ] {
+            assert_eq!(
+                cfg.set(key, value),
+                Err(ConfigError::InvalidValue(key, value.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn set_all_is_atomic() {
+        let mut cfg = Config::default();
+        let result = cfg.set_all(&[("led_count", "16"), ("led_brightness", "-1")]);
+        assert!(result.is_err());
+        assert_eq!(cfg.led_count, Config::default().led_count);
+
+        assert_eq!(
+            cfg.set_all(&[("colour", "red")]),
+            Err(ConfigError::UnknownKey("colour".to_string()))
+        );
+
+        cfg.set_all(&[("led_count", "16")]).expect("valid");
+        assert_eq!(cfg.led_count, 16);
+    }
+
+    #[test]
+    fn layout_must_leave_an_led() {
+        let mut cfg = Config {
+            led_count: 10,
+            ..Default::default()
+        };
+        cfg.set(ConfigKey::LedOffset, "9").expect("one LED left");
+        assert_eq!(cfg.shown_led_count(), 1);
+        assert_eq!(
+            cfg.set(ConfigKey::LedSkip, "0"),
+            Err(ConfigError::InvalidValue(
+                ConfigKey::LedSkip,
+                "0".to_string()
+            ))
+        );
+        assert!(cfg.set(ConfigKey::LedCount, "9").is_err());
+        assert_eq!((cfg.led_count, cfg.layout.skipped.len()), (10, 0));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::diagnostics::ResetReason;
+use std::fmt;
+use std::time::Duration;
+
+/// Longest message we keep, so a report always fits in one NVS string
+pub const MAX_MESSAGE_LEN: usize = 480;
+/// Home Assistant ignores sensor states longer than this
+const MAX_SUMMARY_LEN: usize = 255;
+/// Start in safe mode after this many boots in a row which restarted
+/// before becoming stable
+pub const CRASH_LOOP_BOOTS: u32 = 3;
+/// How long a boot has to stay up before it no longer counts towards a
+/// crash loop
+pub const STABLE_UPTIME: Duration = Duration::from_secs(5 * 60);
+
+/// How many unstable boots came before this one, given the count saved by
+/// the previous boot. Being unplugged isn't a crash, so a power-on reset
+/// starts the count again.
+pub fn previous_unstable_boots(saved: u32, reason: ResetReason) -> u32 {
+    match reason {
+        ResetReason::PowerOn => 0,
+        _ => saved,
+    }
+}
+
+/// Whether we are stuck restarting, and should start in safe mode
+pub fn is_crash_loop(previous_unstable_boots: u32) -> bool {
+    previous_unstable_boots >= CRASH_LOOP_BOOTS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashKind {
+    Panic,
+    /// An error which made us restart
+    Error,
+}
+
+impl fmt::Display for CrashKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrashKind::Panic => write!(f, "panic"),
+            CrashKind::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Why the previous run ended, saved just before restarting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    pub kind: CrashKind,
+    pub message: String,
+    /// Source location, for panics
+    pub location: Option<String>,
+    /// Which boot (see the boot counter) crashed
+    pub boot: u32,
+    /// How long that boot had been running
+    pub uptime: Duration,
+}
+
+impl CrashReport {
+    pub fn panic(message: &str, location: Option<String>, boot: u32, uptime: Duration) -> Self {
+        Self {
+            kind: CrashKind::Panic,
+            message: truncate(message, MAX_MESSAGE_LEN),
+            location,
+            boot,
+            uptime,
+        }
+    }
+
+    /// An error, from the outermost context to the root cause (eg from
+    /// `anyhow::Error::chain()`)
+    pub fn error<S: AsRef<str>>(chain: &[S], boot: u32, uptime: Duration) -> Self {
+        let message = chain
+            .iter()
+            .map(|e| e.as_ref())
+            .collect::<Vec<_>>()
+            .join(": ");
+        Self {
+            kind: CrashKind::Error,
+            message: truncate(&message, MAX_MESSAGE_LEN),
+            location: None,
+            boot,
+            uptime,
+        }
+    }
+
+    /// One line, short enough for a sensor state
+    pub fn summary(&self) -> String {
+        let summary = match &self.location {
+            Some(location) => format!(
+                "boot {}: {} at {}: {}",
+                self.boot, self.kind, location, self.message
+            ),
+            None => format!("boot {}: {}: {}", self.boot, self.kind, self.message),
+        };
+        truncate(&summary.replace('\n', " "), MAX_SUMMARY_LEN)
+    }
+
+    /// Tab-separated fields, for storage
+    pub fn encode(&self) -> String {
+        [
+            self.kind.to_string(),
+            self.boot.to_string(),
+            self.uptime.as_secs().to_string(),
+            escape(self.location.as_deref().unwrap_or("")),
+            escape(&self.message),
+        ]
+        .join("\t")
+    }
+
+    pub fn decode(data: &str) -> Option<Self> {
+        let mut fields = data.splitn(5, '\t');
+        let kind = match fields.next()? {
+            "panic" => CrashKind::Panic,
+            "error" => CrashKind::Error,
+            _ => return None,
+        };
+        let boot = fields.next()?.parse().ok()?;
+        let uptime = Duration::from_secs(fields.next()?.parse().ok()?);
+        let location = unescape(fields.next()?);
+        let message = unescape(fields.next()?);
+        Some(Self {
+            kind,
+            message,
+            location: Some(location).filter(|l| !l.is_empty()),
+            boot,
+            uptime,
+        })
+    }
+}
+
+/// Cut a string down to at most `max` bytes, on a character boundary
+fn truncate(s: &str, max: usize) -> String {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_string()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => {}
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test_crash {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_storage() {
+        let report = CrashReport::panic(
+            "index out of bounds:\n\tlen is 3",
+            Some("src/rgb.rs:42".to_string()),
+            17,
+            Duration::from_secs(3600),
+        );
+        assert_eq!(CrashReport::decode(&report.encode()), Some(report));
+
+        let report = CrashReport::error(&["Ping failed", "timed out"], 3, Duration::ZERO);
+        assert_eq!(report.message, "Ping failed: timed out");
+        assert_eq!(CrashReport::decode(&report.encode()), Some(report));
+
+        assert_eq!(CrashReport::decode("bogus"), None);
+    }
+
+    #[test]
+    fn summary_is_one_short_line() {
+        let report = CrashReport::panic(
+            "boom\nbang",
+            Some("src/main.rs:10".to_string()),
+            5,
+            Duration::ZERO,
+        );
+        assert_eq!(
+            report.summary(),
+            "boot 5: panic at src/main.rs:10: boom bang"
+        );
+
+        let report = CrashReport::error(&["é".repeat(400)], 1, Duration::ZERO);
+        assert!(report.message.len() <= MAX_MESSAGE_LEN);
+        assert!(report.summary().len() <= MAX_SUMMARY_LEN);
+    }
+
+    #[test]
+    fn detects_crash_loops() {
+        assert!(!is_crash_loop(previous_unstable_boots(
+            0,
+            ResetReason::Panic
+        )));
+        assert!(!is_crash_loop(previous_unstable_boots(
+            2,
+            ResetReason::Software
+        )));
+        assert!(is_crash_loop(previous_unstable_boots(
+            3,
+            ResetReason::Software
+        )));
+        assert!(is_crash_loop(previous_unstable_boots(
+            5,
+            ResetReason::TaskWatchdog
+        )));
+        // Power cycling is not a crash
+        assert!(!is_crash_loop(previous_unstable_boots(
+            5,
+            ResetReason::PowerOn
+        )));
+    }
+}
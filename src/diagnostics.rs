@@ -0,0 +1,302 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Why the chip last restarted, from `esp_reset_reason()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    Unknown,
+    PowerOn,
+    External,
+    Software,
+    Panic,
+    InterruptWatchdog,
+    TaskWatchdog,
+    OtherWatchdog,
+    DeepSleep,
+    Brownout,
+    Sdio,
+    Usb,
+    Jtag,
+    Efuse,
+    PowerGlitch,
+    CpuLockup,
+}
+
+impl ResetReason {
+    /// Map an `esp_reset_reason_t` value
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            1 => ResetReason::PowerOn,
+            2 => ResetReason::External,
+            3 => ResetReason::Software,
+            4 => ResetReason::Panic,
+            5 => ResetReason::InterruptWatchdog,
+            6 => ResetReason::TaskWatchdog,
+            7 => ResetReason::OtherWatchdog,
+            8 => ResetReason::DeepSleep,
+            9 => ResetReason::Brownout,
+            10 => ResetReason::Sdio,
+            11 => ResetReason::Usb,
+            12 => ResetReason::Jtag,
+            13 => ResetReason::Efuse,
+            14 => ResetReason::PowerGlitch,
+            15 => ResetReason::CpuLockup,
+            _ => ResetReason::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResetReason::Unknown => "unknown",
+            ResetReason::PowerOn => "power on",
+            ResetReason::External => "external pin",
+            ResetReason::Software => "software restart",
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog => "interrupt watchdog",
+            ResetReason::TaskWatchdog => "task watchdog",
+            ResetReason::OtherWatchdog => "other watchdog",
+            ResetReason::DeepSleep => "deep sleep",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Sdio => "SDIO",
+            ResetReason::Usb => "USB",
+            ResetReason::Jtag => "JTAG",
+            ResetReason::Efuse => "eFuse error",
+            ResetReason::PowerGlitch => "power glitch",
+            ResetReason::CpuLockup => "CPU lockup",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How a diagnostic value is described to Home Assistant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sensor {
+    /// Used in the entity ID and the `<key>/state` topic
+    pub key: &'static str,
+    pub name: &'static str,
+    pub unit: Option<&'static str>,
+    pub device_class: Option<&'static str>,
+    pub icon: &'static str,
+}
+
+const fn sensor(
+    key: &'static str,
+    name: &'static str,
+    unit: Option<&'static str>,
+    device_class: Option<&'static str>,
+    icon: &'static str,
+) -> Sensor {
+    Sensor {
+        key,
+        name,
+        unit,
+        device_class,
+        icon,
+    }
+}
+
+/// Every diagnostic sensor, in the same order as `Diagnostics::values`
+pub const SENSORS: [Sensor; 14] = [
+    sensor(
+        "uptime",
+        "Uptime",
+        Some("s"),
+        Some("duration"),
+        "mdi:timer-outline",
+    ),
+    sensor("wifi_ssid", "WiFi SSID", None, None, "mdi:wifi"),
+    sensor(
+        "wifi_rssi",
+        "WiFi RSSI",
+        Some("dBm"),
+        Some("signal_strength"),
+        "mdi:wifi",
+    ),
+    sensor("wifi_bssid", "WiFi BSSID", None, None, "mdi:access-point"),
+    sensor("wifi_channel", "WiFi Channel", None, None, "mdi:wifi"),
+    sensor("ip_address", "IP Address", None, None, "mdi:ip-network"),
+    sensor(
+        "free_heap",
+        "Free Heap",
+        Some("B"),
+        Some("data_size"),
+        "mdi:memory",
+    ),
+    sensor(
+        "min_free_heap",
+        "Minimum Free Heap",
+        Some("B"),
+        Some("data_size"),
+        "mdi:memory",
+    ),
+    sensor(
+        "stack_high_water",
+        "Main Stack Headroom",
+        Some("B"),
+        Some("data_size"),
+        "mdi:layers",
+    ),
+    sensor(
+        "reset_reason",
+        "Last Reset Reason",
+        None,
+        None,
+        "mdi:restart-alert",
+    ),
+    sensor("boot_count", "Boot Count", None, None, "mdi:counter"),
+    sensor(
+        "last_crash",
+        "Last Crash",
+        None,
+        None,
+        "mdi:alert-circle-outline",
+    ),
+    sensor(
+        "safe_mode",
+        "Safe Mode",
+        None,
+        None,
+        "mdi:shield-alert-outline",
+    ),
+    sensor(
+        "led_current",
+        "LED Current",
+        Some("mA"),
+        Some("current"),
+        "mdi:current-dc",
+    ),
+];
+
+/// A snapshot of device health
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub uptime: Duration,
+    /// The WiFi fields are None while disconnected
+    pub wifi_ssid: Option<String>,
+    pub wifi_rssi: Option<i32>,
+    pub wifi_bssid: Option<[u8; 6]>,
+    pub wifi_channel: Option<u8>,
+    pub ip_address: Option<Ipv4Addr>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    /// Smallest amount of main task stack which has been left unused
+    pub stack_high_water: u32,
+    pub reset_reason: ResetReason,
+    pub boot_count: u32,
+    /// Summary of the most recent crash report
+    pub last_crash: Option<String>,
+    /// Whether we started in safe mode after a crash loop
+    pub safe_mode: bool,
+    /// Estimated current drawn by the strip (see `power::estimate_ma`), in
+    /// mA: the highest of the frames in the last main loop, after any
+    /// dimming, not an average. None until the main loop has drawn a frame;
+    /// only published over MQTT.
+    pub led_current: Option<u32>,
+}
+
+impl Diagnostics {
+    /// Sensor states, matching `SENSORS`; None for values we don't know
+    pub fn values(&self) -> [Option<String>; SENSORS.len()] {
+        [
+            Some(self.uptime.as_secs().to_string()),
+            self.wifi_ssid.clone(),
+            self.wifi_rssi.map(|rssi| rssi.to_string()),
+            self.wifi_bssid.map(|bssid| format_mac(&bssid)),
+            self.wifi_channel.map(|channel| channel.to_string()),
+            self.ip_address.map(|ip| ip.to_string()),
+            Some(self.free_heap.to_string()),
+            Some(self.min_free_heap.to_string()),
+            Some(self.stack_high_water.to_string()),
+            Some(self.reset_reason.to_string()),
+            Some(self.boot_count.to_string()),
+            Some(self.last_crash.clone().unwrap_or("none".to_string())),
+            Some(if self.safe_mode { "on" } else { "off" }.to_string()),
+            self.led_current.map(|ma| ma.to_string()),
+        ]
+    }
+}
+
+/// Colon-separated lower-case hex, eg "aa:bb:cc:00:11:22"
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod test_diagnostics {
+    use super::*;
+
+    #[test]
+    fn reset_reasons() {
+        assert_eq!(ResetReason::from_code(1), ResetReason::PowerOn);
+        assert_eq!(ResetReason::from_code(4).to_string(), "panic");
+        assert_eq!(ResetReason::from_code(6).to_string(), "task watchdog");
+        assert_eq!(ResetReason::from_code(99), ResetReason::Unknown);
+    }
+
+    #[test]
+    fn values_match_sensors() {
+        let diagnostics = Diagnostics {
+            uptime: Duration::from_secs(90),
+            wifi_ssid: Some("Home".to_string()),
+            wifi_rssi: Some(-61),
+            wifi_bssid: Some([0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]),
+            wifi_channel: Some(6),
+            ip_address: Some(Ipv4Addr::new(192, 168, 1, 50)),
+            free_heap: 150000,
+            min_free_heap: 120000,
+            stack_high_water: 2100,
+            reset_reason: ResetReason::Brownout,
+            boot_count: 12,
+            last_crash: None,
+            safe_mode: false,
+            led_current: Some(420),
+        };
+        let values: Vec<(&str, Option<String>)> = SENSORS
+            .iter()
+            .map(|s| s.key)
+            .zip(diagnostics.values())
+            .collect();
+        assert_eq!(values[0], ("uptime", Some("90".to_string())));
+        assert_eq!(values[1], ("wifi_ssid", Some("Home".to_string())));
+        assert_eq!(
+            values[3],
+            ("wifi_bssid", Some("aa:bb:cc:00:11:22".to_string()))
+        );
+        assert_eq!(values[5], ("ip_address", Some("192.168.1.50".to_string())));
+        assert_eq!(values[9], ("reset_reason", Some("brownout".to_string())));
+        assert_eq!(values[10], ("boot_count", Some("12".to_string())));
+        assert_eq!(values[11], ("last_crash", Some("none".to_string())));
+        assert_eq!(values[12], ("safe_mode", Some("off".to_string())));
+        assert_eq!(values[13], ("led_current", Some("420".to_string())));
+    }
+
+    #[test]
+    fn unknown_wifi_values_are_none() {
+        let diagnostics = Diagnostics {
+            uptime: Duration::ZERO,
+            wifi_ssid: None,
+            wifi_rssi: None,
+            wifi_bssid: None,
+            wifi_channel: None,
+            ip_address: None,
+            free_heap: 1,
+            min_free_heap: 1,
+            stack_high_water: 1,
+            reset_reason: ResetReason::Unknown,
+            boot_count: 1,
+            last_crash: Some("boot 1: panic: oops".to_string()),
+            safe_mode: true,
+            led_current: None,
+        };
+        let values = diagnostics.values();
+        assert_eq!(values[1..6], [None, None, None, None, None]);
+        assert_eq!(values[6], Some("1".to_string()));
+        assert_eq!(values[11], Some("boot 1: panic: oops".to_string()));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::rgb::Palette;
+use smart_leds::RGB8;
+use std::time::Duration;
+
+/// How much wall-clock time once round the dial covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialSpan {
+    Hour,
+    HalfDay,
+    Day,
+}
+
+impl DialSpan {
+    pub const ALL: [DialSpan; 3] = [DialSpan::Hour, DialSpan::HalfDay, DialSpan::Day];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DialSpan::Hour => "1h",
+            DialSpan::HalfDay => "12h",
+            DialSpan::Day => "24h",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.name() == name)
+    }
+
+    pub fn duration(&self) -> Duration {
+        let hours = match self {
+            DialSpan::Hour => 1,
+            DialSpan::HalfDay => 12,
+            DialSpan::Day => 24,
+        };
+        Duration::from_secs(hours * 60 * 60)
+    }
+}
+
+/// Pings within one slot of the dial
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlotStats {
+    /// Slowest successful ping
+    pub worst: Option<Duration>,
+    pub sent: u32,
+    pub lost: u32,
+}
+
+/// The last span of wall-clock time, split into one slot per LED, each
+/// anchored to a fixed time like the marks on a clock face: LED 0 starts
+/// at midnight (or the hour, for `DialSpan::Hour`), local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dial {
+    span: DialSpan,
+    /// Minutes ahead of UTC
+    utc_offset: i32,
+    /// Which slot each LED last counted, and what was in it
+    slots: Vec<(u64, SlotStats)>,
+}
+
+impl Dial {
+    pub fn new(span: DialSpan, led_count: usize, utc_offset: i32) -> Self {
+        Self {
+            span,
+            utc_offset,
+            slots: vec![(u64::MAX, SlotStats::default()); led_count],
+        }
+    }
+
+    /// Change the layout, dropping what was counted if it changed
+    pub fn configure(&mut self, span: DialSpan, led_count: usize, utc_offset: i32) {
+        if (span, led_count, utc_offset) != (self.span, self.slots.len(), self.utc_offset) {
+            *self = Self::new(span, led_count, utc_offset);
+        }
+    }
+
+    /// The slot `unix_time` falls in, counted from the epoch, and how far
+    /// through it (0-1)
+    fn slot(&self, unix_time: Duration) -> (u64, f32) {
+        let span = self.span.duration().as_millis();
+        let local = (unix_time.as_millis() as i128 + self.utc_offset as i128 * 60_000).max(0);
+        let scaled = local as u128 * self.slots.len() as u128;
+        let fraction = (scaled % span) as f32 / span as f32;
+        ((scaled / span) as u64, fraction)
+    }
+
+    /// Count a ping taken at `unix_time`
+    pub fn record(&mut self, unix_time: Duration, rtt: Option<Duration>) {
+        if self.slots.is_empty() {
+            return;
+        }
+        let (slot, _) = self.slot(unix_time);
+        let len = self.slots.len();
+        let (counted, stats) = &mut self.slots[(slot % len as u64) as usize];
+        if *counted != slot {
+            *counted = slot;
+            *stats = SlotStats::default();
+        }
+        stats.sent += 1;
+        match rtt {
+            Some(rtt) => stats.worst = stats.worst.max(Some(rtt)),
+            None => stats.lost += 1,
+        }
+    }
+
+    /// What was counted in each LED's slot within the last span, at
+    /// `unix_time`
+    pub fn slots(&self, unix_time: Duration) -> Vec<Option<SlotStats>> {
+        let len = self.slots.len() as u64;
+        let (now, _) = self.slot(unix_time);
+        self.slots
+            .iter()
+            .enumerate()
+            .map(|(i, (counted, stats))| {
+                // The most recent slot shown by this LED
+                let expected = now.checked_sub((now + len - i as u64) % len);
+                (Some(*counted) == expected).then_some(*stats)
+            })
+            .collect()
+    }
+
+    /// The colours for the dial at `unix_time`: each slot coloured by the
+    /// worst ping in it (or the lost colour if any were lost), dim blue for
+    /// no data, and a white hand sweeping between the current slot and the
+    /// next
+    pub fn pixels(&self, unix_time: Duration, palette: &Palette, brightness: u8) -> Vec<RGB8> {
+        let len = self.slots.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        let mut pixels: Vec<RGB8> = self
+            .slots(unix_time)
+            .iter()
+            .map(|slot| match slot {
+                Some(stats) if stats.lost > 0 => palette.colour(None, brightness),
+                Some(stats) => palette.colour(stats.worst, brightness),
+                None => RGB8::new(0, 0, brightness / 4),
+            })
+            .collect();
+        let (now, fraction) = self.slot(unix_time);
+        let hand = (now % len as u64) as usize;
+        let white = brightness / 2;
+        for (i, weight) in [(hand, 1.0 - fraction), ((hand + 1) % len, fraction)] {
+            let lerp = |c: u8| (c as f32 + (white as f32 - c as f32) * weight).round() as u8;
+            let pixel = pixels[i];
+            pixels[i] = RGB8::new(lerp(pixel.r), lerp(pixel.g), lerp(pixel.b));
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod test_dial {
+    use super::*;
+    use crate::config::Config;
+
+    /// 2024-01-01 00:00 UTC
+    const MIDNIGHT: u64 = 1_704_067_200;
+
+    fn at(hours: u64, minutes: u64) -> Duration {
+        Duration::from_secs(MIDNIGHT + hours * 3600 + minutes * 60)
+    }
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn slots_are_anchored_to_the_clock() {
+        let mut dial = Dial::new(DialSpan::Day, 24, 0);
+        dial.record(at(0, 10), ms(20));
+        dial.record(at(0, 50), ms(40));
+        dial.record(at(3, 0), None);
+        dial.record(at(3, 5), ms(10));
+        let slots = dial.slots(at(5, 30));
+        assert_eq!(
+            slots[0],
+            Some(SlotStats {
+                worst: ms(40),
+                sent: 2,
+                lost: 0
+            })
+        );
+        assert_eq!(slots[3].map(|s| s.lost), Some(1));
+        assert_eq!(slots[1], None);
+        // A day later the old slots have expired, and new ones replace them
+        dial.record(at(27, 0), ms(5));
+        let slots = dial.slots(at(27, 30));
+        assert_eq!(slots[0], None);
+        assert_eq!(slots[3].map(|s| s.sent), Some(1));
+    }
+
+    #[test]
+    fn follows_local_time() {
+        // An hour ahead of UTC: 23:30 UTC is 00:30 local
+        let mut dial = Dial::new(DialSpan::Day, 24, 60);
+        dial.record(at(23, 30), ms(20));
+        assert!(dial.slots(at(23, 45))[0].is_some());
+        // Changing the layout starts again
+        dial.configure(DialSpan::Hour, 24, 60);
+        assert_eq!(dial.slots(at(23, 45)), vec![None; 24]);
+        // 2.5 minutes per LED
+        dial.record(at(23, 6), ms(20));
+        assert!(dial.slots(at(23, 45))[2].is_some());
+    }
+
+    #[test]
+    fn hand_sweeps() {
+        let config = Config::default();
+        let palette = Palette::new(&config, None);
+        let dial = Dial::new(DialSpan::Hour, 4, 0);
+        let no_data = RGB8::new(0, 0, 50);
+        let white = RGB8::new(100, 100, 100);
+        // On the hour, the hand is on LED 0
+        assert_eq!(
+            dial.pixels(at(1, 0), &palette, 200),
+            vec![white, no_data, no_data, no_data]
+        );
+        // Halfway through the last slot, it is between LEDs 3 and 0
+        let pixels = dial.pixels(at(1, 52) + Duration::from_secs(30), &palette, 200);
+        assert_eq!(pixels[3], pixels[0]);
+        assert_eq!(pixels[3], RGB8::new(50, 50, 75));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::dial::{Dial, DialSpan};
+use crate::rollup::Rollup;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Most RTTs kept for the baseline (16 KiB). To still cover the whole
+/// window, at most one RTT is kept per `baseline_window` / this, eg one
+/// every 42 seconds for 24 hours or two minutes for 72, whatever the ping
+/// rate.
+const MAX_BASELINE_SAMPLES: usize = 2048;
+
+/// A successful RTT kept for the baseline, packed to save memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BaselineSample {
+    /// Seconds since boot
+    at: u32,
+    /// Microseconds, saturating at about 71 minutes
+    rtt: u32,
+}
+
+/// The result of a single ping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// When the sample was taken, as time since boot
+    pub at: Duration,
+    /// How long the ping took, or None for "timeout"
+    pub rtt: Option<Duration>,
+    /// Whether a latency anomaly started with this sample
+    pub anomaly: bool,
+}
+
+/// Recent ping results, newest first, plus running totals, a longer
+/// window of RTTs for the baseline, a week of rollups and, once the time is
+/// known, the dial
+#[derive(Debug, Clone)]
+pub struct History {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+    sent: u64,
+    lost: u64,
+    /// Successful RTTs within `baseline_window` of the newest, oldest first
+    baseline: VecDeque<BaselineSample>,
+    baseline_window: Duration,
+    /// Median and 90th percentile of `baseline`, updated with it
+    baseline_median: Option<Duration>,
+    baseline_p90: Option<Duration>,
+    /// Unix time when `at` was zero, once the clock has been set
+    boot_time: Option<Duration>,
+    /// The newest ping, including those taken between samples for the bar
+    /// graph
+    live: Option<Sample>,
+    /// The slowest of those pings within the bar graph's peak hold
+    peak: Option<Sample>,
+    dial: Dial,
+    rollup: Rollup,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            sent: 0,
+            lost: 0,
+            baseline: VecDeque::new(),
+            baseline_window: Duration::from_secs(24 * 60 * 60),
+            baseline_median: None,
+            baseline_p90: None,
+            boot_time: None,
+            live: None,
+            peak: None,
+            dial: Dial::new(DialSpan::Hour, capacity, 0),
+            rollup: Rollup::new(),
+        }
+    }
+
+    /// Record a new sample, dropping the oldest ones if we are over capacity
+    pub fn push(&mut self, at: Duration, rtt: Option<Duration>) {
+        let sample = Sample {
+            at,
+            rtt,
+            anomaly: false,
+        };
+        self.samples.push_front(sample);
+        self.live = Some(sample);
+        self.samples.truncate(self.capacity);
+        self.sent += 1;
+        match rtt {
+            Some(rtt) => self.push_baseline(at, rtt),
+            None => self.lost += 1,
+        }
+        self.prune_baseline(at);
+        self.update_baseline();
+        self.rollup.record(at, rtt);
+        if let Some(time) = self.wall_clock(at) {
+            self.dial.record(time, rtt);
+        }
+    }
+
+    /// Record a ping taken between samples, which only the bar graph shows;
+    /// the slowest within `hold` is kept as the peak
+    pub fn record_live(&mut self, at: Duration, rtt: Option<Duration>, hold: Duration) {
+        let sample = Sample {
+            at,
+            rtt,
+            anomaly: false,
+        };
+        self.live = Some(sample);
+        let slowness = |rtt: Option<Duration>| rtt.unwrap_or(Duration::MAX);
+        if self.peak.map_or(true, |peak| {
+            at.saturating_sub(peak.at) >= hold || slowness(rtt) >= slowness(peak.rtt)
+        }) {
+            self.peak = Some(sample);
+        }
+    }
+
+    /// The newest ping, whether or not it was kept as a sample
+    pub fn live(&self) -> Option<&Sample> {
+        self.live.as_ref()
+    }
+
+    /// The slowest ping recorded by `record_live` within its hold time
+    pub fn peak(&self) -> Option<&Sample> {
+        self.peak.as_ref()
+    }
+
+    /// Set the Unix time when `at` was zero, so samples can be placed on
+    /// the dial
+    pub fn set_boot_time(&mut self, boot_time: Duration) {
+        self.boot_time = Some(boot_time);
+    }
+
+    /// The Unix time for a time since boot, if the clock has been set
+    pub fn wall_clock(&self, at: Duration) -> Option<Duration> {
+        self.boot_time.map(|boot| boot + at)
+    }
+
+    /// Change the dial's layout (which starts it again if it changed)
+    pub fn set_dial(&mut self, span: DialSpan, led_count: usize, utc_offset: i32) {
+        self.dial.configure(span, led_count, utc_offset);
+    }
+
+    pub fn dial(&self) -> &Dial {
+        &self.dial
+    }
+
+    pub fn rollup(&self) -> &Rollup {
+        &self.rollup
+    }
+
+    fn push_baseline(&mut self, at: Duration, rtt: Duration) {
+        let at = at.as_secs().min(u32::MAX as u64) as u32;
+        let spacing = (self.baseline_window / MAX_BASELINE_SAMPLES as u32).as_secs() as u32;
+        if self
+            .baseline
+            .back()
+            .is_some_and(|last| at < last.at.saturating_add(spacing))
+        {
+            return;
+        }
+        self.baseline.push_back(BaselineSample {
+            at,
+            rtt: rtt.as_micros().min(u32::MAX as u128) as u32,
+        });
+    }
+
+    fn prune_baseline(&mut self, now: Duration) {
+        let window = self.baseline_window.as_secs();
+        while self.baseline.len() > MAX_BASELINE_SAMPLES
+            || self
+                .baseline
+                .front()
+                .is_some_and(|sample| now.as_secs().saturating_sub(sample.at as u64) > window)
+        {
+            self.baseline.pop_front();
+        }
+    }
+
+    fn update_baseline(&mut self) {
+        let mut rtts = self.baseline_rtts();
+        let middle = rtts.len() / 2;
+        self.baseline_median = select(&mut rtts, middle);
+        self.baseline_p90 = nearest_rank(&mut rtts, 90);
+    }
+
+    fn baseline_rtts(&self) -> Vec<u32> {
+        self.baseline.iter().map(|sample| sample.rtt).collect()
+    }
+
+    /// Change how far back the baseline looks
+    pub fn set_baseline_window(&mut self, window: Duration) {
+        if window == self.baseline_window {
+            return;
+        }
+        self.baseline_window = window;
+        if let Some(sample) = self.samples.front() {
+            self.prune_baseline(sample.at);
+        }
+        self.update_baseline();
+    }
+
+    /// The median RTT over the baseline window, or None if nothing has
+    /// succeeded in that time
+    pub fn baseline(&self) -> Option<Duration> {
+        self.baseline_median
+    }
+
+    /// The 90th percentile RTT over the baseline window
+    pub fn baseline_p90(&self) -> Option<Duration> {
+        self.baseline_p90
+    }
+
+    /// The RTT which `percent` of the successful pings in the baseline
+    /// window were at or under (nearest rank), or None if nothing has
+    /// succeeded in that time
+    pub fn percentile(&self, percent: u8) -> Option<Duration> {
+        nearest_rank(&mut self.baseline_rtts(), percent)
+    }
+
+    /// How many successful RTTs are in the baseline window
+    pub fn baseline_len(&self) -> usize {
+        self.baseline.len()
+    }
+
+    /// Flag the newest sample as the start of an anomaly
+    pub fn mark_anomaly(&mut self) {
+        if let Some(sample) = self.samples.front_mut() {
+            sample.anomaly = true;
+        }
+    }
+
+    /// Change how many samples are kept (eg when the LED count changes)
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.samples.truncate(capacity);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn latest(&self) -> Option<&Sample> {
+        self.samples.front()
+    }
+
+    /// Samples, newest first
+    pub fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Total pings sent since boot
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Total pings lost since boot
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Percentage of the retained samples which were lost
+    pub fn loss_percent(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let lost = self.samples.iter().filter(|s| s.rtt.is_none()).count();
+        lost as f32 * 100.0 / self.samples.len() as f32
+    }
+}
+
+fn select(rtts: &mut [u32], index: usize) -> Option<Duration> {
+    if rtts.is_empty() {
+        return None;
+    }
+    let rtt = *rtts.select_nth_unstable(index).1;
+    Some(Duration::from_micros(rtt as u64))
+}
+
+fn nearest_rank(rtts: &mut [u32], percent: u8) -> Option<Duration> {
+    let rank = (rtts.len() * percent.min(100) as usize).div_ceil(100);
+    select(rtts, rank.saturating_sub(1))
+}
+
+#[cfg(test)]
+mod test_history {
+    use super::*;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn keeps_newest_samples_first() {
+        let mut history = History::new(3);
+        for n in 1..=5 {
+            history.push(Duration::from_secs(n), ms(n));
+        }
+        let rtts: Vec<Option<Duration>> = history.iter().map(|s| s.rtt).collect();
+        assert_eq!(rtts, vec![ms(5), ms(4), ms(3)]);
+        assert_eq!(history.latest().map(|s| s.at), Some(Duration::from_secs(5)));
+        assert_eq!(history.sent(), 5);
+    }
+
+    #[test]
+    fn loss_is_over_retained_samples() {
+        let mut history = History::new(4);
+        assert_eq!(history.loss_percent(), 0.0);
+        history.push(Duration::ZERO, None);
+        history.push(Duration::ZERO, None);
+        history.push(Duration::ZERO, ms(10));
+        history.push(Duration::ZERO, ms(10));
+        assert_eq!(history.loss_percent(), 50.0);
+        history.push(Duration::ZERO, ms(10));
+        assert_eq!(history.loss_percent(), 25.0);
+        assert_eq!(history.lost(), 2);
+    }
+
+    #[test]
+    fn baseline_is_recent_median() {
+        let mut history = History::new(2);
+        assert_eq!(history.baseline(), None);
+        history.set_baseline_window(Duration::from_secs(60 * 60));
+        for (minute, rtt) in [(0, 500), (10, 20), (20, 30), (30, 10), (40, 40)] {
+            history.push(Duration::from_secs(minute * 60), ms(rtt));
+        }
+        history.push(Duration::from_secs(45 * 60), None);
+        // Covers more than the retained samples, and ignores losses
+        assert_eq!(history.baseline(), ms(30));
+        // The 500ms sample drops out of the window
+        history.push(Duration::from_secs(70 * 60), ms(15));
+        assert_eq!(history.baseline(), ms(20));
+        history.set_baseline_window(Duration::from_secs(30 * 60));
+        assert_eq!(history.baseline(), ms(40));
+    }
+
+    #[test]
+    fn baseline_thins_fast_pings_to_cover_the_window() {
+        let mut history = History::new(1);
+        // One a second, with the default 24 hour window keeping one every
+        // 42 seconds
+        for second in 0..10_000 {
+            history.push(Duration::from_secs(second), ms(10));
+        }
+        assert_eq!(history.baseline_len(), 239);
+        assert_eq!(history.baseline(), ms(10));
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let mut history = History::new(1);
+        assert_eq!(history.percentile(95), None);
+        for (minute, rtt) in (1..=20).rev().enumerate() {
+            history.push(Duration::from_secs(minute as u64 * 60), ms(rtt));
+        }
+        assert_eq!(history.baseline_len(), 20);
+        assert_eq!(history.percentile(0), ms(1));
+        assert_eq!(history.percentile(50), ms(10));
+        assert_eq!(history.percentile(95), ms(19));
+        assert_eq!(history.percentile(100), ms(20));
+        assert_eq!(history.baseline_p90(), ms(18));
+    }
+
+    #[test]
+    fn feeds_dial_once_time_is_known() {
+        let mut history = History::new(4);
+        history.push(Duration::from_secs(10), ms(10));
+        assert_eq!(history.wall_clock(Duration::from_secs(10)), None);
+        // 2024-01-01 00:00 UTC at boot
+        let boot = Duration::from_secs(1_704_067_200);
+        history.set_boot_time(boot);
+        history.push(Duration::from_secs(20), ms(20));
+        let slots = history.dial().slots(boot + Duration::from_secs(30));
+        assert_eq!(slots[0].map(|s| (s.sent, s.worst)), Some((1, ms(20))));
+    }
+
+    #[test]
+    fn shrinking_drops_oldest() {
+        let mut history = History::new(4);
+        for n in 1..=4 {
+            history.push(Duration::from_secs(n), ms(n));
+        }
+        history.set_capacity(2);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.iter().last().map(|s| s.rtt), Some(ms(3)));
+    }
+}
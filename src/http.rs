@@ -0,0 +1,228 @@
+use esp_idf_svc::{
+    http::{
+        server::{Configuration as HttpConfiguration, EspHttpConnection, EspHttpServer, Request},
+        Method,
+    },
+    io::Write,
+};
+use esp_ping_leds::{
+    config::{Config, ConfigKey},
+    history::History,
+    metrics::{self, DeviceMetrics, ProbeMetrics},
+    render, web,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::storage::Storage;
+use crate::system;
+
+/// Longest request body we will accept
+const MAX_BODY_LEN: usize = 1024;
+
+/// Start the HTTP server with the status page and JSON API:
+///
+/// * `GET /` - status page
+/// * `GET /api/status` - latest sample, loss and baseline
+/// * `GET /api/history` - retained samples, newest first
+/// * `GET /api/config` - current settings
+/// * `PUT /api/config` - change one or more settings
+/// * `GET /metrics` - Prometheus metrics
+pub fn start_server(
+    config: Arc<Mutex<Config>>,
+    history: Arc<Mutex<History>>,
+    probe_metrics: Arc<Mutex<ProbeMetrics>>,
+    mqtt_connected: Option<Arc<AtomicBool>>,
+    publish_pending: Arc<Mutex<bool>>,
+    storage: Arc<Mutex<Storage>>,
+    boot: Instant,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        stack_size: 10240,
+        ..Default::default()
+    })?;
+
+    let (cfg, hist) = (config.clone(), history.clone());
+    server.fn_handler::<anyhow::Error, _>("/", Method::Get, move |req| {
+        let cfg = lock(&cfg)?.clone();
+        let page = {
+            let hist = lock(&hist)?;
+            // Full brightness, the real strip is much easier to see than a screen
+            let pixels = render::render(&hist, &cfg, u8::MAX, boot.elapsed());
+            web::render_status_page(&hist, &cfg, &pixels, boot.elapsed())
+        };
+        req.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?
+            .write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+
+    let (cfg, hist) = (config.clone(), history.clone());
+    server.fn_handler::<anyhow::Error, _>("/api/status", Method::Get, move |req| {
+        let ping_host = lock(&cfg)?.ping_host.clone();
+        let hist = lock(&hist)?;
+        let now = boot.elapsed();
+        let latest = hist.latest();
+        json_response(
+            req,
+            200,
+            serde_json::json!({
+                "ping_host": ping_host,
+                "rtt_ms": latest.and_then(|s| s.rtt).map(|rtt| rtt.as_millis() as u64),
+                "last_sample_age_s": latest.map(|s| now.saturating_sub(s.at).as_secs()),
+                "loss_percent": hist.loss_percent(),
+                "samples": hist.len(),
+                "sent": hist.sent(),
+                "lost": hist.lost(),
+                "baseline_ms": hist.baseline().map(|rtt| rtt.as_millis() as u64),
+                "baseline_p90_ms": hist.baseline_p90().map(|rtt| rtt.as_millis() as u64),
+                "uptime_s": now.as_secs(),
+            }),
+        )
+    })?;
+
+    let hist = history.clone();
+    server.fn_handler::<anyhow::Error, _>("/api/history", Method::Get, move |req| {
+        let hist = lock(&hist)?;
+        let now = boot.elapsed();
+        let samples: Vec<serde_json::Value> = hist
+            .iter()
+            .map(|s| {
+                serde_json::json!({
+                    "age_s": now.saturating_sub(s.at).as_secs(),
+                    "rtt_ms": s.rtt.map(|rtt| rtt.as_millis() as u64),
+                    "anomaly": s.anomaly,
+                })
+            })
+            .collect();
+        json_response(req, 200, serde_json::Value::Array(samples))
+    })?;
+
+    let cfg = config.clone();
+    server.fn_handler::<anyhow::Error, _>("/api/config", Method::Get, move |req| {
+        let json = config_json(&lock(&cfg)?);
+        json_response(req, 200, json)
+    })?;
+
+    let cfg = config.clone();
+    server.fn_handler::<anyhow::Error, _>("/api/config", Method::Put, move |mut req| {
+        let body = match read_body(&mut req)? {
+            Some(body) => body,
+            None => {
+                return json_response(
+                    req,
+                    413,
+                    serde_json::json!({"error": "request body too large"}),
+                )
+            }
+        };
+        let values = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .into_iter()
+                .map(|(name, value)| match value {
+                    serde_json::Value::String(s) => Ok((name, s)),
+                    serde_json::Value::Number(n) => Ok((name, n.to_string())),
+                    serde_json::Value::Bool(b) => Ok((name, b.to_string())),
+                    other => Err(format!("invalid {} {}", name, other)),
+                })
+                .collect::<Result<Vec<(String, String)>, String>>(),
+            Ok(_) => Err("expected a JSON object".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let values = match values {
+            Ok(values) => values,
+            Err(e) => return json_response(req, 400, serde_json::json!({ "error": e })),
+        };
+        let mut config = lock(&cfg)?;
+        let updated = lock(&storage)?.update_config(&mut config, &values);
+        match updated {
+            Ok(_) => {
+                log::info!("Config updated over HTTP: {:?}", values);
+                *lock(&publish_pending)? = true;
+                let json = config_json(&config);
+                drop(config);
+                json_response(req, 200, json)
+            }
+            Err(e) => {
+                drop(config);
+                json_response(req, 400, serde_json::json!({ "error": e.to_string() }))
+            }
+        }
+    })?;
+
+    let cfg = config.clone();
+    server.fn_handler::<anyhow::Error, _>("/metrics", Method::Get, move |req| {
+        let device = DeviceMetrics {
+            uptime: boot.elapsed(),
+            wifi_rssi: system::wifi_rssi(),
+            free_heap: system::free_heap(),
+            mqtt_connected: mqtt_connected.as_ref().map(|c| c.load(Ordering::Relaxed)),
+        };
+        let cfg = lock(&cfg)?.clone();
+        let text = metrics::render(&lock(&probe_metrics)?, &device, &cfg);
+        req.into_response(
+            200,
+            None,
+            &[("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+        )?
+        .write_all(text.as_bytes())?;
+        Ok(())
+    })?;
+
+    log::info!("HTTP server started");
+    Ok(server)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> anyhow::Result<std::sync::MutexGuard<'_, T>> {
+    mutex.lock().map_err(|_| anyhow::anyhow!("Lock poisoned"))
+}
+
+/// Settings as a JSON object, with numbers and booleans as native JSON types
+fn config_json(config: &Config) -> serde_json::Value {
+    let mut json = serde_json::Map::new();
+    for key in ConfigKey::ALL {
+        let value = config.get(key);
+        let value = if key.range().is_some() {
+            value
+                .parse::<u64>()
+                .map(serde_json::Value::from)
+                .unwrap_or(serde_json::Value::String(value))
+        } else if let Ok(value) = value.parse::<bool>() {
+            serde_json::Value::Bool(value)
+        } else {
+            serde_json::Value::String(value)
+        };
+        json.insert(key.name().to_string(), value);
+    }
+    serde_json::Value::Object(json)
+}
+
+/// Read the whole request body, or None if it is too large
+fn read_body(req: &mut Request<&mut EspHttpConnection<'_>>) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut body = vec![];
+    let mut buf = [0u8; 256];
+    loop {
+        let n = req.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Some(body));
+        }
+        body.extend_from_slice(&buf[..n]);
+        if body.len() > MAX_BODY_LEN {
+            return Ok(None);
+        }
+    }
+}
+
+fn json_response(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    json: serde_json::Value,
+) -> anyhow::Result<()> {
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(json.to_string().as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use crate::config::MAX_LED_COUNT;
+use smart_leds::RGB8;
+use std::fmt;
+
+/// Most segments a layout can have
+pub const MAX_SEGMENTS: usize = 8;
+/// Most pixels a layout can skip
+pub const MAX_SKIPPED: usize = 32;
+/// Physical indices (after the offset) must be below this
+pub const MAX_INDEX: usize = MAX_LED_COUNT as usize;
+
+/// A named run of physical pixels, from `first` to `last` inclusive; the
+/// run goes backwards along the strip if `last` is before `first`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Segment {
+    fn indices(&self) -> impl Iterator<Item = usize> {
+        let (first, last) = (self.first, self.last);
+        (first.min(last)..=first.max(last)).map(
+            move |i| {
+                if first <= last {
+                    i
+                } else {
+                    first + last - i
+                }
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Not a number, or not `name:first-last`
+    Syntax(String),
+    TooMany(usize),
+    IndexTooLarge(usize),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutError::Syntax(s) => write!(f, "can't parse {:?}", s),
+            LayoutError::TooMany(n) => write!(f, "too many entries ({})", n),
+            LayoutError::IndexTooLarge(i) => {
+                write!(f, "index {} is too large (max {})", i, MAX_INDEX - 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+fn index(s: &str) -> Result<usize, LayoutError> {
+    let i = s
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| LayoutError::Syntax(s.to_string()))?;
+    if i >= MAX_INDEX {
+        return Err(LayoutError::IndexTooLarge(i));
+    }
+    Ok(i)
+}
+
+/// Parse a comma separated list of pixels to skip, eg "5,6"
+pub fn parse_skipped(s: &str) -> Result<Vec<usize>, LayoutError> {
+    let skipped = s
+        .split(',')
+        .filter(|i| !i.trim().is_empty())
+        .map(index)
+        .collect::<Result<Vec<_>, _>>()?;
+    if skipped.len() > MAX_SKIPPED {
+        return Err(LayoutError::TooMany(skipped.len()));
+    }
+    Ok(skipped)
+}
+
+pub fn format_skipped(skipped: &[usize]) -> String {
+    skipped
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a comma separated list of segments, eg "front:0-11,back:23-12"
+pub fn parse_segments(s: &str) -> Result<Vec<Segment>, LayoutError> {
+    let segments = s
+        .split(',')
+        .filter(|segment| !segment.trim().is_empty())
+        .map(|segment| {
+            let syntax = || LayoutError::Syntax(segment.to_string());
+            let (name, range) = segment.split_once(':').ok_or_else(syntax)?;
+            let (first, last) = range.split_once('-').ok_or_else(syntax)?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(syntax());
+            }
+            Ok(Segment {
+                name: name.to_string(),
+                first: index(first)?,
+                last: index(last)?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if segments.len() > MAX_SEGMENTS {
+        return Err(LayoutError::TooMany(segments.len()));
+    }
+    Ok(segments)
+}
+
+pub fn format_segments(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .map(|s| format!("{}:{}-{}", s.name, s.first, s.last))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// How the strip is wired: which physical pixel shows each logical one
+/// (LED 0 being the newest sample, for the history)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    /// Physical pixels before the first one used; the other indices count
+    /// from here
+    pub offset: usize,
+    /// Run the logical pixels the other way
+    pub reverse: bool,
+    /// Where logical pixel 0 goes, as a count along the pixels in use, eg
+    /// to start a ring at 3 o'clock
+    pub rotation: usize,
+    /// Physical pixels to leave dark, eg behind dividers
+    pub skipped: Vec<usize>,
+    /// Runs of physical pixels to use, in order; the whole strip if empty
+    pub segments: Vec<Segment>,
+}
+
+impl Layout {
+    /// Where each logical pixel goes on a strip of `led_count` physical
+    /// pixels. The offset and skipped pixels come out of the strip, and
+    /// segment pixels past its end are left out, so the layout never
+    /// reaches past the end and fewer logical pixels may be shown.
+    pub fn mapping(&self, led_count: usize) -> Mapping {
+        let on_strip = led_count.saturating_sub(self.offset);
+        let in_use = |i: &usize| *i < on_strip && !self.skipped.contains(i);
+        let mut physical: Vec<usize> = if self.segments.is_empty() {
+            (0..on_strip).filter(in_use).collect()
+        } else {
+            self.segments
+                .iter()
+                .flat_map(|s| s.indices())
+                .filter(in_use)
+                .take(led_count)
+                .collect()
+        };
+        if self.reverse {
+            physical.reverse();
+        }
+        if !physical.is_empty() {
+            let rotation = self.rotation % physical.len();
+            physical.rotate_left(rotation);
+        }
+        for i in &mut physical {
+            *i += self.offset;
+        }
+        Mapping { physical }
+    }
+}
+
+/// The physical index for each logical pixel, from `Layout::mapping`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    physical: Vec<usize>,
+}
+
+impl Mapping {
+    /// Number of physical pixels to write, up to the last one used
+    pub fn len(&self) -> usize {
+        self.physical.iter().max().map_or(0, |i| i + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.physical.is_empty()
+    }
+
+    /// Number of logical pixels shown
+    pub fn pixels(&self) -> usize {
+        self.physical.len()
+    }
+
+    /// The values to write to the strip for `pixels`, with the unused
+    /// pixels dark
+    pub fn apply(&self, pixels: &[RGB8]) -> Vec<RGB8> {
+        let mut out = vec![RGB8::default(); self.len()];
+        for (i, pixel) in self.physical.iter().zip(pixels) {
+            out[*i] = *pixel;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test_layout {
+    use super::*;
+
+    fn grey(n: u8) -> RGB8 {
+        RGB8::new(n, n, n)
+    }
+
+    #[test]
+    fn default_is_the_strip_in_order() {
+        let pixels: Vec<RGB8> = (1..=4).map(grey).collect();
+        let mapping = Layout::default().mapping(4);
+        assert_eq!(mapping.len(), 4);
+        assert_eq!(mapping.apply(&pixels), pixels);
+    }
+
+    #[test]
+    fn offsets_reverses_rotates_and_skips() {
+        let pixels: Vec<RGB8> = (1..=4).map(grey).collect();
+        let off = RGB8::default();
+        let layout = Layout {
+            offset: 1,
+            reverse: true,
+            skipped: vec![2],
+            ..Default::default()
+        };
+        assert_eq!(
+            layout.mapping(6).apply(&pixels),
+            vec![off, grey(4), grey(3), off, grey(2), grey(1)]
+        );
+        // A ring of 4 starting one along
+        let layout = Layout {
+            rotation: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            layout.mapping(4).apply(&pixels),
+            vec![grey(4), grey(1), grey(2), grey(3)]
+        );
+    }
+
+    #[test]
+    fn segments_are_followed_in_order() {
+        let segments = parse_segments("top:0-2, bottom:6-4").expect("valid");
+        assert_eq!(format_segments(&segments), "top:0-2,bottom:6-4");
+        let layout = Layout {
+            segments,
+            skipped: vec![5],
+            ..Default::default()
+        };
+        let pixels: Vec<RGB8> = (1..=6).map(grey).collect();
+        let off = RGB8::default();
+        // Only 5 fit, so the last is dropped
+        assert_eq!(
+            layout.mapping(7).apply(&pixels),
+            vec![grey(1), grey(2), grey(3), off, grey(5), off, grey(4)]
+        );
+
+        assert_eq!(parse_skipped(" 5, 6"), Ok(vec![5, 6]));
+        assert_eq!(parse_skipped(""), Ok(vec![]));
+        assert_eq!(
+            parse_skipped("1,x"),
+            Err(LayoutError::Syntax("x".to_string()))
+        );
+        assert_eq!(
+            parse_segments(":0-1"),
+            Err(LayoutError::Syntax(":0-1".to_string()))
+        );
+        assert_eq!(
+            parse_segments("a:0-300"),
+            Err(LayoutError::IndexTooLarge(300))
+        );
+    }
+
+    #[test]
+    fn stays_within_the_strip() {
+        let layout = Layout {
+            offset: 1,
+            skipped: vec![0],
+            ..Default::default()
+        };
+        let mapping = layout.mapping(4);
+        assert_eq!((mapping.len(), mapping.pixels()), (4, 2));
+        let layout = Layout {
+            segments: parse_segments("a:0-299").expect("valid"),
+            ..Default::default()
+        };
+        assert_eq!(layout.mapping(24).len(), 24);
+        let layout = Layout {
+            offset: 24,
+            ..Default::default()
+        };
+        assert!(layout.mapping(24).is_empty());
+    }
+}
@@ -1,2 +1,25 @@
 // Library containing platform-independent code that can be tested on any architecture
+pub mod animation;
+pub mod blink;
+pub mod calibration;
+pub mod colour;
+pub mod config;
+pub mod crash;
+pub mod diagnostics;
+pub mod dial;
+pub mod history;
+pub mod layout;
+pub mod matrix;
+pub mod metrics;
+pub mod ota;
+pub mod output;
+pub mod power;
+pub mod provisioning;
+pub mod render;
 pub mod rgb;
+pub mod rollup;
+pub mod stats;
+pub mod status;
+pub mod text;
+pub mod web;
+pub mod wifi;
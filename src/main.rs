@@ -1,25 +1,44 @@
-mod config;
+mod http;
 mod mqtt;
 mod network;
-mod rgb;
+mod portal;
+mod restart;
+mod storage;
+mod system;
+mod updater;
 
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{delay::FreeRtos, peripherals::Peripherals},
     ipv4::Ipv4Addr,
     nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
     wifi::{BlockingWifi, EspWifi},
 };
 use smart_leds::SmartLedsWrite;
 use smart_leds::RGB;
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
 };
 use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
 
-use config::Config;
+use esp_ping_leds::{
+    animation::{Animator, Effect},
+    blink::{self, BootFailure},
+    calibration::Calibrator,
+    config::Config,
+    history::History,
+    metrics::ProbeMetrics,
+    output::Output,
+    power,
+    render::{self, Mode},
+    stats::{AnomalyDetector, AnomalyEvent},
+    status::{self, DeviceStatus},
+    text::TextLayer,
+    wifi::WifiNetwork,
+};
+use storage::Storage;
 
 #[derive(Debug, Clone, Copy)]
 pub enum BootStage {
@@ -29,12 +48,21 @@ pub enum BootStage {
     WifiDhcp = 4,
     WifiPrintInfo = 5,
     WifiComplete = 6,
+    /// Not a stage as such: we are in a crash loop and started in safe mode
+    SafeMode = 7,
 }
 
 const WIFI_SSID: Option<&str> = std::option_env!("WIFI_SSID");
 const WIFI_PASS: Option<&str> = std::option_env!("WIFI_PASS");
 const PING_HOST: Option<&str> = std::option_env!("PING_HOST");
-const RESTART_SECONDS: u32 = 3;
+/// Start the provisioning portal after this many boots in a row without WiFi
+const WIFI_FAILURES_BEFORE_PORTAL: u32 = 3;
+/// How often the strip is refreshed while dithering or animating, fast
+/// enough that the flicker between levels isn't visible
+const FRAME_RATE: u32 = 50;
+/// Shortest ping timeout; the timeout otherwise follows the max healthy
+/// duration, which auto-calibration can shrink until real RTTs time out
+const MIN_PING_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches
@@ -45,59 +73,158 @@ fn main() -> anyhow::Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    log::info!("Root startup...");
+    log::info!("Root startup, firmware {}...", system::sw_version());
     log::info!("Get peripherals");
+    let boot = Instant::now();
     let peripherals = Peripherals::take()?;
     log::info!("Get system event loop");
     let sysloop = EspSystemEventLoop::take()?;
     log::info!("Get NVS partition");
     let nvs = EspDefaultNvsPartition::take()?;
+    log::info!("Open settings storage");
+    let storage = Arc::new(Mutex::new(Storage::new(nvs.clone())?));
+    match storage
+        .lock()
+        .expect("Failed to lock storage")
+        .increment_boot_count()
+    {
+        Ok(boot_count) => log::info!("Boot #{}", boot_count),
+        Err(e) => log::warn!("Failed to update boot count: {}", e),
+    }
+    if let Err(e) = restart::install_panic_hook(nvs.clone()) {
+        log::warn!("Failed to install panic hook: {}", e);
+    }
 
     log::info!("Allocate wifi");
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs))?,
-        sysloop,
+        sysloop.clone(),
     )?;
-    let wifi_ssid = WIFI_SSID.unwrap_or("Wokwi-GUEST");
-    let wifi_pass = WIFI_PASS.unwrap_or("");
+    let networks = known_networks(&storage.lock().expect("Failed to lock storage"));
+
+    let ota_unverified = updater::running_image_unverified();
+    if ota_unverified {
+        log::info!("Running a new firmware image, will confirm once the network works");
+    }
 
     log::info!("LED setup");
     // gpio6 for C3 mini, wokwi, esp32-c3-devkit-rust-1
     // gpio13 for ESP-WROOM-32
-    let mut ws2812 = Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio6)?;
+    let mut ws2812 = match Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio6) {
+        Ok(ws2812) => ws2812,
+        Err(e) => {
+            // Nothing to show the blink code on, but it still goes in the log
+            let failure = BootFailure::LedDriver;
+            log::error!("Boot failure, {}", failure);
+            restart::restart_after_error(&storage, &anyhow::Error::new(e).context(failure));
+        }
+    };
     log::info!("LED boot debug lights");
     debug_lights(&mut ws2812, BootStage::Startup)?;
 
-    match network::connect_wifi(&mut ws2812, &mut wifi, wifi_ssid, wifi_pass) {
-        Ok(_) => log::info!("Wifi ok"),
-        Err(e) => {
-            log::error!("Wifi connection failed: {}", e);
-            log::info!("Restarting in {}s...", RESTART_SECONDS);
-            FreeRtos::delay_ms(RESTART_SECONDS * 1000);
-            unsafe { esp_idf_svc::sys::esp_restart() };
+    let wifi_failures = storage
+        .lock()
+        .expect("Failed to lock storage")
+        .wifi_failures()
+        .unwrap_or(0);
+    if networks.is_empty() || wifi_failures >= WIFI_FAILURES_BEFORE_PORTAL {
+        log::info!(
+            "{} known networks, {} failed boots, starting provisioning portal",
+            networks.len(),
+            wifi_failures
+        );
+        if let Err(e) = storage
+            .lock()
+            .expect("Failed to lock storage")
+            .set_wifi_failures(0)
+        {
+            log::warn!("Failed to reset WiFi failure count: {}", e);
         }
+        if let Err(e) = portal::run_portal(&mut ws2812, &mut wifi, Arc::clone(&storage)) {
+            log::error!("Provisioning portal failed: {}", e);
+        }
+        restart::restart();
     }
-    let ping_host_str = if let Some(ping_host) = PING_HOST {
-        ping_host.to_string()
-    } else {
-        wifi.wifi()
-            .sta_netif()
-            .get_ip_info()?
-            .subnet
-            .gateway
-            .to_string()
+
+    match network::connect_wifi(&mut ws2812, &mut wifi, &sysloop, &networks) {
+        Ok(ssid) => {
+            log::info!("Wifi ok, connected to {}", ssid);
+            if wifi_failures > 0 {
+                if let Err(e) = storage
+                    .lock()
+                    .expect("Failed to lock storage")
+                    .set_wifi_failures(0)
+                {
+                    log::warn!("Failed to reset WiFi failure count: {}", e);
+                }
+            }
+        }
+        Err(e) => {
+            if ota_unverified {
+                let e = updater::rollback();
+                log::error!("Rollback failed: {}", e);
+            }
+            if let Err(e) = storage
+                .lock()
+                .expect("Failed to lock storage")
+                .set_wifi_failures(wifi_failures + 1)
+            {
+                log::warn!("Failed to record WiFi failure: {}", e);
+            }
+            if let Some(failure) = e.downcast_ref::<BootFailure>() {
+                show_failure(&mut ws2812, *failure);
+            }
+            restart::restart_after_error(&storage, &e.context("WiFi connection failed"));
+        }
     };
+    // Only boots which get this far count towards a crash loop, so a WiFi
+    // outage doesn't put us into safe mode
+    let health = restart::BootHealth::start(Arc::clone(&storage), ota_unverified);
+    let gateway = wifi
+        .wifi()
+        .sta_netif()
+        .get_ip_info()?
+        .subnet
+        .gateway
+        .to_string();
+
+    // Sets the clock for the dial; kept alive for as long as we run
+    let _sntp = EspSntp::new_default()
+        .inspect_err(|e| log::warn!("Failed to start SNTP, the dial won't show: {}", e))
+        .ok();
 
     log::info!("Creating config...");
-    let config = Config::new_shared(
-        Duration::from_millis(10),    // min_healthy_duration
-        Duration::from_millis(100),   // max_healthy_duration
-        127,                          // led_brightness
-        true,                         // led_enabled
-        ping_host_str,                // ping_host
-        Duration::from_secs(30 * 60), // led_strip_duration (30 minutes)
-        24,                           // led_count
-    );
+    let config = if health.safe_mode() {
+        log::warn!("Crash loop detected, starting in safe mode with the default config");
+        debug_lights(&mut ws2812, BootStage::SafeMode)?;
+        storage
+            .lock()
+            .expect("Failed to lock storage")
+            .set_safe_mode(true);
+        Arc::new(Mutex::new(Config {
+            ping_host: gateway,
+            ..Config::default()
+        }))
+    } else {
+        let ping_host_str = PING_HOST
+            .map(|ping_host| ping_host.to_string())
+            .unwrap_or(gateway);
+        let config = Config::new_shared(
+            Duration::from_millis(10),    // min_healthy_duration
+            Duration::from_millis(100),   // max_healthy_duration
+            127,                          // led_brightness
+            true,                         // led_enabled
+            ping_host_str,                // ping_host
+            Duration::from_secs(30 * 60), // led_strip_duration (30 minutes)
+            24,                           // led_count
+        );
+        // Settings changed over MQTT, HTTP or the portal take precedence
+        storage
+            .lock()
+            .expect("Failed to lock storage")
+            .load_config(&mut config.lock().expect("Failed to lock config"));
+        config
+    };
 
     log::info!("Starting MQTT manager...");
     // Get MAC address from wifi interface
@@ -111,22 +238,80 @@ fn main() -> anyhow::Result<()> {
         mac_address[4],
         mac_address[5]
     );
-    let mqtt = match mqtt::MqttManager::new(Arc::clone(&config), mac_address) {
-        Ok(m) => m,
+    // Flag to signal when state should be published
+    let publish_pending = Arc::new(Mutex::new(false));
+    let mqtt = match mqtt::MqttManager::new(
+        Arc::clone(&config),
+        mac_address,
+        Arc::clone(&storage),
+        Arc::clone(&publish_pending),
+        health.safe_mode(),
+    ) {
+        Ok(m) => {
+            // Still retried, with a growing delay, from the main loop
+            if let Some(failure) = m.as_ref().and_then(|m| m.auth_failure()) {
+                show_failure(&mut ws2812, failure);
+            }
+            m
+        }
         Err(e) => {
-            log::error!("Failed to start MQTT manager: {}", e);
+            log::error!("Failed to start MQTT manager: {:#}", e);
+            if let Some(failure) = e.downcast_ref::<BootFailure>() {
+                show_failure(&mut ws2812, *failure);
+            }
             None
         }
     };
 
-    match main_loop(config, ws2812, mqtt) {
-        Ok(_) => unreachable!(),
+    let history = Arc::new(Mutex::new(History::new(
+        config.lock().expect("Failed to lock config").led_count as usize,
+    )));
+    let probe_metrics = Arc::new(Mutex::new(ProbeMetrics::default()));
+
+    log::info!("Starting HTTP server...");
+    // Keep the server alive for as long as the main loop runs
+    let _http = match http::start_server(
+        Arc::clone(&config),
+        Arc::clone(&history),
+        Arc::clone(&probe_metrics),
+        mqtt.as_ref().map(|m| m.connected()),
+        publish_pending,
+        Arc::clone(&storage),
+        boot,
+    ) {
+        Ok(server) => Some(server),
         Err(e) => {
-            log::error!("Major Error: {}", e);
-            log::info!("Restarting in {}s...", RESTART_SECONDS);
-            FreeRtos::delay_ms(RESTART_SECONDS * 1000);
-            unsafe { esp_idf_svc::sys::esp_restart() };
+            log::error!("Failed to start HTTP server: {}", e);
+            None
         }
+    };
+
+    let result = if health.safe_mode() {
+        safe_mode_loop(ws2812, mqtt, health)
+    } else {
+        main_loop(config, history, probe_metrics, boot, ws2812, mqtt, health)
+    };
+    match result {
+        Ok(_) => unreachable!(),
+        Err(e) => restart::restart_after_error(&storage, &e),
+    }
+}
+
+/// WiFi networks stored in NVS, or the compile-time credentials if there are
+/// none; empty if we have neither
+fn known_networks(storage: &Storage) -> Vec<WifiNetwork> {
+    match storage.wifi_networks() {
+        Ok(networks) if !networks.is_empty() => return networks,
+        Ok(_) => log::info!("No stored WiFi networks"),
+        Err(e) => log::warn!("Failed to load stored WiFi networks: {}", e),
+    }
+    match WIFI_SSID.map(|ssid| WifiNetwork::new(ssid, WIFI_PASS.unwrap_or(""))) {
+        Some(Ok(network)) => vec![network],
+        Some(Err(e)) => {
+            log::error!("Ignoring built-in WiFi credentials: {}", e);
+            vec![]
+        }
+        None => vec![],
     }
 }
 
@@ -134,7 +319,14 @@ pub fn debug_lights(ws2812: &mut Ws2812Esp32Rmt, stage: BootStage) -> anyhow::Re
     let stage_num = stage as u32;
     let led_count = 24; // Use a constant here for boot-time debug
     ws2812.write((0..led_count).map(|n| {
-        if n < stage_num {
+        if let BootStage::SafeMode = stage {
+            // Alternating red, unlike anything else we show
+            if n % 2 == 0 {
+                RGB::new(100, 0, 0)
+            } else {
+                RGB::new(0, 0, 0)
+            }
+        } else if n < stage_num {
             RGB::new(100, 50, 75)
         } else {
             RGB::new(0, 0, 50)
@@ -143,85 +335,282 @@ pub fn debug_lights(ws2812: &mut Ws2812Esp32Rmt, stage: BootStage) -> anyhow::Re
     Ok(())
 }
 
+/// Log a boot failure, and show its blink code on the strip for long
+/// enough to read it
+fn show_failure(ws2812: &mut Ws2812Esp32Rmt, failure: BootFailure) {
+    let code = failure.blink_code();
+    log::error!("Boot failure, {} (shown as {})", failure, code);
+    let start = Instant::now();
+    while start.elapsed() < blink::HOLD_TIME {
+        let led_count = 24; // As for debug_lights
+        if let Err(e) = ws2812.write(code.pixels(led_count, start.elapsed())) {
+            log::warn!("Failed to show blink code: {}", e);
+            return;
+        }
+        FreeRtos::delay_ms(50);
+    }
+}
+
 fn main_loop(
     config: Arc<Mutex<Config>>,
+    history: Arc<Mutex<History>>,
+    probe_metrics: Arc<Mutex<ProbeMetrics>>,
+    boot: Instant,
     mut ws2812: Ws2812Esp32Rmt,
     mut mqtt: Option<mqtt::MqttManager>,
+    mut health: restart::BootHealth,
 ) -> anyhow::Result<()> {
     log::info!("Main loop...");
 
-    let mut samples: VecDeque<Option<Duration>> = VecDeque::new();
-    let mut elapsed_since_sample = Duration::MAX;
+    // When the last sample was taken, as time since boot
+    let mut last_sample: Option<Duration> = None;
+    let mut output = Output::new();
+    // Physical pixels written so far
+    let mut strip_len = 0;
+    let mut animator = Animator::new();
+    let mut calibrator = Calibrator::new();
+    let mut anomalies = AnomalyDetector::new();
+    let mut text = TextLayer::new();
 
     loop {
         // Read config values for this iteration
-        let (
-            ping_host,
-            min_healthy_duration,
-            max_healthy_duration,
-            led_brightness,
-            led_enabled,
-            led_count,
-            time_per_led,
-        ) = {
-            let cfg = config.lock().expect("Failed to lock config for reading");
-            let time_per_led = cfg.led_strip_duration / cfg.led_count;
-            (
-                cfg.ping_host.clone(),
-                cfg.min_healthy_duration,
-                cfg.max_healthy_duration,
-                cfg.led_brightness,
-                cfg.led_enabled,
-                cfg.led_count,
-                time_per_led,
-            )
-        };
+        let cfg = config
+            .lock()
+            .expect("Failed to lock config for reading")
+            .clone();
+        // LEDs for the history, after any status pixels
+        let history_len = cfg.history_led_count();
+        let time_per_led = cfg.led_strip_duration / history_len;
 
-        // Check if it's time to take a new sample
-        if elapsed_since_sample >= time_per_led {
-            let ping_host_addr = ping_host.parse::<Ipv4Addr>()?;
-            let sample = network::ping(ping_host_addr, max_healthy_duration * 5)?;
+        // Check if it's time to take a new sample; the bar graph shows the
+        // latest ping, so pings on every loop in between
+        let sample_due =
+            last_sample.map_or(true, |at| boot.elapsed().saturating_sub(at) >= time_per_led);
+        let ping_host_addr = cfg.ping_host.parse::<Ipv4Addr>()?;
+        let ping_timeout = (cfg.max_healthy_duration * 5).max(MIN_PING_TIMEOUT);
+        if !sample_due && cfg.led_mode == Mode::BarGraph {
+            let rtt = network::ping(ping_host_addr, ping_timeout)?;
+            history.lock().expect("Failed to lock history").record_live(
+                boot.elapsed(),
+                rtt,
+                cfg.bar_peak_hold,
+            );
+            probe_metrics
+                .lock()
+                .expect("Failed to lock metrics")
+                .record(&cfg.ping_host, rtt);
+        }
+        if sample_due {
+            let sample = network::ping(ping_host_addr, ping_timeout)?;
             log::info!("Sample: {:?}", sample);
-            samples.push_front(sample);
-            if samples.len() > led_count as usize {
-                samples.pop_back();
-            }
-            elapsed_since_sample = Duration::ZERO;
-        }
-
-        // Update the pixels
-        let pixels: Vec<RGB<u8>> = if led_enabled {
-            samples
-                .clone()
-                .into_iter()
-                .map(|ms| {
-                    rgb::ms2rgb(
-                        ms,
-                        min_healthy_duration,
-                        max_healthy_duration,
-                        led_brightness,
-                    )
-                })
-                .chain(
-                    std::iter::repeat(smart_leds::RGB8::new(0, 0, led_brightness / 4))
-                        .take(led_count as usize - samples.len()),
-                )
-                .collect()
-        } else {
-            vec![RGB::new(0, 0, 0); led_count as usize]
-        };
-        ws2812.write(pixels)?;
+            let mut history = history.lock().expect("Failed to lock history");
+            history.set_capacity(history_len as usize);
+            history.set_baseline_window(cfg.baseline_window);
+            history.set_dial(cfg.dial_span, history_len as usize, cfg.utc_offset);
+            if let Some(now) = system::wall_clock() {
+                history.set_boot_time(now.saturating_sub(boot.elapsed()));
+            }
+            history.push(boot.elapsed(), sample);
+            let mut live_config = config.lock().expect("Failed to lock config");
+            if calibrator.update(boot.elapsed(), &history, &mut live_config) {
+                log::info!(
+                    "Calibrated healthy durations to {:?}-{:?}",
+                    live_config.min_healthy_duration,
+                    live_config.max_healthy_duration
+                );
+                if let Some(ref mqtt_manager) = mqtt {
+                    mqtt_manager.request_publish();
+                }
+            }
+            drop(live_config);
+            probe_metrics
+                .lock()
+                .expect("Failed to lock metrics")
+                .record(&cfg.ping_host, sample);
+            if let Some(event) = anomalies.record(&cfg.ping_host, sample) {
+                log::warn!("Latency {}: {:?}", event.name(), event.deviation());
+                if let AnomalyEvent::Started(_) = event {
+                    history.mark_anomaly();
+                }
+                if let Some(ref mut mqtt_manager) = mqtt {
+                    if let Err(e) = mqtt_manager.publish_anomaly(&event) {
+                        log::warn!("Failed to publish anomaly: {}", e);
+                    }
+                }
+            }
+            last_sample = Some(boot.elapsed());
+            health.probe(sample)?;
+        }
+        health.tick();
+
+        // Work out the colours; brightness is applied by `output`, which
+        // knows how it is perceived
+        {
+            let history = history.lock().expect("Failed to lock history");
+            let pixels = render::render(&history, &cfg, u8::MAX, boot.elapsed());
+            animator.update(boot.elapsed(), pixels, &history);
+            if cfg.matrix_width > 0 {
+                let width = cfg.matrix_width as usize;
+                text.update(boot.elapsed(), width, &history, cfg.text_interval);
+            }
+        }
+        let effect = render::effect(&cfg);
+
+        // Read before publishing, which clears it
+        let publish_pending = mqtt.as_ref().is_some_and(|m| m.publish_pending());
 
         // Periodically publish MQTT state (every 60 iterations)
         if let Some(ref mut mqtt_manager) = mqtt {
             if let Err(e) = mqtt_manager.periodic_publish() {
                 log::warn!("Failed to publish MQTT state: {}", e);
             }
+            if let Some(request) = mqtt_manager.take_ota_request() {
+                apply_update(request, mqtt_manager, &mut ws2812, cfg.led_count)?;
+            }
+            for message in mqtt_manager.take_messages() {
+                text.push(&message);
+            }
         }
 
-        // Sleep until the next loop
+        let device = DeviceStatus {
+            wifi_rssi: system::wifi_rssi(),
+            mqtt_connected: mqtt.as_ref().map(|m| m.connected().load(Ordering::Relaxed)),
+            time_synced: system::wall_clock().is_some(),
+            publish_pending,
+        };
+        let indicators: &[_] = if cfg.led_enabled {
+            &cfg.status_pixels
+        } else {
+            &[]
+        };
+
+        // Show the pixels until the next loop, redrawing them often if
+        // dithering, animating, scrolling text or blinking indicators
         let loop_delay = Duration::from_secs(1);
-        FreeRtos::delay_ms(loop_delay.as_millis() as u32);
-        elapsed_since_sample += loop_delay;
+        let frames = if cfg.led_dithering
+            || effect != Effect::Static
+            || text.is_active()
+            || !indicators.is_empty()
+        {
+            FRAME_RATE
+        } else {
+            1
+        };
+        let mapping = cfg.layout.mapping(cfg.led_count as usize);
+        // Highest estimated draw of this loop's frames, in mA
+        let mut led_current = 0;
+        // Frames are due at fixed times from the start of the loop, so the
+        // time spent drawing them doesn't add up
+        let loop_start = boot.elapsed();
+        for frame in 1..=frames {
+            let mut pixels = animator.frame(boot.elapsed(), effect);
+            let width = cfg.matrix_width as usize;
+            text.composite(boot.elapsed(), &mut pixels, width, cfg.matrix_wiring);
+            status::overlay(indicators, &device, boot.elapsed(), &mut pixels);
+            let pixels = output.frame(&pixels, cfg.led_brightness, cfg.led_dithering);
+            let mut strip = mapping.apply(&pixels);
+            // Blank pixels the layout used to light but no longer does
+            strip.resize(strip.len().max(strip_len), RGB::default());
+            strip_len = strip.len();
+            led_current = led_current.max(power::limit(&mut strip, cfg.power_budget));
+            ws2812.write(strip)?;
+            let due = loop_start + loop_delay * frame / frames;
+            FreeRtos::delay_ms(due.saturating_sub(boot.elapsed()).as_millis() as u32);
+        }
+        if let Some(ref mut mqtt_manager) = mqtt {
+            mqtt_manager.set_led_current(led_current);
+        }
+    }
+}
+
+/// Keep MQTT (and so remote configuration and updates) going without
+/// pinging or drawing history, until someone restarts us
+fn safe_mode_loop(
+    mut ws2812: Ws2812Esp32Rmt,
+    mut mqtt: Option<mqtt::MqttManager>,
+    mut health: restart::BootHealth,
+) -> anyhow::Result<()> {
+    log::info!("Safe mode loop...");
+
+    loop {
+        health.tick();
+        if let Some(ref mut mqtt_manager) = mqtt {
+            if let Err(e) = mqtt_manager.periodic_publish() {
+                log::warn!("Failed to publish MQTT state: {}", e);
+            }
+            if let Some(request) = mqtt_manager.take_ota_request() {
+                apply_update(request, mqtt_manager, &mut ws2812, 24)?;
+                debug_lights(&mut ws2812, BootStage::SafeMode)?;
+            }
+        }
+        FreeRtos::delay_ms(1000);
+    }
+}
+
+/// Download and install a firmware update, showing progress on the strip
+/// and over MQTT, then restart into it. Returns normally if the update
+/// failed, so the old firmware can carry on.
+fn apply_update(
+    request: updater::OtaRequest,
+    mqtt: &mut mqtt::MqttManager,
+    ws2812: &mut Ws2812Esp32Rmt,
+    led_count: u32,
+) -> anyhow::Result<()> {
+    let manifest = match request {
+        updater::OtaRequest::ManifestUrl(url) => updater::fetch_manifest(&url),
+        updater::OtaRequest::Manifest(manifest) => Ok(manifest),
+    };
+    let result = manifest.and_then(|manifest| {
+        log::info!(
+            "Updating firmware {} -> {}",
+            system::VERSION,
+            manifest.version
+        );
+        updater::install(&manifest, |progress| {
+            // Progress bar in blue, so it can't be mistaken for ping history
+            let lit = led_count * progress as u32 / 100;
+            if let Err(e) = ws2812.write((0..led_count).map(|n| {
+                if n < lit {
+                    RGB::new(0, 0, 100)
+                } else {
+                    RGB::new(0, 0, 0)
+                }
+            })) {
+                log::warn!("Failed to show update progress: {}", e);
+            }
+            if progress % 10 == 0 {
+                let status = updater::OtaStatus::Downloading {
+                    version: manifest.version.clone(),
+                    progress,
+                };
+                if let Err(e) = mqtt.publish_ota_status(&status) {
+                    log::warn!("Failed to publish update progress: {}", e);
+                }
+            }
+        })?;
+        Ok(manifest)
+    });
+
+    match result {
+        Ok(manifest) => {
+            let status = updater::OtaStatus::Installed {
+                version: manifest.version,
+            };
+            if let Err(e) = mqtt.publish_ota_status(&status) {
+                log::warn!("Failed to publish update result: {}", e);
+            }
+            log::info!("Restarting into new firmware");
+            restart::restart();
+        }
+        Err(e) => {
+            log::error!("Firmware update failed: {}", e);
+            let status = updater::OtaStatus::Failed {
+                error: e.to_string(),
+            };
+            if let Err(e) = mqtt.publish_ota_status(&status) {
+                log::warn!("Failed to publish update result: {}", e);
+            }
+            Ok(())
+        }
     }
 }
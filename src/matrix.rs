@@ -0,0 +1,167 @@
+use smart_leds::RGB8;
+
+/// The order a matrix's pixels are wired in, starting at the top left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wiring {
+    /// Each row left to right
+    Progressive,
+    /// Rows alternately left to right and right to left
+    Serpentine,
+    /// Each column top to bottom
+    ColumnProgressive,
+    /// Columns alternately top to bottom and bottom to top, as on most
+    /// 8x32 panels
+    ColumnSerpentine,
+}
+
+impl Wiring {
+    pub const ALL: [Wiring; 4] = [
+        Wiring::Progressive,
+        Wiring::Serpentine,
+        Wiring::ColumnProgressive,
+        Wiring::ColumnSerpentine,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Wiring::Progressive => "progressive",
+            Wiring::Serpentine => "serpentine",
+            Wiring::ColumnProgressive => "column_progressive",
+            Wiring::ColumnSerpentine => "column_serpentine",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|w| w.name() == name)
+    }
+}
+
+/// A 2D image for an LED matrix, (0, 0) being the top left
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGB8>,
+}
+
+impl Framebuffer {
+    /// A dark image
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![RGB8::default(); width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Set a pixel; those outside the image are ignored
+    pub fn set(&mut self, x: usize, y: usize, colour: RGB8) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = colour;
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<RGB8> {
+        (x < self.width && y < self.height).then(|| self.pixels[y * self.width + x])
+    }
+
+    /// Where the `i`th pixel along the wire is
+    fn position(&self, i: usize, wiring: Wiring) -> (usize, usize) {
+        let (width, height) = (self.width, self.height);
+        match wiring {
+            Wiring::Progressive => (i % width, i / width),
+            Wiring::Serpentine if (i / width) % 2 == 1 => (width - 1 - i % width, i / width),
+            Wiring::Serpentine => (i % width, i / width),
+            Wiring::ColumnProgressive => (i / height, i % height),
+            Wiring::ColumnSerpentine if (i / height) % 2 == 1 => {
+                (i / height, height - 1 - i % height)
+            }
+            Wiring::ColumnSerpentine => (i / height, i % height),
+        }
+    }
+
+    /// The pixels in the order they are wired
+    pub fn to_strip(&self, wiring: Wiring) -> Vec<RGB8> {
+        (0..self.pixels.len())
+            .map(|i| {
+                let (x, y) = self.position(i, wiring);
+                self.pixels[y * self.width + x]
+            })
+            .collect()
+    }
+
+    /// The image `width` wide on a matrix showing `pixels`, wired as
+    /// `wiring`; any pixels after the last full row are ignored
+    pub fn from_strip(pixels: &[RGB8], width: usize, wiring: Wiring) -> Self {
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        let mut image = Self::new(width, height);
+        for (i, pixel) in pixels.iter().take(width * height).enumerate() {
+            let (x, y) = image.position(i, wiring);
+            image.pixels[y * width + x] = *pixel;
+        }
+        image
+    }
+
+    /// The image as text, one line per row, with a character for each
+    /// pixel chosen by `key`, to compare against expected images in tests
+    pub fn to_text(&self, key: impl Fn(RGB8) -> char) -> String {
+        self.pixels
+            .chunks(self.width.max(1))
+            .map(|row| row.iter().map(|p| key(*p)).collect::<String>() + "\n")
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_matrix {
+    use super::*;
+
+    fn grey(n: u8) -> RGB8 {
+        RGB8::new(n, n, n)
+    }
+
+    /// A 3x2 image numbered 1-6 along the rows
+    fn numbered() -> Framebuffer {
+        let mut image = Framebuffer::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                image.set(x, y, grey((y * 3 + x + 1) as u8));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn wiring_orders() {
+        let image = numbered();
+        let order = |wiring| -> Vec<u8> { image.to_strip(wiring).iter().map(|p| p.r).collect() };
+        assert_eq!(order(Wiring::Progressive), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(order(Wiring::Serpentine), [1, 2, 3, 6, 5, 4]);
+        assert_eq!(order(Wiring::ColumnProgressive), [1, 4, 2, 5, 3, 6]);
+        assert_eq!(order(Wiring::ColumnSerpentine), [1, 4, 5, 2, 3, 6]);
+        for wiring in Wiring::ALL {
+            assert_eq!(
+                Framebuffer::from_strip(&image.to_strip(wiring), 3, wiring),
+                image
+            );
+        }
+    }
+
+    #[test]
+    fn draws_as_text() {
+        let mut image = numbered();
+        image.set(9, 9, grey(0));
+        assert_eq!(image.get(1, 1), Some(grey(5)));
+        assert_eq!(image.get(3, 0), None);
+        let text = image.to_text(|p| if p.r % 2 == 0 { '#' } else { '.' });
+        assert_eq!(text, ".#.\n#.#\n");
+    }
+}
@@ -0,0 +1,372 @@
+use crate::colour;
+use crate::config::Config;
+use crate::layout;
+use crate::status;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the RTT histogram buckets (the +Inf bucket is implicit)
+pub const RTT_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_millis(1000),
+];
+
+/// Probe counters for a single target
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetStats {
+    /// Number of successful probes with an RTT <= each of RTT_BUCKETS
+    /// (not cumulative; the exposition format makes them cumulative)
+    pub buckets: [u64; RTT_BUCKETS.len()],
+    /// Successful probes slower than the largest bucket
+    pub overflow: u64,
+    /// Sum of all successful probe RTTs
+    pub rtt_sum: Duration,
+    pub sent: u64,
+    pub lost: u64,
+}
+
+impl TargetStats {
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        self.sent += 1;
+        match rtt {
+            Some(rtt) => {
+                self.rtt_sum += rtt;
+                match RTT_BUCKETS.iter().position(|b| rtt <= *b) {
+                    Some(i) => self.buckets[i] += 1,
+                    None => self.overflow += 1,
+                }
+            }
+            None => self.lost += 1,
+        }
+    }
+}
+
+/// Probe counters for every target we have pinged since boot
+#[derive(Debug, Clone, Default)]
+pub struct ProbeMetrics {
+    targets: BTreeMap<String, TargetStats>,
+}
+
+impl ProbeMetrics {
+    pub fn record(&mut self, target: &str, rtt: Option<Duration>) {
+        self.targets
+            .entry(target.to_string())
+            .or_default()
+            .record(rtt);
+    }
+}
+
+/// Device state sampled at scrape time
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMetrics {
+    pub uptime: Duration,
+    /// None if WiFi is not connected
+    pub wifi_rssi: Option<i32>,
+    pub free_heap: u32,
+    /// None if MQTT is disabled
+    pub mqtt_connected: Option<bool>,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    // Writing to a String can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render(probes: &ProbeMetrics, device: &DeviceMetrics, config: &Config) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "ping_leds_probe_rtt_seconds",
+        "histogram",
+        "Round trip time of successful probes.",
+    );
+    for (target, stats) in &probes.targets {
+        let target = escape_label(target);
+        let mut cumulative = 0;
+        for (bound, count) in RTT_BUCKETS.iter().zip(stats.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "ping_leds_probe_rtt_seconds_bucket{{target=\"{}\",le=\"{}\"}} {}",
+                target,
+                bound.as_secs_f64(),
+                cumulative
+            );
+        }
+        cumulative += stats.overflow;
+        let _ = writeln!(
+            out,
+            "ping_leds_probe_rtt_seconds_bucket{{target=\"{}\",le=\"+Inf\"}} {}",
+            target, cumulative
+        );
+        let _ = writeln!(
+            out,
+            "ping_leds_probe_rtt_seconds_sum{{target=\"{}\"}} {}",
+            target,
+            stats.rtt_sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "ping_leds_probe_rtt_seconds_count{{target=\"{}\"}} {}",
+            target, cumulative
+        );
+    }
+
+    header(
+        &mut out,
+        "ping_leds_probes_sent_total",
+        "counter",
+        "Probes sent.",
+    );
+    for (target, stats) in &probes.targets {
+        let _ = writeln!(
+            out,
+            "ping_leds_probes_sent_total{{target=\"{}\"}} {}",
+            escape_label(target),
+            stats.sent
+        );
+    }
+
+    header(
+        &mut out,
+        "ping_leds_probes_lost_total",
+        "counter",
+        "Probes which got no reply.",
+    );
+    for (target, stats) in &probes.targets {
+        let _ = writeln!(
+            out,
+            "ping_leds_probes_lost_total{{target=\"{}\"}} {}",
+            escape_label(target),
+            stats.lost
+        );
+    }
+
+    let mut gauge = |name: &str, help: &str, value: String| {
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    gauge(
+        "ping_leds_uptime_seconds",
+        "Time since boot.",
+        device.uptime.as_secs().to_string(),
+    );
+    if let Some(rssi) = device.wifi_rssi {
+        gauge(
+            "ping_leds_wifi_rssi_dbm",
+            "WiFi signal strength.",
+            rssi.to_string(),
+        );
+    }
+    gauge(
+        "ping_leds_free_heap_bytes",
+        "Free heap memory.",
+        device.free_heap.to_string(),
+    );
+    if let Some(connected) = device.mqtt_connected {
+        gauge(
+            "ping_leds_mqtt_connected",
+            "Whether the MQTT client is connected to the broker.",
+            (connected as u8).to_string(),
+        );
+    }
+    gauge(
+        "ping_leds_config_min_healthy_seconds",
+        "Probes faster than this are shown solid green.",
+        config.min_healthy_duration.as_secs_f64().to_string(),
+    );
+    gauge(
+        "ping_leds_config_max_healthy_seconds",
+        "Probes slower than this are shown as problems.",
+        config.max_healthy_duration.as_secs_f64().to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_brightness",
+        "LED brightness (0-255).",
+        config.led_brightness.to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_enabled",
+        "Whether the LEDs are turned on.",
+        (config.led_enabled as u8).to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_dithering",
+        "Whether temporal dithering is used for dim colours.",
+        (config.led_dithering as u8).to_string(),
+    );
+    gauge(
+        "ping_leds_config_auto_calibrate",
+        "Whether the healthy durations are learnt from recent RTTs.",
+        (config.auto_calibrate as u8).to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_strip_duration_seconds",
+        "Total time the LED strip represents.",
+        config.led_strip_duration.as_secs().to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_count",
+        "Number of LEDs in the strip.",
+        config.led_count.to_string(),
+    );
+    gauge(
+        "ping_leds_config_power_budget_milliamps",
+        "Current the supply can give the strip, or 0 for no limit.",
+        config.power_budget.to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_offset",
+        "Physical pixels before the first one used.",
+        config.layout.offset.to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_reverse",
+        "Whether the strip runs the other way.",
+        (config.layout.reverse as u8).to_string(),
+    );
+    gauge(
+        "ping_leds_config_led_rotation",
+        "Where the first LED is, counting along the pixels in use.",
+        config.layout.rotation.to_string(),
+    );
+    gauge(
+        "ping_leds_config_matrix_width",
+        "Columns of the LED matrix, or 0 for a strip.",
+        config.matrix_width.to_string(),
+    );
+    gauge(
+        "ping_leds_config_text_interval_seconds",
+        "How often a matrix scrolls the latest RTT across, or 0 for never.",
+        config.text_interval.as_secs().to_string(),
+    );
+    gauge(
+        "ping_leds_config_bar_peak_hold_seconds",
+        "How long the bar graph's peak is held for.",
+        config.bar_peak_hold.as_secs().to_string(),
+    );
+    gauge(
+        "ping_leds_config_utc_offset_minutes",
+        "Local time zone for the dial, as minutes ahead of UTC.",
+        config.utc_offset.to_string(),
+    );
+    gauge(
+        "ping_leds_config_log_span_seconds",
+        "Total time the log time axis covers.",
+        config.log_span.as_secs().to_string(),
+    );
+    gauge(
+        "ping_leds_config_baseline_window_seconds",
+        "How far back the baseline median looks.",
+        config.baseline_window.as_secs().to_string(),
+    );
+
+    header(
+        &mut out,
+        "ping_leds_config_info",
+        "gauge",
+        "Non-numeric settings, as labels.",
+    );
+    let _ = writeln!(
+        out,
+        "ping_leds_config_info{{ping_host=\"{}\",colour_scale=\"{}\",led_mode=\"{}\",led_effect=\"{}\",dial_span=\"{}\",log_aggregation=\"{}\",led_skip=\"{}\",led_segments=\"{}\",matrix_wiring=\"{}\",status_pixels=\"{}\",colour_stops=\"{}\"}} 1",
+        escape_label(&config.ping_host),
+        config.colour_scale.name(),
+        config.led_mode.name(),
+        config.led_effect.name(),
+        config.dial_span.name(),
+        config.log_aggregation.name(),
+        layout::format_skipped(&config.layout.skipped),
+        escape_label(&layout::format_segments(&config.layout.segments)),
+        config.matrix_wiring.name(),
+        status::format_indicators(&config.status_pixels),
+        config
+            .colour_stops
+            .as_deref()
+            .map(colour::format_stops)
+            .unwrap_or_default()
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn buckets_by_rtt() {
+        let mut stats = TargetStats::default();
+        for rtt in [ms(1), ms(5), ms(6), ms(2000), None] {
+            stats.record(rtt);
+        }
+        assert_eq!(stats.buckets, [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(stats.overflow, 1);
+        assert_eq!(stats.sent, 5);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.rtt_sum, Duration::from_millis(2012));
+    }
+
+    #[test]
+    fn matches_golden_file() {
+        let mut probes = ProbeMetrics::default();
+        for rtt in [ms(4), ms(12), ms(12), ms(80), None, ms(1500)] {
+            probes.record("192.168.1.1", rtt);
+        }
+        probes.record("1.1.1.1", ms(20));
+        probes.record("1.1.1.1", None);
+        let device = DeviceMetrics {
+            uptime: Duration::from_secs(3600),
+            wifi_rssi: Some(-67),
+            free_heap: 123456,
+            mqtt_connected: Some(true),
+        };
+        let config = Config {
+            ping_host: "1.1.1.1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&probes, &device, &config),
+            include_str!("../testdata/metrics.prom")
+        );
+    }
+
+    #[test]
+    fn matches_golden_file_when_idle() {
+        let device = DeviceMetrics {
+            uptime: Duration::from_secs(5),
+            wifi_rssi: None,
+            free_heap: 200000,
+            mqtt_connected: None,
+        };
+        let config = Config {
+            ping_host: "we\"ird\\host".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&ProbeMetrics::default(), &device, &config),
+            include_str!("../testdata/metrics_idle.prom")
+        );
+    }
+}
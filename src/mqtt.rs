@@ -1,10 +1,102 @@
-use crate::config::Config;
+use crate::restart;
+use crate::storage::Storage;
+use crate::system;
+use crate::updater::{self, OtaRequest, OtaStatus};
+use esp_idf_svc::handle::RawHandle;
 use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttConnection, MqttClientConfiguration, QoS};
+use esp_idf_svc::sys::{
+    esp, esp_event_base_t, esp_mqtt_client_register_event, esp_mqtt_client_start,
+    esp_mqtt_client_stop, esp_mqtt_error_type_t_MQTT_ERROR_TYPE_CONNECTION_REFUSED,
+    esp_mqtt_event_handle_t, esp_mqtt_event_id_t_MQTT_EVENT_ERROR,
+};
+use esp_ping_leds::blink::BootFailure;
+use esp_ping_leds::colour::{self, ColourError, ColourStop, Scale};
+use esp_ping_leds::config::{Config, ConfigKey, MAX_LED_COUNT, MAX_UTC_OFFSET, MIN_UTC_OFFSET};
+use esp_ping_leds::crash::CrashReport;
+use esp_ping_leds::diagnostics::SENSORS;
+use esp_ping_leds::dial::DialSpan;
+use esp_ping_leds::layout;
+use esp_ping_leds::matrix::Wiring;
+use esp_ping_leds::ota::Manifest;
+use esp_ping_leds::power::MAX_POWER_BUDGET;
+use esp_ping_leds::render;
+use esp_ping_leds::rgb;
+use esp_ping_leds::rollup::Aggregation;
+use esp_ping_leds::stats::AnomalyEvent;
+use esp_ping_leds::status;
+use esp_ping_leds::text::{MAX_MESSAGE_LEN, MAX_QUEUED};
+use esp_ping_leds::wifi::{WifiNetwork, MAX_NETWORKS};
 use serde::Deserialize;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use smart_leds::RGB8;
+use std::ffi::c_void;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 
 const MQTT_URL: Option<&str> = std::option_env!("MQTT_URL");
+/// Where release manifests are published (retained), unless another topic
+/// has been stored; shared by every device
+const DEFAULT_LATEST_TOPIC: &str = "esp-ping-leds/firmware/latest";
+/// How often the diagnostic sensors are published
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for the broker to accept or refuse us at startup
+const CONNECT_WAIT: Duration = Duration::from_secs(5);
+/// First wait before trying again when the broker refuses our credentials,
+/// doubling with each refusal up to `AUTH_RETRY_MAX`
+const AUTH_RETRY_MIN: Duration = Duration::from_secs(60);
+const AUTH_RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Return code of the last refused connection, 0 if none; esp-idf-svc
+/// doesn't pass on the details of error events, so this is set from a raw
+/// event handler
+static REFUSED_RETURN_CODE: AtomicU32 = AtomicU32::new(0);
+
+unsafe extern "C" fn on_mqtt_error(
+    _arg: *mut c_void,
+    _base: esp_event_base_t,
+    _id: i32,
+    data: *mut c_void,
+) {
+    let event = data as esp_mqtt_event_handle_t;
+    if event.is_null() || (*event).error_handle.is_null() {
+        return;
+    }
+    let error = &*(*event).error_handle;
+    if error.error_type == esp_mqtt_error_type_t_MQTT_ERROR_TYPE_CONNECTION_REFUSED {
+        REFUSED_RETURN_CODE.store(error.connect_return_code as u32, Ordering::Relaxed);
+    }
+}
+
+/// Settings exposed as individual Home Assistant entities, each with
+/// `<name>/state` and `<name>/set` topics
+const ENTITY_KEYS: [ConfigKey; 24] = [
+    ConfigKey::MinHealthyDuration,
+    ConfigKey::MaxHealthyDuration,
+    ConfigKey::LedStripDuration,
+    ConfigKey::LedCount,
+    ConfigKey::PowerBudget,
+    ConfigKey::PingHost,
+    ConfigKey::LedDithering,
+    ConfigKey::ColourScale,
+    ConfigKey::BaselineWindow,
+    ConfigKey::AutoCalibrate,
+    ConfigKey::BarPeakHold,
+    ConfigKey::DialSpan,
+    ConfigKey::UtcOffset,
+    ConfigKey::LogSpan,
+    ConfigKey::LogAggregation,
+    ConfigKey::LedOffset,
+    ConfigKey::LedReverse,
+    ConfigKey::LedRotation,
+    ConfigKey::LedSkip,
+    ConfigKey::LedSegments,
+    ConfigKey::MatrixWidth,
+    ConfigKey::MatrixWiring,
+    ConfigKey::TextInterval,
+    ConfigKey::StatusPixels,
+];
 
 /// MQTT client wrapper for Home Assistant integration
 pub struct MqttManager {
@@ -13,12 +105,68 @@ pub struct MqttManager {
     device_id: String,
     device_path: String,
     publish_pending: Arc<Mutex<bool>>,
+    connected: Arc<AtomicBool>,
+    /// An update requested over MQTT, waiting for the main loop to pick it up
+    ota_request: Arc<Mutex<Option<OtaRequest>>>,
+    /// The newest release published on the "latest" topic
+    latest_firmware: Arc<Mutex<Option<Manifest>>>,
+    latest_topic: String,
+    /// Text sent to show on the matrix, waiting for the main loop
+    messages: Arc<Mutex<Vec<String>>>,
+    last_diagnostics: Option<Instant>,
+    boot_count: u32,
+    last_crash: Option<CrashReport>,
+    safe_mode: bool,
+    /// Latest estimate of the strip's current draw, from the main loop
+    led_current: Option<u32>,
+    storage: Arc<Mutex<Storage>>,
+    /// Whether discovery and subscriptions were sent on this connection
+    announced: bool,
+    /// When to start the client again, after it was stopped because the
+    /// broker refused our credentials
+    auth_retry: Option<Instant>,
+    auth_delay: Duration,
+}
+
+/// Everything the connection handler thread needs to act on commands
+#[derive(Clone)]
+struct CommandContext {
+    config: Arc<Mutex<Config>>,
+    storage: Arc<Mutex<Storage>>,
+    device_path: String,
+    publish_pending: Arc<Mutex<bool>>,
+    ota_request: Arc<Mutex<Option<OtaRequest>>>,
+    latest_firmware: Arc<Mutex<Option<Manifest>>>,
+    latest_topic: String,
+    messages: Arc<Mutex<Vec<String>>>,
 }
 
 impl MqttManager {
-    /// Create a new MQTT manager if MQTT_URL environment variable is set
-    pub fn new(config: Arc<Mutex<Config>>, mac_address: [u8; 6]) -> anyhow::Result<Option<Self>> {
-        let broker_url = match MQTT_URL {
+    /// Create a new MQTT manager if a broker URL has been stored, or the
+    /// MQTT_URL environment variable was set at compile time. Fails with a
+    /// `BootFailure` if the broker rejects our credentials.
+    pub fn new(
+        config: Arc<Mutex<Config>>,
+        mac_address: [u8; 6],
+        storage: Arc<Mutex<Storage>>,
+        publish_pending: Arc<Mutex<bool>>,
+        safe_mode: bool,
+    ) -> anyhow::Result<Option<Self>> {
+        let (stored_url, stored_latest_topic, boot_count, last_crash) = {
+            let storage = storage
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?;
+            (
+                storage.mqtt_url()?,
+                storage.ota_latest_topic()?,
+                storage.boot_count()?,
+                storage.crash_report().unwrap_or_else(|e| {
+                    log::warn!("Failed to load crash report: {}", e);
+                    None
+                }),
+            )
+        };
+        let broker_url = match stored_url.or(MQTT_URL.map(|url| url.to_string())) {
             Some(url) => url,
             None => {
                 log::info!("MQTT_URL not set, MQTT disabled");
@@ -34,13 +182,18 @@ impl MqttManager {
             ..Default::default()
         };
 
-        let (client, mut connection) = EspMqttClient::new(broker_url, &mqtt_config)?;
+        let (client, mut connection) = EspMqttClient::new(&broker_url, &mqtt_config)?;
+        esp!(unsafe {
+            esp_mqtt_client_register_event(
+                client.handle(),
+                esp_mqtt_event_id_t_MQTT_EVENT_ERROR,
+                Some(on_mqtt_error),
+                std::ptr::null_mut(),
+            )
+        })?;
 
         log::info!("MQTT client created, spawning connection handler");
 
-        // Flag to signal when state should be published
-        let publish_pending = Arc::new(Mutex::new(false));
-
         // Generate device ID from MAC address: ping_leds_aabbccddeeff
         let device_id = format!(
             "ping_leds_{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
@@ -59,45 +212,141 @@ impl MqttManager {
             device_path: format!("homeassistant/device/{}", device_id),
             device_id: device_id.clone(),
             publish_pending,
+            connected: Arc::new(AtomicBool::new(false)),
+            ota_request: Arc::new(Mutex::new(None)),
+            latest_firmware: Arc::new(Mutex::new(None)),
+            latest_topic: stored_latest_topic.unwrap_or(DEFAULT_LATEST_TOPIC.to_string()),
+            messages: Arc::new(Mutex::new(Vec::new())),
+            last_diagnostics: None,
+            boot_count,
+            last_crash,
+            safe_mode,
+            led_current: None,
+            storage: storage.clone(),
+            announced: false,
+            auth_retry: None,
+            auth_delay: Duration::ZERO,
         };
 
         // Spawn connection handler thread
-        let config_clone = config.clone();
-        let device_path_clone = manager.device_path.clone();
-        let publish_pending_clone = manager.publish_pending.clone();
+        let context = CommandContext {
+            config: config.clone(),
+            storage: storage.clone(),
+            device_path: manager.device_path.clone(),
+            publish_pending: manager.publish_pending.clone(),
+            ota_request: manager.ota_request.clone(),
+            latest_firmware: manager.latest_firmware.clone(),
+            latest_topic: manager.latest_topic.clone(),
+            messages: manager.messages.clone(),
+        };
+        let connected_clone = manager.connected.clone();
         std::thread::Builder::new()
             .stack_size(8192)
             .spawn(move || {
-                Self::connection_handler(
-                    &mut connection,
-                    config_clone,
-                    device_path_clone,
-                    publish_pending_clone,
-                );
+                Self::connection_handler(&mut connection, context, connected_clone);
             })?;
 
         // Wait briefly for connection
-        std::thread::sleep(Duration::from_millis(1000));
+        let start = Instant::now();
+        while !manager.connected.load(Ordering::Relaxed)
+            && manager.auth_failure().is_none()
+            && start.elapsed() < CONNECT_WAIT
+        {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        if manager.connected.load(Ordering::Relaxed) {
+            manager.announce()?;
+        } else {
+            // Announced from `periodic_publish` once we do connect
+            log::warn!("Not connected to MQTT broker yet");
+        }
 
+        log::info!("MQTT manager initialized");
+        Ok(Some(manager))
+    }
+
+    /// Send discovery, subscribe to the command topics and publish the
+    /// state; needed on every connection, as the broker forgets our
+    /// subscriptions when we disconnect
+    fn announce(&mut self) -> anyhow::Result<()> {
         // Send discovery messages
-        manager.send_discovery_messages()?;
+        self.send_discovery_messages()?;
 
         // Wait a bit for subscription to complete
         std::thread::sleep(Duration::from_millis(500));
 
         // Publish initial state
-        manager.publish_state()?;
+        self.publish_state()?;
+        let storage = self.storage.clone();
+        self.publish_crash_report(&storage)?;
+        self.announced = true;
+        self.auth_delay = Duration::ZERO;
+        Ok(())
+    }
 
-        log::info!("MQTT manager initialized");
-        Ok(Some(manager))
+    /// Why the broker last refused us, if it was over our credentials
+    pub fn auth_failure(&self) -> Option<BootFailure> {
+        BootFailure::from_mqtt_return_code(REFUSED_RETURN_CODE.load(Ordering::Relaxed))
+    }
+
+    /// The client would retry refused credentials every few seconds, so stop
+    /// it instead and start it again after a delay which doubles with each
+    /// refusal
+    fn back_off_after_refusal(&mut self) {
+        match self.auth_retry {
+            Some(retry_at) => {
+                if Instant::now() >= retry_at {
+                    log::info!("Retrying MQTT broker");
+                    REFUSED_RETURN_CODE.store(0, Ordering::Relaxed);
+                    self.auth_retry = None;
+                    if let Err(e) = esp!(unsafe { esp_mqtt_client_start(self.client.handle()) }) {
+                        log::warn!("Failed to restart MQTT client: {}", e);
+                    }
+                }
+            }
+            None => {
+                if let Some(failure) = self.auth_failure() {
+                    self.auth_delay = (self.auth_delay * 2).clamp(AUTH_RETRY_MIN, AUTH_RETRY_MAX);
+                    log::error!("{}, retrying in {:?}", failure, self.auth_delay);
+                    if let Err(e) = esp!(unsafe { esp_mqtt_client_stop(self.client.handle()) }) {
+                        log::warn!("Failed to stop MQTT client: {}", e);
+                    }
+                    self.auth_retry = Some(Instant::now() + self.auth_delay);
+                }
+            }
+        }
+    }
+
+    /// Flag which tracks whether we are currently connected to the broker
+    pub fn connected(&self) -> Arc<AtomicBool> {
+        self.connected.clone()
+    }
+
+    /// Publish the state on the next `periodic_publish`, eg after the
+    /// config was changed other than by a command
+    pub fn request_publish(&self) {
+        if let Ok(mut pending) = self.publish_pending.lock() {
+            *pending = true;
+        }
+    }
+
+    /// Record the strip's estimated current draw, in mA, for the next
+    /// diagnostics: the highest of the last loop's frames, not smoothed
+    pub fn set_led_current(&mut self, milliamps: u32) {
+        self.led_current = Some(milliamps);
+    }
+
+    /// Whether a settings change is waiting for `periodic_publish`
+    pub fn publish_pending(&self) -> bool {
+        status::is_pending(&self.publish_pending)
     }
 
     /// Handle incoming MQTT messages
     fn connection_handler(
         connection: &mut EspMqttConnection,
-        config: Arc<Mutex<Config>>,
-        device_path: String,
-        publish_pending: Arc<Mutex<bool>>,
+        context: CommandContext,
+        connected: Arc<AtomicBool>,
     ) {
         log::info!("MQTT connection handler started");
 
@@ -107,9 +356,11 @@ impl MqttManager {
             match event.payload() {
                 EventPayload::Connected(_) => {
                     log::info!("MQTT Connected");
+                    connected.store(true, Ordering::Relaxed);
                 }
                 EventPayload::Disconnected => {
                     log::warn!("MQTT Disconnected");
+                    connected.store(false, Ordering::Relaxed);
                 }
                 EventPayload::Subscribed(id) => {
                     log::info!("MQTT Subscribed to topic ID: {}", id);
@@ -122,13 +373,7 @@ impl MqttManager {
                 } => {
                     if let Ok(payload) = std::str::from_utf8(data) {
                         log::info!("MQTT Received [{}] {}: {}", id, topic, payload);
-                        Self::handle_command(
-                            topic,
-                            payload,
-                            &config,
-                            &device_path,
-                            &publish_pending,
-                        );
+                        Self::handle_command(topic, payload, &context);
                     }
                 }
                 EventPayload::Error(err) => {
@@ -138,29 +383,51 @@ impl MqttManager {
             }
         }
 
+        connected.store(false, Ordering::Relaxed);
         log::warn!("MQTT connection handler exited");
     }
 
     /// Handle incoming MQTT command messages
-    fn handle_command(
-        topic: &str,
-        payload: &str,
-        config: &Arc<Mutex<Config>>,
-        device_path: &str,
-        publish_pending: &Arc<Mutex<bool>>,
-    ) {
+    fn handle_command(topic: &str, payload: &str, context: &CommandContext) {
+        let CommandContext {
+            config,
+            storage,
+            device_path,
+            publish_pending,
+            ota_request,
+            latest_firmware,
+            latest_topic,
+            messages,
+        } = context;
         let light_cmd_topic = format!("{}/light/set", device_path);
-        let min_healthy_topic = format!("{}/min_healthy_duration/set", device_path);
-        let max_healthy_topic = format!("{}/max_healthy_duration/set", device_path);
-        let led_strip_topic = format!("{}/led_strip_duration/set", device_path);
-        let led_count_topic = format!("{}/led_count/set", device_path);
-        let ping_host_topic = format!("{}/ping_host/set", device_path);
+        let wifi_networks_topic = format!("{}/wifi_networks/set", device_path);
+        let ota_topic = format!("{}/ota/set", device_path);
+        let install_topic = format!("{}/update/install", device_path);
+        let latest_topic_cmd_topic = format!("{}/ota_latest_topic/set", device_path);
+        let restart_topic = format!("{}/restart/set", device_path);
+        let colour_stops_topic = format!("{}/colour_stops/set", device_path);
+        let message_topic = format!("{}/message/set", device_path);
+        let config_key = topic
+            .strip_prefix(device_path.as_str())
+            .and_then(|t| t.strip_prefix('/'))
+            .and_then(|t| t.strip_suffix("/set"))
+            .and_then(ConfigKey::from_name)
+            .filter(|key| ENTITY_KEYS.contains(key));
 
         if topic == light_cmd_topic {
+            #[derive(Deserialize)]
+            struct LightColour {
+                r: u8,
+                g: u8,
+                b: u8,
+            }
+
             #[derive(Deserialize)]
             struct LightCommand {
                 state: Option<String>,
                 brightness: Option<u8>,
+                effect: Option<String>,
+                color: Option<LightColour>,
             }
 
             // Parse JSON payload
@@ -194,6 +461,25 @@ impl MqttManager {
                             }
                             state_changed = true;
                         }
+
+                        // Handle effect, which also clears any colour
+                        if let Some(effect) = cmd.effect {
+                            if render::set_light_effect(&mut cfg, &effect) {
+                                log::info!("Setting effect to {}", effect);
+                                state_changed = true;
+                            } else {
+                                log::error!("Unknown effect {:?}", effect);
+                            }
+                        }
+
+                        // Handle colour, shown on the whole strip until an
+                        // effect is chosen
+                        if let Some(LightColour { r, g, b }) = cmd.color {
+                            log::info!("Setting colour to {},{},{}", r, g, b);
+                            cfg.led_colour = Some(RGB8::new(r, g, b));
+                            cfg.led_enabled = true;
+                            state_changed = true;
+                        }
                     }
 
                     // Signal that state should be published
@@ -207,62 +493,133 @@ impl MqttManager {
                     log::error!("Failed to parse light command: {}", e);
                 }
             }
-        } else if topic == min_healthy_topic {
-            if let Ok(ms) = payload.trim().parse::<u64>() {
-                if let Ok(mut cfg) = config.lock() {
-                    cfg.min_healthy_duration = Duration::from_millis(ms);
-                    log::info!("Set min_healthy_duration to {}ms", ms);
-                    if let Ok(mut pending) = publish_pending.lock() {
-                        *pending = true;
+        } else if let Some(key) = config_key {
+            if let (Ok(mut cfg), Ok(mut storage)) = (config.lock(), storage.lock()) {
+                match storage.update_config(&mut cfg, &[(key.name(), payload)]) {
+                    Ok(_) => {
+                        log::info!("Set {} to {}", key.name(), payload);
+                        if let Ok(mut pending) = publish_pending.lock() {
+                            *pending = true;
+                        }
                     }
+                    Err(e) => log::error!("Failed to set {}: {}", key.name(), e),
                 }
-            } else {
-                log::error!("Failed to parse min_healthy_duration: {}", payload);
             }
-        } else if topic == max_healthy_topic {
-            if let Ok(ms) = payload.trim().parse::<u64>() {
-                if let Ok(mut cfg) = config.lock() {
-                    cfg.max_healthy_duration = Duration::from_millis(ms);
-                    log::info!("Set max_healthy_duration to {}ms", ms);
-                    if let Ok(mut pending) = publish_pending.lock() {
-                        *pending = true;
+        } else if topic == wifi_networks_topic {
+            #[derive(Deserialize)]
+            struct NetworkCommand {
+                ssid: String,
+                #[serde(default)]
+                password: String,
+            }
+
+            let networks = serde_json::from_str::<Vec<NetworkCommand>>(payload)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .and_then(|cmds| {
+                    if cmds.len() > MAX_NETWORKS {
+                        anyhow::bail!("At most {} networks can be stored", MAX_NETWORKS);
                     }
+                    cmds.iter()
+                        .map(|c| WifiNetwork::new(&c.ssid, &c.password).map_err(Into::into))
+                        .collect::<anyhow::Result<Vec<WifiNetwork>>>()
+                });
+            match networks {
+                Ok(networks) => {
+                    if let Ok(mut storage) = storage.lock() {
+                        match storage.set_wifi_networks(&networks) {
+                            Ok(_) => log::info!(
+                                "Stored {} WiFi networks, used from next boot",
+                                networks.len()
+                            ),
+                            Err(e) => log::error!("Failed to store WiFi networks: {}", e),
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to parse wifi_networks: {}", e);
+                }
+            }
+        } else if topic == ota_topic {
+            let url = payload.trim();
+            if url.starts_with("http://") || url.starts_with("https://") {
+                log::info!("Firmware update requested from {}", url);
+                if let Ok(mut request) = ota_request.lock() {
+                    *request = Some(OtaRequest::ManifestUrl(url.to_string()));
                 }
             } else {
-                log::error!("Failed to parse max_healthy_duration: {}", payload);
+                log::error!("Invalid firmware manifest URL: {}", url);
             }
-        } else if topic == led_strip_topic {
-            if let Ok(secs) = payload.trim().parse::<u64>() {
-                if let Ok(mut cfg) = config.lock() {
-                    cfg.led_strip_duration = Duration::from_secs(secs);
-                    log::info!("Set led_strip_duration to {}s", secs);
+        } else if topic == latest_topic.as_str() {
+            match updater::parse_manifest(payload.as_bytes()) {
+                Ok(manifest) => {
+                    log::info!("Latest firmware is {}", manifest.version);
+                    if let Ok(mut latest) = latest_firmware.lock() {
+                        *latest = Some(manifest);
+                    }
                     if let Ok(mut pending) = publish_pending.lock() {
                         *pending = true;
                     }
                 }
-            } else {
-                log::error!("Failed to parse led_strip_duration: {}", payload);
+                Err(e) => log::error!("Failed to parse latest firmware: {}", e),
             }
-        } else if topic == led_count_topic {
-            if let Ok(count) = payload.trim().parse::<u32>() {
-                if let Ok(mut cfg) = config.lock() {
-                    cfg.led_count = count;
-                    log::info!("Set led_count to {}", count);
-                    if let Ok(mut pending) = publish_pending.lock() {
-                        *pending = true;
+        } else if topic == install_topic {
+            let latest = latest_firmware.lock().ok().and_then(|l| l.clone());
+            match latest {
+                Some(manifest) => {
+                    log::info!("Install of firmware {} requested", manifest.version);
+                    if let Ok(mut request) = ota_request.lock() {
+                        *request = Some(OtaRequest::Manifest(manifest));
                     }
                 }
-            } else {
-                log::error!("Failed to parse led_count: {}", payload);
+                None => log::error!("Install requested, but no firmware has been published"),
+            }
+        } else if topic == latest_topic_cmd_topic {
+            let new_topic = payload.trim();
+            if let Ok(mut storage) = storage.lock() {
+                match storage.set_ota_latest_topic(Some(new_topic).filter(|t| !t.is_empty())) {
+                    Ok(_) => log::info!(
+                        "Stored latest firmware topic {:?}, used from next boot",
+                        new_topic
+                    ),
+                    Err(e) => log::error!("Failed to store latest firmware topic: {}", e),
+                }
+            }
+        } else if topic == message_topic {
+            log::info!("Message to show: {:?}", payload);
+            if let Ok(mut messages) = messages.lock() {
+                if messages.len() == MAX_QUEUED {
+                    messages.remove(0);
+                }
+                messages.push(payload.chars().take(MAX_MESSAGE_LEN).collect());
             }
-        } else if topic == ping_host_topic {
-            if let Ok(mut cfg) = config.lock() {
-                cfg.ping_host = payload.trim().to_string();
-                log::info!("Set ping_host to {}", cfg.ping_host);
-                if let Ok(mut pending) = publish_pending.lock() {
-                    *pending = true;
+        } else if topic == colour_stops_topic {
+            match parse_colour_stops(payload) {
+                Ok(stops) => {
+                    if let (Ok(mut cfg), Ok(mut storage)) = (config.lock(), storage.lock()) {
+                        match &stops {
+                            Some(stops) => log::info!("Set {} colour stops", stops.len()),
+                            None => log::info!("Reset colour stops to the default"),
+                        }
+                        let value = stops.as_deref().map(colour::format_stops);
+                        let value = value.unwrap_or_default();
+                        if let Err(e) = storage
+                            .update_config(&mut cfg, &[(ConfigKey::ColourStops.name(), value)])
+                        {
+                            log::error!("Failed to set colour stops: {}", e);
+                        }
+                    }
+                    if let Ok(mut pending) = publish_pending.lock() {
+                        *pending = true;
+                    }
                 }
+                Err(e) => log::error!("Failed to set colour stops: {}", e),
             }
+        } else if topic == restart_topic {
+            // A deliberate restart, so this boot doesn't count towards a
+            // crash loop; this is how to leave safe mode
+            log::info!("Restart requested");
+            restart::clear_unstable_boots(storage);
+            restart::restart();
         } else {
             log::warn!("Received command for unknown topic: {}", topic);
         }
@@ -278,12 +635,14 @@ impl MqttManager {
             .expect("Failed to lock config for discovery message");
 
         // Single discovery message with all components
-        let discovery_config = serde_json::json!({
+        let mut discovery_config = serde_json::json!({
             "device": {
                 "identifiers": [&self.device_id],
                 "name": "Ping LEDs",
                 "manufacturer": "Shish",
-                "model": "ESP32-C3 Ping Monitor"
+                "model": "ESP32-C3 Ping Monitor",
+                "sw_version": system::sw_version(),
+                "hw_version": system::hw_version()
             },
             "origin": {
                 "name": "esp-ping-leds-firmware"
@@ -298,6 +657,9 @@ impl MqttManager {
                     "command_topic": format!("{}/light/set", self.device_path),
                     "brightness": true,
                     "brightness_scale": 255,
+                    "effect": true,
+                    "effect_list": render::light_effects(),
+                    "supported_color_modes": ["rgb"],
                     "schema": "json"
                 },
                 "min_healthy_duration": {
@@ -347,10 +709,24 @@ impl MqttManager {
                     "state_topic": format!("{}/led_count/state", self.device_path),
                     "command_topic": format!("{}/led_count/set", self.device_path),
                     "min": 1,
-                    "max": 300,
+                    "max": MAX_LED_COUNT,
                     "step": 1,
                     "mode": "box"
                 },
+                "power_budget": {
+                    "platform": "number",
+                    "name": "Power Budget",
+                    "unique_id": format!("{}_power_budget", self.device_id),
+                    "object_id": format!("{}_power_budget", self.device_id),
+                    "state_topic": format!("{}/power_budget/state", self.device_path),
+                    "command_topic": format!("{}/power_budget/set", self.device_path),
+                    "unit_of_measurement": "mA",
+                    "min": 0,
+                    "max": MAX_POWER_BUDGET,
+                    "step": 50,
+                    "mode": "box",
+                    "entity_category": "config"
+                },
                 "ping_host": {
                     "platform": "text",
                     "name": "Ping Host",
@@ -359,10 +735,281 @@ impl MqttManager {
                     "state_topic": format!("{}/ping_host/state", self.device_path),
                     "command_topic": format!("{}/ping_host/set", self.device_path),
                     "mode": "text"
+                },
+                "led_offset": {
+                    "platform": "number",
+                    "name": "LED Offset",
+                    "unique_id": format!("{}_led_offset", self.device_id),
+                    "object_id": format!("{}_led_offset", self.device_id),
+                    "state_topic": format!("{}/led_offset/state", self.device_path),
+                    "command_topic": format!("{}/led_offset/set", self.device_path),
+                    "min": 0,
+                    "max": layout::MAX_INDEX - 1,
+                    "step": 1,
+                    "mode": "box",
+                    "entity_category": "config"
+                },
+                "led_reverse": {
+                    "platform": "switch",
+                    "name": "LED Reverse",
+                    "unique_id": format!("{}_led_reverse", self.device_id),
+                    "object_id": format!("{}_led_reverse", self.device_id),
+                    "state_topic": format!("{}/led_reverse/state", self.device_path),
+                    "command_topic": format!("{}/led_reverse/set", self.device_path),
+                    "payload_on": "true",
+                    "payload_off": "false",
+                    "entity_category": "config",
+                    "icon": "mdi:swap-horizontal"
+                },
+                "led_rotation": {
+                    "platform": "number",
+                    "name": "LED Rotation",
+                    "unique_id": format!("{}_led_rotation", self.device_id),
+                    "object_id": format!("{}_led_rotation", self.device_id),
+                    "state_topic": format!("{}/led_rotation/state", self.device_path),
+                    "command_topic": format!("{}/led_rotation/set", self.device_path),
+                    "min": 0,
+                    "max": layout::MAX_INDEX - 1,
+                    "step": 1,
+                    "mode": "box",
+                    "entity_category": "config"
+                },
+                "led_skip": {
+                    "platform": "text",
+                    "name": "LED Skip",
+                    "unique_id": format!("{}_led_skip", self.device_id),
+                    "object_id": format!("{}_led_skip", self.device_id),
+                    "state_topic": format!("{}/led_skip/state", self.device_path),
+                    "command_topic": format!("{}/led_skip/set", self.device_path),
+                    "mode": "text",
+                    "entity_category": "config"
+                },
+                "led_segments": {
+                    "platform": "text",
+                    "name": "LED Segments",
+                    "unique_id": format!("{}_led_segments", self.device_id),
+                    "object_id": format!("{}_led_segments", self.device_id),
+                    "state_topic": format!("{}/led_segments/state", self.device_path),
+                    "command_topic": format!("{}/led_segments/set", self.device_path),
+                    "mode": "text",
+                    "entity_category": "config"
+                },
+                "matrix_width": {
+                    "platform": "number",
+                    "name": "Matrix Width",
+                    "unique_id": format!("{}_matrix_width", self.device_id),
+                    "object_id": format!("{}_matrix_width", self.device_id),
+                    "state_topic": format!("{}/matrix_width/state", self.device_path),
+                    "command_topic": format!("{}/matrix_width/set", self.device_path),
+                    "min": 0,
+                    "max": MAX_LED_COUNT,
+                    "step": 1,
+                    "mode": "box",
+                    "entity_category": "config"
+                },
+                "matrix_wiring": {
+                    "platform": "select",
+                    "name": "Matrix Wiring",
+                    "unique_id": format!("{}_matrix_wiring", self.device_id),
+                    "object_id": format!("{}_matrix_wiring", self.device_id),
+                    "state_topic": format!("{}/matrix_wiring/state", self.device_path),
+                    "command_topic": format!("{}/matrix_wiring/set", self.device_path),
+                    "options": Wiring::ALL.iter().map(|w| w.name()).collect::<Vec<_>>(),
+                    "entity_category": "config",
+                    "icon": "mdi:grid"
+                },
+                "led_dithering": {
+                    "platform": "switch",
+                    "name": "Dithering",
+                    "unique_id": format!("{}_led_dithering", self.device_id),
+                    "object_id": format!("{}_led_dithering", self.device_id),
+                    "state_topic": format!("{}/led_dithering/state", self.device_path),
+                    "command_topic": format!("{}/led_dithering/set", self.device_path),
+                    "payload_on": "true",
+                    "payload_off": "false",
+                    "entity_category": "config",
+                    "icon": "mdi:blur"
+                },
+                "colour_scale": {
+                    "platform": "select",
+                    "name": "Colour Scale",
+                    "unique_id": format!("{}_colour_scale", self.device_id),
+                    "object_id": format!("{}_colour_scale", self.device_id),
+                    "state_topic": format!("{}/colour_scale/state", self.device_path),
+                    "command_topic": format!("{}/colour_scale/set", self.device_path),
+                    "options": Scale::ALL.iter().map(|s| s.name()).collect::<Vec<_>>(),
+                    "icon": "mdi:chart-bell-curve"
+                },
+                "baseline_window": {
+                    "platform": "number",
+                    "name": "Baseline Window",
+                    "unique_id": format!("{}_baseline_window", self.device_id),
+                    "object_id": format!("{}_baseline_window", self.device_id),
+                    "state_topic": format!("{}/baseline_window/state", self.device_path),
+                    "command_topic": format!("{}/baseline_window/set", self.device_path),
+                    "unit_of_measurement": "h",
+                    "min": 1,
+                    "max": 72,
+                    "step": 1,
+                    "mode": "box"
+                },
+                "text_interval": {
+                    "platform": "number",
+                    "name": "Text Interval",
+                    "unique_id": format!("{}_text_interval", self.device_id),
+                    "object_id": format!("{}_text_interval", self.device_id),
+                    "state_topic": format!("{}/text_interval/state", self.device_path),
+                    "command_topic": format!("{}/text_interval/set", self.device_path),
+                    "unit_of_measurement": "s",
+                    "min": 0,
+                    "max": 3600,
+                    "step": 1,
+                    "mode": "box"
+                },
+                "status_pixels": {
+                    "platform": "text",
+                    "name": "Status Pixels",
+                    "unique_id": format!("{}_status_pixels", self.device_id),
+                    "object_id": format!("{}_status_pixels", self.device_id),
+                    "state_topic": format!("{}/status_pixels/state", self.device_path),
+                    "command_topic": format!("{}/status_pixels/set", self.device_path),
+                    "mode": "text",
+                    "entity_category": "config"
+                },
+                "bar_peak_hold": {
+                    "platform": "number",
+                    "name": "Bar Peak Hold",
+                    "unique_id": format!("{}_bar_peak_hold", self.device_id),
+                    "object_id": format!("{}_bar_peak_hold", self.device_id),
+                    "state_topic": format!("{}/bar_peak_hold/state", self.device_path),
+                    "command_topic": format!("{}/bar_peak_hold/set", self.device_path),
+                    "unit_of_measurement": "s",
+                    "min": 0,
+                    "max": 3600,
+                    "step": 1,
+                    "mode": "box"
+                },
+                "dial_span": {
+                    "platform": "select",
+                    "name": "Dial Span",
+                    "unique_id": format!("{}_dial_span", self.device_id),
+                    "object_id": format!("{}_dial_span", self.device_id),
+                    "state_topic": format!("{}/dial_span/state", self.device_path),
+                    "command_topic": format!("{}/dial_span/set", self.device_path),
+                    "options": DialSpan::ALL.iter().map(|s| s.name()).collect::<Vec<_>>(),
+                    "icon": "mdi:clock-outline"
+                },
+                "utc_offset": {
+                    "platform": "number",
+                    "name": "UTC Offset",
+                    "unique_id": format!("{}_utc_offset", self.device_id),
+                    "object_id": format!("{}_utc_offset", self.device_id),
+                    "state_topic": format!("{}/utc_offset/state", self.device_path),
+                    "command_topic": format!("{}/utc_offset/set", self.device_path),
+                    "unit_of_measurement": "min",
+                    "min": MIN_UTC_OFFSET,
+                    "max": MAX_UTC_OFFSET,
+                    "step": 15,
+                    "mode": "box",
+                    "entity_category": "config"
+                },
+                "log_span": {
+                    "platform": "number",
+                    "name": "Log Span",
+                    "unique_id": format!("{}_log_span", self.device_id),
+                    "object_id": format!("{}_log_span", self.device_id),
+                    "state_topic": format!("{}/log_span/state", self.device_path),
+                    "command_topic": format!("{}/log_span/set", self.device_path),
+                    "unit_of_measurement": "h",
+                    "min": 1,
+                    "max": 168,
+                    "step": 1,
+                    "mode": "box"
+                },
+                "log_aggregation": {
+                    "platform": "select",
+                    "name": "Log Aggregation",
+                    "unique_id": format!("{}_log_aggregation", self.device_id),
+                    "object_id": format!("{}_log_aggregation", self.device_id),
+                    "state_topic": format!("{}/log_aggregation/state", self.device_path),
+                    "command_topic": format!("{}/log_aggregation/set", self.device_path),
+                    "options": Aggregation::ALL.iter().map(|a| a.name()).collect::<Vec<_>>(),
+                    "icon": "mdi:sigma"
+                },
+                "auto_calibrate": {
+                    "platform": "switch",
+                    "name": "Auto Calibrate",
+                    "unique_id": format!("{}_auto_calibrate", self.device_id),
+                    "object_id": format!("{}_auto_calibrate", self.device_id),
+                    "state_topic": format!("{}/auto_calibrate/state", self.device_path),
+                    "command_topic": format!("{}/auto_calibrate/set", self.device_path),
+                    "payload_on": "true",
+                    "payload_off": "false",
+                    "entity_category": "config",
+                    "icon": "mdi:tune-variant"
+                },
+                "firmware": {
+                    "platform": "update",
+                    "name": "Firmware",
+                    "unique_id": format!("{}_firmware", self.device_id),
+                    "object_id": format!("{}_firmware", self.device_id),
+                    "device_class": "firmware",
+                    "entity_category": "config",
+                    "state_topic": format!("{}/update/state", self.device_path),
+                    "command_topic": format!("{}/update/install", self.device_path),
+                    "payload_install": "install"
+                },
+                "message": {
+                    "platform": "notify",
+                    "name": "Message",
+                    "unique_id": format!("{}_message", self.device_id),
+                    "object_id": format!("{}_message", self.device_id),
+                    "command_topic": format!("{}/message/set", self.device_path),
+                    "icon": "mdi:message-text"
+                },
+                "restart": {
+                    "platform": "button",
+                    "name": "Restart",
+                    "unique_id": format!("{}_restart", self.device_id),
+                    "object_id": format!("{}_restart", self.device_id),
+                    "command_topic": format!("{}/restart/set", self.device_path),
+                    "payload_press": "PRESS",
+                    "device_class": "restart",
+                    "entity_category": "config"
+                },
+                "anomaly": {
+                    "platform": "event",
+                    "name": "Latency Anomaly",
+                    "unique_id": format!("{}_anomaly", self.device_id),
+                    "object_id": format!("{}_anomaly", self.device_id),
+                    "state_topic": format!("{}/anomaly/state", self.device_path),
+                    "event_types": ["anomaly_started", "anomaly_ended"],
+                    "icon": "mdi:chart-bell-curve-cumulative"
                 }
             }
-        })
-        .to_string();
+        });
+        if let Some(components) = discovery_config["components"].as_object_mut() {
+            for sensor in SENSORS {
+                let mut component = serde_json::json!({
+                    "platform": "sensor",
+                    "name": sensor.name,
+                    "unique_id": format!("{}_{}", self.device_id, sensor.key),
+                    "object_id": format!("{}_{}", self.device_id, sensor.key),
+                    "state_topic": format!("{}/{}/state", self.device_path, sensor.key),
+                    "entity_category": "diagnostic",
+                    "icon": sensor.icon
+                });
+                if let Some(unit) = sensor.unit {
+                    component["unit_of_measurement"] = unit.into();
+                    component["state_class"] = "measurement".into();
+                }
+                if let Some(device_class) = sensor.device_class {
+                    component["device_class"] = device_class.into();
+                }
+                components.insert(sensor.key.to_string(), component);
+            }
+        }
+        let discovery_config = discovery_config.to_string();
 
         log::info!("Sending discovery config to {}/config", self.device_path);
 
@@ -377,24 +1024,38 @@ impl MqttManager {
         // Subscribe to all command topics
         self.client
             .subscribe(&format!("{}/light/set", self.device_path), QoS::AtLeastOnce)?;
+        for key in ENTITY_KEYS {
+            self.client.subscribe(
+                &format!("{}/{}/set", self.device_path, key.name()),
+                QoS::AtLeastOnce,
+            )?;
+        }
+        self.client.subscribe(
+            &format!("{}/wifi_networks/set", self.device_path),
+            QoS::AtLeastOnce,
+        )?;
+        self.client
+            .subscribe(&format!("{}/ota/set", self.device_path), QoS::AtLeastOnce)?;
         self.client.subscribe(
-            &format!("{}/min_healthy_duration/set", self.device_path),
+            &format!("{}/update/install", self.device_path),
             QoS::AtLeastOnce,
         )?;
         self.client.subscribe(
-            &format!("{}/max_healthy_duration/set", self.device_path),
+            &format!("{}/ota_latest_topic/set", self.device_path),
             QoS::AtLeastOnce,
         )?;
+        self.client
+            .subscribe(&self.latest_topic, QoS::AtLeastOnce)?;
         self.client.subscribe(
-            &format!("{}/led_strip_duration/set", self.device_path),
+            &format!("{}/restart/set", self.device_path),
             QoS::AtLeastOnce,
         )?;
         self.client.subscribe(
-            &format!("{}/led_count/set", self.device_path),
+            &format!("{}/message/set", self.device_path),
             QoS::AtLeastOnce,
         )?;
         self.client.subscribe(
-            &format!("{}/ping_host/set", self.device_path),
+            &format!("{}/colour_stops/set", self.device_path),
             QoS::AtLeastOnce,
         )?;
 
@@ -411,69 +1072,241 @@ impl MqttManager {
             .lock()
             .expect("Failed to lock config for publishing state");
 
+        let mut light_state = serde_json::json!({
+            "state": if cfg.led_enabled { "ON" } else { "OFF" },
+            "brightness": cfg.led_brightness,
+            "effect": render::light_effect(&cfg),
+            "color_mode": "rgb",
+        });
+        // Left out rather than null when showing latency colours
+        if let Some(c) = cfg.led_colour {
+            light_state["color"] = serde_json::json!({"r": c.r, "g": c.g, "b": c.b});
+        }
         self.client.enqueue(
             &format!("{}/light/state", self.device_path),
             QoS::AtLeastOnce,
             true,
-            serde_json::json!({
-                "state": if cfg.led_enabled { "ON" } else { "OFF" },
-                "brightness": cfg.led_brightness
-            })
-            .to_string()
-            .as_bytes(),
+            light_state.to_string().as_bytes(),
         )?;
+        for key in ENTITY_KEYS {
+            self.client.enqueue(
+                &format!("{}/{}/state", self.device_path, key.name()),
+                QoS::AtLeastOnce,
+                true,
+                cfg.get(key).as_bytes(),
+            )?;
+        }
         self.client.enqueue(
-            &format!("{}/min_healthy_duration/state", self.device_path),
+            &format!("{}/colour_stops/state", self.device_path),
             QoS::AtLeastOnce,
             true,
-            cfg.min_healthy_duration.as_millis().to_string().as_bytes(),
+            colour_stops_json(&cfg).to_string().as_bytes(),
         )?;
+        drop(cfg);
+        self.publish_update_state(None)?;
+
+        Ok(())
+    }
+
+    /// Publish the state of the firmware `update` entity, with the download
+    /// progress if an update is in progress
+    fn publish_update_state(&mut self, progress: Option<u8>) -> anyhow::Result<()> {
+        let latest_version = self
+            .latest_firmware
+            .lock()
+            .ok()
+            .and_then(|latest| latest.as_ref().map(|m| m.version.clone()))
+            .unwrap_or(system::VERSION.to_string());
         self.client.enqueue(
-            &format!("{}/max_healthy_duration/state", self.device_path),
+            &format!("{}/update/state", self.device_path),
             QoS::AtLeastOnce,
             true,
-            cfg.max_healthy_duration.as_millis().to_string().as_bytes(),
+            serde_json::json!({
+                "installed_version": system::VERSION,
+                "latest_version": latest_version,
+                "in_progress": progress.is_some(),
+                "update_percentage": progress,
+            })
+            .to_string()
+            .as_bytes(),
         )?;
+        Ok(())
+    }
+
+    /// Periodically publish state (call this from main loop)
+    pub fn periodic_publish(&mut self) -> anyhow::Result<()> {
+        self.back_off_after_refusal();
+        if !self.connected.load(Ordering::Relaxed) {
+            self.announced = false;
+        } else if !self.announced {
+            self.announce()?;
+        }
+
+        // Publish a pending state change, keeping it pending until it has
+        // gone out
+        if self.connected.load(Ordering::Relaxed) {
+            let pending = self.publish_pending.clone();
+            status::publish_if_pending(&pending, || self.publish_state())?;
+        }
+
+        if self
+            .last_diagnostics
+            .map_or(true, |last| last.elapsed() >= DIAGNOSTICS_INTERVAL)
+        {
+            self.last_diagnostics = Some(Instant::now());
+            self.publish_diagnostics()?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish the most recent crash report (retained), so it can be seen
+    /// even by clients which connect later; each report is only published
+    /// once, though it stays in the "Last Crash" sensor
+    fn publish_crash_report(&mut self, storage: &Mutex<Storage>) -> anyhow::Result<()> {
+        let Some(report) = &self.last_crash else {
+            return Ok(());
+        };
+        log::warn!("Last crash: {}", report.summary());
+        let mut storage = storage
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?;
+        if storage.crash_report_sent()? == Some(report.boot) {
+            return Ok(());
+        }
         self.client.enqueue(
-            &format!("{}/led_strip_duration/state", self.device_path),
+            &format!("{}/crash", self.device_path),
             QoS::AtLeastOnce,
             true,
-            cfg.led_strip_duration.as_secs().to_string().as_bytes(),
+            serde_json::json!({
+                "kind": report.kind.to_string(),
+                "message": report.message,
+                "location": report.location,
+                "boot": report.boot,
+                "uptime_s": report.uptime.as_secs(),
+            })
+            .to_string()
+            .as_bytes(),
         )?;
+        storage.set_crash_report_sent(report.boot)?;
+        Ok(())
+    }
+
+    /// Publish the diagnostic sensors
+    fn publish_diagnostics(&mut self) -> anyhow::Result<()> {
+        let diagnostics = system::diagnostics(
+            self.boot_count,
+            self.last_crash.as_ref(),
+            self.safe_mode,
+            self.led_current,
+        );
+        for (sensor, value) in SENSORS.iter().zip(diagnostics.values()) {
+            if let Some(value) = value {
+                self.client.enqueue(
+                    &format!("{}/{}/state", self.device_path, sensor.key),
+                    QoS::AtMostOnce,
+                    false,
+                    value.as_bytes(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a pending firmware update request, if any
+    pub fn take_ota_request(&self) -> Option<OtaRequest> {
+        self.ota_request.lock().ok()?.take()
+    }
+
+    /// Take the messages sent to show since the last call
+    pub fn take_messages(&self) -> Vec<String> {
+        self.messages
+            .lock()
+            .map(|mut messages| std::mem::take(&mut *messages))
+            .unwrap_or_default()
+    }
+
+    /// Publish an anomaly starting or ending, with how far from normal the
+    /// RTTs are. Not retained, as it is an event rather than a state.
+    pub fn publish_anomaly(&mut self, event: &AnomalyEvent) -> anyhow::Result<()> {
+        let deviation = event.deviation();
+        let ms = |d: Duration| (d.as_secs_f64() * 1000.0 * 10.0).round() / 10.0;
         self.client.enqueue(
-            &format!("{}/led_count/state", self.device_path),
+            &format!("{}/anomaly/state", self.device_path),
             QoS::AtLeastOnce,
-            true,
-            cfg.led_count.to_string().as_bytes(),
+            false,
+            serde_json::json!({
+                "event_type": event.name(),
+                "target": deviation.target,
+                "recent_ms": ms(deviation.recent),
+                "baseline_ms": ms(deviation.baseline),
+                "median_ms": ms(deviation.median),
+                "p95_ms": ms(deviation.p95),
+                "deviations": (deviation.deviations * 10.0).round() / 10.0,
+            })
+            .to_string()
+            .as_bytes(),
         )?;
+        Ok(())
+    }
+
+    /// Publish the progress or result of a firmware update
+    pub fn publish_ota_status(&mut self, status: &OtaStatus) -> anyhow::Result<()> {
         self.client.enqueue(
-            &format!("{}/ping_host/state", self.device_path),
+            &format!("{}/ota/state", self.device_path),
             QoS::AtLeastOnce,
             true,
-            cfg.ping_host.as_bytes(),
+            status.to_json().to_string().as_bytes(),
         )?;
-
-        Ok(())
+        match status {
+            OtaStatus::Downloading { progress, .. } => self.publish_update_state(Some(*progress)),
+            _ => self.publish_update_state(None),
+        }
     }
+}
 
-    /// Periodically publish state (call this from main loop)
-    pub fn periodic_publish(&mut self) -> anyhow::Result<()> {
-        // Check if there's a pending state change to publish
-        let should_publish = if let Ok(mut pending) = self.publish_pending.lock() {
-            if *pending {
-                *pending = false;
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        };
-
-        if should_publish {
-            self.publish_state()?;
-        }
+/// Parse a colour stops command, eg
+/// `[{"latency": 10, "colour": "#00ff00"}, {"latency": 100, "colour": "#ff0000"}]`
+/// with latencies in ms. An empty list (or payload) means the default stops.
+fn parse_colour_stops(payload: &str) -> anyhow::Result<Option<Vec<ColourStop>>> {
+    #[derive(Deserialize)]
+    struct StopCommand {
+        latency: u64,
+        colour: String,
+    }
 
-        Ok(())
+    if payload.trim().is_empty() {
+        return Ok(None);
     }
+    let commands =
+        serde_json::from_str::<Vec<StopCommand>>(payload).map_err(|e| anyhow::anyhow!("{}", e))?;
+    if commands.is_empty() {
+        return Ok(None);
+    }
+    let stops = commands
+        .iter()
+        .map(|c| {
+            colour::parse_hex_colour(&c.colour)
+                .map(|colour| ColourStop::new(Duration::from_millis(c.latency), colour))
+                .ok_or_else(|| ColourError::InvalidColour(c.colour.clone()))
+        })
+        .collect::<Result<Vec<ColourStop>, ColourError>>()?;
+    colour::validate_stops(&stops)?;
+    Ok(Some(stops))
+}
+
+/// The colour stops in use, in the same format as the command
+fn colour_stops_json(config: &Config) -> serde_json::Value {
+    let stops = config.colour_stops.clone().unwrap_or_else(|| {
+        rgb::default_stops(config.min_healthy_duration, config.max_healthy_duration)
+    });
+    stops
+        .iter()
+        .map(|stop| {
+            serde_json::json!({
+                "latency": stop.latency.as_millis() as u64,
+                "colour": colour::to_hex_colour(stop.colour),
+            })
+        })
+        .collect()
 }
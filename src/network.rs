@@ -1,20 +1,31 @@
+use anyhow::Context;
 use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
     handle::RawHandle,
     ipv4::Ipv4Addr,
-    wifi::{AuthMethod, BlockingWifi, EspWifi},
+    wifi::{AuthMethod, BlockingWifi, EspWifi, WifiEvent},
+};
+use esp_ping_leds::blink::BootFailure;
+use esp_ping_leds::wifi::{rank_networks, WifiNetwork};
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
 };
 use std::time::Duration;
 use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
 
 use crate::{debug_lights, BootStage};
 
+/// Connect to the best available known network, returning its SSID. If
+/// that fails, the error lists why each network didn't work, with a
+/// `BootFailure` context for the one which got furthest, where we know.
 pub fn connect_wifi(
     ws2812: &mut Ws2812Esp32Rmt,
     wifi: &mut BlockingWifi<EspWifi<'static>>,
-    ssid: &str,
-    password: &str,
-) -> anyhow::Result<()> {
-    log::info!("Wifi starting, target: {}...", ssid);
+    sysloop: &EspSystemEventLoop,
+    networks: &[WifiNetwork],
+) -> anyhow::Result<String> {
+    log::info!("Wifi starting, {} known networks...", networks.len());
     debug_lights(ws2812, BootStage::WifiStarting)?;
 
     // Set hostname before starting wifi
@@ -30,43 +41,105 @@ pub fn connect_wifi(
 
     wifi.start()?;
 
-    let auth_method = scan_wifi(wifi, ssid, password)?;
+    // The driver only says why a connection failed in its disconnect event
+    let disconnect_reason = Arc::new(AtomicU16::new(0));
+    let _subscription = {
+        let disconnect_reason = disconnect_reason.clone();
+        sysloop.subscribe::<WifiEvent, _>(move |event| {
+            if let WifiEvent::StaDisconnected(disconnected) = event {
+                disconnect_reason.store(disconnected.reason(), Ordering::Relaxed);
+            }
+        })?
+    };
+
+    let mut errors = Vec::new();
+    let mut failure = None;
+    for (network, auth_method) in scan_wifi(wifi, networks)? {
+        disconnect_reason.store(0, Ordering::Relaxed);
+        match connect_network(ws2812, wifi, network, auth_method, &disconnect_reason) {
+            Ok(_) => {
+                debug_lights(ws2812, BootStage::WifiComplete)?;
+                return Ok(network.ssid.clone());
+            }
+            Err(e) => {
+                log::warn!("Failed to connect to {}: {:#}", network.ssid, e);
+                failure = failure.max(e.downcast_ref::<BootFailure>().copied());
+                errors.push(format!("{}: {:#}", network.ssid, e));
+                if let Err(e) = wifi.disconnect() {
+                    log::debug!("Disconnect after failure: {}", e);
+                }
+            }
+        }
+    }
+
+    let error = anyhow::anyhow!(
+        "Could not connect to any of {} known networks ({})",
+        networks.len(),
+        errors.join("; ")
+    );
+    Err(match failure {
+        Some(failure) => error.context(failure),
+        None => error,
+    })
+}
+
+fn connect_network(
+    ws2812: &mut Ws2812Esp32Rmt,
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    network: &WifiNetwork,
+    auth_method: AuthMethod,
+    disconnect_reason: &AtomicU16,
+) -> anyhow::Result<()> {
+    log::info!("Trying {} with auth method {:?}", network.ssid, auth_method);
     let wifi_configuration =
         esp_idf_svc::wifi::Configuration::Client(esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid.try_into().expect("Wifi SSID invalid"),
+            ssid: network.ssid.as_str().try_into().expect("Wifi SSID invalid"),
             auth_method,
-            password: password.try_into().expect("Wifi password invalid"),
+            password: network
+                .password
+                .as_str()
+                .try_into()
+                .expect("Wifi password invalid"),
             ..Default::default()
         });
     wifi.set_configuration(&wifi_configuration)?;
 
     log::info!("Connecting...");
     debug_lights(ws2812, BootStage::WifiConnecting)?;
-    wifi.connect()?;
+    if let Err(e) = wifi.connect() {
+        let reason = disconnect_reason.load(Ordering::Relaxed);
+        let error = anyhow::Error::new(e).context(format!("disconnect reason {}", reason));
+        return Err(match BootFailure::from_wifi_reason(reason) {
+            Some(failure) => error.context(failure),
+            None => error,
+        });
+    }
 
     log::info!("Waiting for DHCP...");
     debug_lights(ws2812, BootStage::WifiDhcp)?;
-    wifi.wait_netif_up()?;
+    wifi.wait_netif_up().context(BootFailure::DhcpTimeout)?;
 
     log::info!("Print DHCP info...");
     debug_lights(ws2812, BootStage::WifiPrintInfo)?;
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
     log::info!("Wifi DHCP info: {:?}", ip_info);
 
-    debug_lights(ws2812, BootStage::WifiComplete)?;
     Ok(())
 }
 
-pub fn scan_wifi(
+/// Scan for known networks, returning them in the order they should be tried
+/// along with the auth method each one advertises
+pub fn scan_wifi<'a>(
     wifi: &mut BlockingWifi<EspWifi<'static>>,
-    ssid: &str,
-    password: &str,
-) -> anyhow::Result<AuthMethod> {
+    networks: &'a [WifiNetwork],
+) -> anyhow::Result<Vec<(&'a WifiNetwork, AuthMethod)>> {
     log::info!("Scanning...");
-    let guessed_auth = if password.is_empty() {
-        AuthMethod::None
-    } else {
-        AuthMethod::WPA2Personal
+    let guess_auth = |network: &WifiNetwork| {
+        if network.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        }
     };
     match wifi.scan() {
         Ok(aps) => {
@@ -76,27 +149,41 @@ pub fn scan_wifi(
                     i.ssid, i.bssid, i.channel, i.signal_strength, i.auth_method
                 )
             });
-            let ours = aps.into_iter().find(|a| a.ssid == ssid);
-            if let Some(ours) = ours {
-                if let Some(auth_method) = ours.auth_method {
-                    log::info!(
-                        "Found configured AP {} with auth method {:?}",
-                        ssid,
-                        auth_method
-                    );
-                    Ok(auth_method)
-                } else {
-                    log::info!("Found configured AP {} with unknown auth method", ssid);
-                    Ok(guessed_auth)
-                }
-            } else {
-                log::debug!("Configured AP {} not found", ssid);
-                Ok(guessed_auth)
-            }
+            let visible: Vec<(&str, i8)> = aps
+                .iter()
+                .map(|a| (a.ssid.as_str(), a.signal_strength))
+                .collect();
+            Ok(rank_networks(networks, &visible)
+                .into_iter()
+                .map(|network| {
+                    let ours = aps.iter().find(|a| a.ssid == network.ssid.as_str());
+                    match ours.map(|a| a.auth_method) {
+                        Some(Some(auth_method)) => {
+                            log::info!(
+                                "Found configured AP {} with auth method {:?}",
+                                network.ssid,
+                                auth_method
+                            );
+                            (network, auth_method)
+                        }
+                        Some(None) => {
+                            log::info!(
+                                "Found configured AP {} with unknown auth method",
+                                network.ssid
+                            );
+                            (network, guess_auth(network))
+                        }
+                        None => {
+                            log::debug!("Configured AP {} not found", network.ssid);
+                            (network, guess_auth(network))
+                        }
+                    }
+                })
+                .collect())
         }
         Err(e) => {
             log::error!("Wifi scan failed: {}", e);
-            Ok(guessed_auth)
+            Ok(networks.iter().map(|n| (n, guess_auth(n))).collect())
         }
     }
 }
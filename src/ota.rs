@@ -0,0 +1,244 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Size of each OTA app slot in partitions.csv
+pub const MAX_IMAGE_SIZE: u32 = 0x1e0000;
+
+/// A firmware release, as described by a JSON manifest like:
+///
+/// ```json
+/// {"version": "0.3.0", "url": "http://example.com/esp-ping-leds.bin",
+///  "size": 1234567, "sha256": "ab12..."}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    pub size: u32,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtaError {
+    InvalidVersion,
+    InvalidUrl(String),
+    InvalidSize(u32),
+    InvalidDigest(String),
+    /// The download went past the size given in the manifest
+    TooLong {
+        expected: u32,
+    },
+    SizeMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    DigestMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::InvalidVersion => write!(f, "manifest has no version"),
+            OtaError::InvalidUrl(url) => write!(f, "invalid image URL {:?}", url),
+            OtaError::InvalidSize(size) => write!(
+                f,
+                "invalid image size {} (must be 1-{} bytes)",
+                size, MAX_IMAGE_SIZE
+            ),
+            OtaError::InvalidDigest(hex) => write!(f, "invalid SHA-256 {:?}", hex),
+            OtaError::TooLong { expected } => {
+                write!(f, "image is larger than the expected {} bytes", expected)
+            }
+            OtaError::SizeMismatch { expected, actual } => {
+                write!(f, "image is {} bytes, expected {} bytes", actual, expected)
+            }
+            OtaError::DigestMismatch { expected, actual } => {
+                write!(f, "image SHA-256 is {}, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+impl Manifest {
+    pub fn new(version: &str, url: &str, size: u32, sha256: &str) -> Result<Self, OtaError> {
+        let version = version.trim();
+        if version.is_empty() {
+            return Err(OtaError::InvalidVersion);
+        }
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(OtaError::InvalidUrl(url.to_string()));
+        }
+        if size == 0 || size > MAX_IMAGE_SIZE {
+            return Err(OtaError::InvalidSize(size));
+        }
+        let digest = from_hex(sha256).ok_or_else(|| OtaError::InvalidDigest(sha256.to_string()))?;
+        Ok(Self {
+            version: version.to_string(),
+            url: url.to_string(),
+            size,
+            sha256: digest,
+        })
+    }
+}
+
+/// Lower-case hex, as used in manifests and by `sha256sum`
+pub fn to_hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a 64-character hex digest, in either case
+pub fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Checks an image against its manifest as it is downloaded
+#[derive(Debug, Clone)]
+pub struct ImageVerifier {
+    expected_size: u32,
+    expected_sha256: [u8; 32],
+    received: u32,
+    sha: Sha256,
+}
+
+impl ImageVerifier {
+    pub fn new(manifest: &Manifest) -> Self {
+        Self {
+            expected_size: manifest.size,
+            expected_sha256: manifest.sha256,
+            received: 0,
+            sha: Sha256::new(),
+        }
+    }
+
+    /// Add the next chunk of the image; fails as soon as the image is
+    /// longer than expected, so we don't write junk past the end
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), OtaError> {
+        let received = self.received as u64 + chunk.len() as u64;
+        if received > self.expected_size as u64 {
+            return Err(OtaError::TooLong {
+                expected: self.expected_size,
+            });
+        }
+        self.received = received as u32;
+        self.sha.update(chunk);
+        Ok(())
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// How much of the image has been received, 0-100
+    pub fn progress_percent(&self) -> u8 {
+        (self.received as u64 * 100 / self.expected_size.max(1) as u64) as u8
+    }
+
+    /// Check that the complete image matches the manifest
+    pub fn finish(self) -> Result<(), OtaError> {
+        if self.received != self.expected_size {
+            return Err(OtaError::SizeMismatch {
+                expected: self.expected_size,
+                actual: self.received,
+            });
+        }
+        let actual: [u8; 32] = self.sha.finalize().into();
+        if actual != self.expected_sha256 {
+            return Err(OtaError::DigestMismatch {
+                expected: to_hex(&self.expected_sha256),
+                actual: to_hex(&actual),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_ota {
+    use super::*;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn manifest(size: u32) -> Manifest {
+        Manifest::new("0.3.0", "http://example.com/fw.bin", size, ABC_SHA256)
+            .expect("valid manifest")
+    }
+
+    #[test]
+    fn validates_manifest() {
+        assert_eq!(manifest(3).version, "0.3.0");
+        assert_eq!(
+            Manifest::new("", "http://x/fw.bin", 3, ABC_SHA256),
+            Err(OtaError::InvalidVersion)
+        );
+        assert!(matches!(
+            Manifest::new("1", "ftp://x/fw.bin", 3, ABC_SHA256),
+            Err(OtaError::InvalidUrl(_))
+        ));
+        assert_eq!(
+            Manifest::new("1", "http://x/fw.bin", MAX_IMAGE_SIZE + 1, ABC_SHA256),
+            Err(OtaError::InvalidSize(MAX_IMAGE_SIZE + 1))
+        );
+        assert!(matches!(
+            Manifest::new("1", "http://x/fw.bin", 3, "abc"),
+            Err(OtaError::InvalidDigest(_))
+        ));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let hex = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let digest = from_hex(hex).expect("valid hex");
+        assert_eq!(to_hex(&digest), hex.to_lowercase());
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex(&"zz".repeat(32)), None);
+    }
+
+    #[test]
+    fn accepts_matching_image() {
+        let mut verifier = ImageVerifier::new(&manifest(3));
+        verifier.update(b"a").expect("within size");
+        assert_eq!(verifier.progress_percent(), 33);
+        verifier.update(b"bc").expect("within size");
+        assert_eq!(verifier.progress_percent(), 100);
+        assert_eq!(verifier.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_wrong_size_or_digest() {
+        let mut verifier = ImageVerifier::new(&manifest(3));
+        assert_eq!(
+            verifier.update(b"abcd"),
+            Err(OtaError::TooLong { expected: 3 })
+        );
+
+        let mut verifier = ImageVerifier::new(&manifest(3));
+        verifier.update(b"ab").expect("within size");
+        assert_eq!(
+            verifier.finish(),
+            Err(OtaError::SizeMismatch {
+                expected: 3,
+                actual: 2
+            })
+        );
+
+        let mut verifier = ImageVerifier::new(&manifest(3));
+        verifier.update(b"abd").expect("within size");
+        assert!(matches!(
+            verifier.finish(),
+            Err(OtaError::DigestMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+use smart_leds::RGB8;
+
+/// WS2812s are driven with PWM, so their light output is linear in the
+/// value sent, while the colours we compute are meant to look evenly spaced
+const GAMMA: f32 = 2.2;
+/// Full scale of the intermediate linear values; the low byte is what
+/// dithering spreads over time
+const LINEAR_MAX: f32 = u16::MAX as f32;
+
+/// Perceived lightness for a 0-255 brightness setting (as Home Assistant
+/// sends it), as a 0-1 light output, following CIE L*: equal brightness
+/// steps look like equal changes, rather than the bottom few being huge
+pub fn perceived_brightness(brightness: u8) -> f32 {
+    let lightness = brightness as f32 * 100.0 / 255.0;
+    if lightness > 8.0 {
+        ((lightness + 16.0) / 116.0).powi(3)
+    } else {
+        lightness / 903.3
+    }
+}
+
+/// Post-processing between computing colours and writing them to the
+/// strip: gamma correction, the brightness curve, and optional temporal
+/// dithering. Keeps per-LED state for dithering, so one instance should be
+/// used per strip and called once per frame.
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// The brightness `table` was built for
+    brightness: Option<u8>,
+    /// Linear output (0-65535) for each input channel value
+    table: Vec<u16>,
+    /// Fraction of a step not yet shown, per LED channel
+    residue: Vec<[u8; 3]>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Self {
+            brightness: None,
+            table: vec![0; 256],
+            residue: Vec::new(),
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        if self.brightness == Some(brightness) {
+            return;
+        }
+        let scale = perceived_brightness(brightness) * LINEAR_MAX;
+        for (value, out) in self.table.iter_mut().enumerate() {
+            let linear = (value as f32 / 255.0).powf(GAMMA);
+            *out = (linear * scale).round() as u16;
+        }
+        self.brightness = Some(brightness);
+    }
+
+    /// The values to send for `pixels` at `brightness`. Without dithering
+    /// each channel is rounded to the nearest step; with it, the fraction
+    /// is carried over to later frames, so a channel which should be 0.25
+    /// is lit one frame in four rather than never.
+    pub fn frame(&mut self, pixels: &[RGB8], brightness: u8, dithering: bool) -> Vec<RGB8> {
+        self.set_brightness(brightness);
+        self.residue.resize(pixels.len(), [0; 3]);
+        pixels
+            .iter()
+            .zip(self.residue.iter_mut())
+            .map(|(pixel, residue)| {
+                let channel = |value: u8, residue: &mut u8| {
+                    let linear = self.table[value as usize] as u32;
+                    if dithering {
+                        let total = linear + *residue as u32;
+                        *residue = (total & 0xff) as u8;
+                        (total >> 8).min(255) as u8
+                    } else {
+                        ((linear + 0x80) >> 8).min(255) as u8
+                    }
+                };
+                let [r, g, b] = residue;
+                RGB8::new(
+                    channel(pixel.r, r),
+                    channel(pixel.g, g),
+                    channel(pixel.b, b),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_output {
+    use super::*;
+
+    #[test]
+    fn brightness_curve() {
+        assert_eq!(perceived_brightness(0), 0.0);
+        assert!((perceived_brightness(255) - 1.0).abs() < 0.001);
+        // Half brightness looks half as bright, with a fifth of the light
+        assert!((perceived_brightness(128) - 0.18).abs() < 0.01);
+        // Small steps at the bottom
+        assert!(perceived_brightness(1) < 0.001);
+        let steps: Vec<f32> = (0..=255u8).map(perceived_brightness).collect();
+        assert!(steps.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn gamma_corrects() {
+        let mut output = Output::new();
+        let pixels = [RGB8::new(255, 128, 0), RGB8::new(1, 10, 64)];
+        assert_eq!(
+            output.frame(&pixels, 255, false),
+            [RGB8::new(255, 56, 0), RGB8::new(0, 0, 12)]
+        );
+        // Half brightness
+        assert_eq!(
+            output.frame(&pixels, 128, false),
+            [RGB8::new(48, 10, 0), RGB8::new(0, 0, 2)]
+        );
+        assert_eq!(output.frame(&pixels, 0, false), [RGB8::default(); 2]);
+    }
+
+    #[test]
+    fn dithering_keeps_dim_colours() {
+        let mut output = Output::new();
+        // Dim enough that green rounds away to nothing without dithering
+        let pixels = [RGB8::new(255, 100, 0)];
+        let plain = output.frame(&pixels, 20, false);
+        assert_eq!(plain[0].g, 0);
+
+        let frames: Vec<RGB8> = (0..256)
+            .map(|_| output.frame(&pixels, 20, true)[0])
+            .collect();
+        let average = |f: fn(&RGB8) -> u8| {
+            frames.iter().map(|p| f(p) as u32).sum::<u32>() as f32 / frames.len() as f32
+        };
+        let expected =
+            |value: u8| (value as f32 / 255.0).powf(GAMMA) * perceived_brightness(20) * 255.0;
+        assert!((average(|p| p.r) - expected(255)).abs() < 0.05);
+        assert!((average(|p| p.g) - expected(100)).abs() < 0.05);
+        assert!(average(|p| p.g) > 0.0);
+        assert_eq!(average(|p| p.b), 0.0);
+        // Only ever one step apart
+        assert!(frames.iter().all(|p| p.r.abs_diff(plain[0].r) <= 1));
+    }
+}
@@ -0,0 +1,178 @@
+use esp_idf_svc::{
+    hal::delay::FreeRtos,
+    http::{
+        server::{Configuration as HttpConfiguration, EspHttpServer},
+        Method,
+    },
+    io::Write,
+    wifi::{
+        AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi, WifiDeviceId,
+    },
+};
+use esp_ping_leds::provisioning::{captive_dns_response, render_page, ProvisioningSettings};
+use smart_leds::{SmartLedsWrite, RGB};
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
+
+use crate::storage::Storage;
+
+/// Give up and retry WiFi if nobody configures us within this time
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Longest form submission we will accept
+const MAX_FORM_LEN: usize = 2048;
+
+/// Start an open access point serving a configuration page, and wait until
+/// new settings have been saved (or the portal times out). The caller is
+/// expected to restart afterwards.
+pub fn run_portal(
+    ws2812: &mut Ws2812Esp32Rmt,
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    storage: Arc<Mutex<Storage>>,
+) -> anyhow::Result<()> {
+    // Alternating amber, so it's obvious we aren't showing ping history
+    ws2812.write((0..24).map(|n| {
+        if n % 2 == 0 {
+            RGB::new(100, 50, 0)
+        } else {
+            RGB::new(0, 0, 0)
+        }
+    }))?;
+
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    let mac_address = wifi.wifi().driver().get_mac(WifiDeviceId::Ap)?;
+    let ssid = format!("ping-leds-{:02x}{:02x}", mac_address[4], mac_address[5]);
+    log::info!("Starting provisioning access point {}", ssid);
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().try_into().expect("AP SSID invalid"),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Provisioning portal at http://{}/", ip);
+
+    let saved = Arc::new(AtomicBool::new(false));
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let page_storage = storage.clone();
+    server.fn_handler::<anyhow::Error, _>("/", Method::Get, move |req| {
+        let current = page_storage
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?
+            .provisioning_settings()
+            .unwrap_or_default();
+        req.into_ok_response()?
+            .write_all(render_page(&current, None).as_bytes())?;
+        Ok(())
+    })?;
+
+    let save_storage = storage.clone();
+    let save_flag = saved.clone();
+    server.fn_handler::<anyhow::Error, _>("/save", Method::Post, move |mut req| {
+        let mut body = vec![];
+        let mut buf = [0u8; 256];
+        loop {
+            let n = req.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+            if body.len() > MAX_FORM_LEN {
+                req.into_status_response(413)?
+                    .write_all(b"Request too big")?;
+                return Ok(());
+            }
+        }
+        let body = String::from_utf8_lossy(&body);
+        let stored = save_storage
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?
+            .provisioning_settings()
+            .unwrap_or_default();
+
+        match ProvisioningSettings::from_form(&body, &stored) {
+            Ok(settings) => {
+                save_storage
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?
+                    .set_provisioning_settings(&settings)?;
+                log::info!(
+                    "Provisioning saved: {} networks, MQTT {}",
+                    settings.networks.len(),
+                    if settings.mqtt_url.is_some() {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                );
+                req.into_ok_response()?
+                    .write_all(b"Settings saved, rebooting...")?;
+                save_flag.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log::warn!("Provisioning form rejected: {}", e);
+                let current = save_storage
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Storage lock poisoned"))?
+                    .provisioning_settings()
+                    .unwrap_or_default();
+                req.into_status_response(400)?
+                    .write_all(render_page(&current, Some(&e.to_string())).as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    // Anything else (eg OS connectivity checks) gets sent to the form
+    let location = format!("http://{}/", ip);
+    server.fn_handler::<anyhow::Error, _>("/*", Method::Get, move |req| {
+        req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+        Ok(())
+    })?;
+
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = dns_server(ip) {
+                log::error!("Captive DNS server failed: {}", e);
+            }
+        })?;
+
+    let started = Instant::now();
+    while !saved.load(Ordering::Relaxed) && started.elapsed() < PORTAL_TIMEOUT {
+        FreeRtos::delay_ms(500);
+    }
+    if !saved.load(Ordering::Relaxed) {
+        log::warn!("Provisioning portal timed out");
+    }
+
+    // Give the browser a chance to receive the response
+    FreeRtos::delay_ms(1000);
+    drop(server);
+    Ok(())
+}
+
+/// Answer every DNS lookup with our own address
+fn dns_server(ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = captive_dns_response(&buf[..len], ip) {
+            socket.send_to(&reply, peer)?;
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use smart_leds::RGB8;
+
+/// Current drawn by one WS2812B channel at full duty, in µA
+const CHANNEL_UA: u64 = 12_000;
+/// Current drawn by each pixel's controller, lit or not, in µA
+const IDLE_UA: u64 = 1_000;
+
+/// Largest supply budget which can be set, in mA
+pub const MAX_POWER_BUDGET: u32 = 10_000;
+/// Budget until one is set, in mA: safe for a USB port powering the board
+/// as well as a short strip
+pub const DEFAULT_POWER_BUDGET: u32 = 500;
+
+/// The idle and lit current of `pixels` (values as sent to the strip), in
+/// µA
+fn current_ua(pixels: &[RGB8]) -> (u64, u64) {
+    let duty: u64 = pixels
+        .iter()
+        .map(|p| p.r as u64 + p.g as u64 + p.b as u64)
+        .sum();
+    (pixels.len() as u64 * IDLE_UA, duty * CHANNEL_UA / 255)
+}
+
+/// Estimated current drawn by the strip showing `pixels`, in mA
+pub fn estimate_ma(pixels: &[RGB8]) -> u32 {
+    let (idle, lit) = current_ua(pixels);
+    ((idle + lit) / 1000) as u32
+}
+
+/// Dim `pixels` uniformly so the strip draws no more than `budget_ma`, or
+/// leave them be if the budget is 0 (no limit). Returns the estimated
+/// current afterwards, in mA; this can still be over the budget if the
+/// idle current alone is.
+pub fn limit(pixels: &mut [RGB8], budget_ma: u32) -> u32 {
+    let (idle, lit) = current_ua(pixels);
+    let available = (budget_ma as u64 * 1000).saturating_sub(idle);
+    if budget_ma > 0 && lit > available {
+        // Rounding down keeps us under the budget
+        let scale = |c: u8| (c as u64 * available / lit) as u8;
+        for pixel in pixels.iter_mut() {
+            *pixel = RGB8::new(scale(pixel.r), scale(pixel.g), scale(pixel.b));
+        }
+    }
+    estimate_ma(pixels)
+}
+
+#[cfg(test)]
+mod test_power {
+    use super::*;
+
+    #[test]
+    fn estimates_per_channel() {
+        assert_eq!(estimate_ma(&[]), 0);
+        assert_eq!(estimate_ma(&[RGB8::default(); 10]), 10);
+        // Full white is three channels plus the controller
+        assert_eq!(estimate_ma(&[RGB8::new(255, 255, 255)]), 37);
+        assert_eq!(estimate_ma(&[RGB8::new(255, 0, 0); 100]), 1300);
+        assert_eq!(estimate_ma(&[RGB8::new(0, 128, 0); 2]), 14);
+    }
+
+    #[test]
+    fn scales_down_to_the_budget() {
+        let mut pixels = vec![RGB8::new(255, 255, 255); 100];
+        let current = limit(&mut pixels, 1000);
+        assert!(current <= 1000, "{} mA", current);
+        assert!(current >= 980, "{} mA", current);
+        // Uniformly, keeping the colours' proportions
+        let mut pixels = vec![RGB8::new(200, 100, 0); 100];
+        limit(&mut pixels, 500);
+        assert_eq!(pixels[0], pixels[99]);
+        assert_eq!(pixels[0].r, pixels[0].g * 2);
+    }
+
+    #[test]
+    fn leaves_frames_within_budget() {
+        let original = vec![RGB8::new(255, 0, 0); 10];
+        let mut pixels = original.clone();
+        assert_eq!(limit(&mut pixels, 500), 130);
+        assert_eq!(pixels, original);
+        // No limit
+        let mut pixels = vec![RGB8::new(255, 255, 255); 300];
+        assert_eq!(limit(&mut pixels, 0), 11100);
+        // Only the idle current left
+        let mut pixels = vec![RGB8::new(255, 255, 255); 300];
+        assert_eq!(limit(&mut pixels, 200), 300);
+        assert_eq!(pixels[0], RGB8::default());
+    }
+}
@@ -0,0 +1,699 @@
+use crate::animation::Effect;
+use crate::config::Config;
+use crate::history::History;
+use crate::layout::Layout;
+use crate::matrix::Framebuffer;
+use crate::rgb::{self, Palette};
+use crate::rollup;
+use smart_leds::RGB8;
+use std::time::Duration;
+
+/// Colours the test pattern repeats, to check the strip's wiring and
+/// colour order
+const TEST_PATTERN: [RGB8; 4] = [
+    RGB8::new(255, 0, 0),
+    RGB8::new(0, 255, 0),
+    RGB8::new(0, 0, 255),
+    RGB8::new(255, 255, 255),
+];
+
+/// What the strip shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// One LED per sample, newest first
+    History,
+    /// The latest RTT as a bar, longer for slower pings
+    BarGraph,
+    /// The whole strip in the latest sample's colour
+    Solid,
+    /// Red, green, blue and white, repeated
+    TestPattern,
+    /// Dark while the latest ping is healthy, otherwise the history
+    OffWhenHealthy,
+    /// Each LED a fixed slot of wall-clock time, like a clock face (see
+    /// `dial::Dial`); the history until the time is known
+    Dial,
+    /// The history on a logarithmic time axis: the first LED one sample,
+    /// each after it longer, out to `log_span` (see `rollup::Rollup`)
+    LogTime,
+    /// On a matrix, recent RTTs as a column chart, newest on the right
+    Sparkline,
+    /// On a matrix, the last week as a row per day and a column per hour
+    Heatmap,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 9] = [
+        Mode::History,
+        Mode::BarGraph,
+        Mode::Solid,
+        Mode::TestPattern,
+        Mode::OffWhenHealthy,
+        Mode::Dial,
+        Mode::LogTime,
+        Mode::Sparkline,
+        Mode::Heatmap,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::History => "history",
+            Mode::BarGraph => "bar_graph",
+            Mode::Solid => "solid",
+            Mode::TestPattern => "test_pattern",
+            Mode::OffWhenHealthy => "off_when_healthy",
+            Mode::Dial => "dial",
+            Mode::LogTime => "log_time",
+            Mode::Sparkline => "sparkline",
+            Mode::Heatmap => "heatmap",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|m| m.name() == name)
+    }
+}
+
+/// The Home Assistant light's effect list: the history with each of its
+/// animations, then the other modes
+pub fn light_effects() -> Vec<&'static str> {
+    Effect::ALL
+        .iter()
+        .map(|e| e.name())
+        .chain(
+            Mode::ALL
+                .iter()
+                .filter(|m| **m != Mode::History)
+                .map(|m| m.name()),
+        )
+        .collect()
+}
+
+/// The current entry of `light_effects`
+pub fn light_effect(config: &Config) -> &'static str {
+    match config.led_mode {
+        Mode::History => config.led_effect.name(),
+        mode => mode.name(),
+    }
+}
+
+/// Select an entry of `light_effects`, which also drops any override
+/// colour; returns false (changing nothing) if there is no such effect
+pub fn set_light_effect(config: &mut Config, name: &str) -> bool {
+    if let Some(effect) = Effect::from_name(name) {
+        config.led_mode = Mode::History;
+        config.led_effect = effect;
+    } else if let Some(mode) = Mode::from_name(name) {
+        config.led_mode = mode;
+    } else {
+        return false;
+    }
+    config.led_colour = None;
+    true
+}
+
+/// The animation to run over `render`'s pixels; only the history is
+/// animated, and nothing lights up while the strip is off
+pub fn effect(config: &Config) -> Effect {
+    if config.led_enabled && config.led_mode == Mode::History && config.led_colour.is_none() {
+        config.led_effect
+    } else {
+        Effect::Static
+    }
+}
+
+/// The colours for the whole strip in the configured mode at `now` (time
+/// since boot, as for samples), or the override colour if one is set. Any
+/// status pixels are left dark, for `status::overlay`. There is one colour
+/// per LED the layout shows.
+pub fn render(history: &History, config: &Config, brightness: u8, now: Duration) -> Vec<RGB8> {
+    let shown = config.shown_led_count();
+    if !config.status_pixels.is_empty() || shown != config.led_count {
+        let mut area = config.clone();
+        area.led_count = config.history_led_count();
+        area.status_pixels.clear();
+        area.layout = Layout::default();
+        let mut pixels = render(history, &area, brightness, now);
+        pixels.resize(shown as usize, RGB8::default());
+        return pixels;
+    }
+    let led_count = config.led_count as usize;
+    if !config.led_enabled {
+        return vec![RGB8::default(); led_count];
+    }
+    if let Some(colour) = config.led_colour {
+        return vec![colour; led_count];
+    }
+    let latest = history.latest().map(|s| s.rtt);
+    let colour = |rtt| Palette::new(config, history.baseline()).colour(rtt, brightness);
+    match config.led_mode {
+        Mode::History => rgb::strip_pixels(history, config, brightness),
+        Mode::BarGraph => bar_graph(history, config, now, colour),
+        Mode::Solid => match latest {
+            Some(rtt) => vec![colour(rtt); led_count],
+            None => vec![RGB8::default(); led_count],
+        },
+        Mode::TestPattern => TEST_PATTERN
+            .iter()
+            .copied()
+            .cycle()
+            .take(led_count)
+            .collect(),
+        Mode::OffWhenHealthy => match latest {
+            Some(Some(rtt)) if rtt <= config.max_healthy_duration => {
+                vec![RGB8::default(); led_count]
+            }
+            _ => rgb::strip_pixels(history, config, brightness),
+        },
+        Mode::Dial => match history.wall_clock(now) {
+            Some(time) => {
+                let palette = Palette::new(config, history.baseline());
+                history.dial().pixels(time, &palette, brightness)
+            }
+            None => rgb::strip_pixels(history, config, brightness),
+        },
+        Mode::LogTime => log_time(history, config, brightness, now),
+        Mode::Sparkline | Mode::Heatmap => {
+            let Some((width, height)) = matrix_size(config) else {
+                return rgb::strip_pixels(history, config, brightness);
+            };
+            let image = if config.led_mode == Mode::Sparkline {
+                sparkline(history, config, brightness, now, width, height)
+            } else {
+                heatmap(history, config, brightness, now, width, height)
+            };
+            let mut pixels = image.to_strip(config.matrix_wiring);
+            pixels.resize(led_count, RGB8::default());
+            pixels
+        }
+    }
+}
+
+/// The width and height of the matrix, if the strip is one
+fn matrix_size(config: &Config) -> Option<(usize, usize)> {
+    let width = config.matrix_width as usize;
+    let height = (config.led_count as usize).checked_div(width)?;
+    (height > 0).then_some((width, height))
+}
+
+/// Columns of `led_strip_duration / width` each, newest on the right, as
+/// bars up from the bottom (see `bar_length`), combined by the log time
+/// axis's aggregation
+fn sparkline(
+    history: &History,
+    config: &Config,
+    brightness: u8,
+    now: Duration,
+    width: usize,
+    height: usize,
+) -> Framebuffer {
+    let palette = Palette::new(config, history.baseline());
+    let column = config.led_strip_duration / width as u32;
+    let mut image = Framebuffer::new(width, height);
+    for x in 0..width {
+        let age = (width - x) as u32;
+        let (from, to) = (
+            now.saturating_sub(column * age),
+            now.saturating_sub(column * (age - 1)),
+        );
+        let Some(bucket) = history.rollup().aggregate(from, to, now) else {
+            continue;
+        };
+        let colour = config.log_aggregation.colour(&bucket, &palette, brightness);
+        let length = bar_length(config.log_aggregation.rtt(&bucket), config, height);
+        for y in height - length..height {
+            image.set(x, y, colour);
+        }
+    }
+    image
+}
+
+/// A row per day, today at the top, and columns of `24h / width` (an hour
+/// each on a 24 wide matrix), combined by the log time axis's aggregation.
+/// Days start at local midnight once the time is known; until then the top
+/// row is the last 24 hours. Only a week is kept, so later rows stay dark.
+fn heatmap(
+    history: &History,
+    config: &Config,
+    brightness: u8,
+    now: Duration,
+    width: usize,
+    height: usize,
+) -> Framebuffer {
+    const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+    let palette = Palette::new(config, history.baseline());
+    let cell = DAY_MS / width as i64;
+    let now_ms = now.as_millis() as i64;
+    // How long ago today started
+    let today = match history.wall_clock(now) {
+        Some(time) => {
+            (time.as_millis() as i64 + config.utc_offset as i64 * 60_000).rem_euclid(DAY_MS)
+        }
+        None => DAY_MS,
+    };
+    // A time this long ago, clamped to between boot and now
+    let at = |ago: i64| Duration::from_millis((now_ms - ago).clamp(0, now_ms) as u64);
+    let mut image = Framebuffer::new(width, height);
+    for y in 0..height.min(7) {
+        for x in 0..width {
+            let start = today + y as i64 * DAY_MS - x as i64 * cell;
+            if let Some(bucket) = history.rollup().aggregate(at(start), at(start - cell), now) {
+                image.set(
+                    x,
+                    y,
+                    config.log_aggregation.colour(&bucket, &palette, brightness),
+                );
+            }
+        }
+    }
+    image
+}
+
+/// Each LED's stretch of the log time axis, combined by the configured
+/// aggregation; dark where nothing was recorded
+fn log_time(history: &History, config: &Config, brightness: u8, now: Duration) -> Vec<RGB8> {
+    let palette = Palette::new(config, history.baseline());
+    let first = config.led_strip_duration / config.led_count.max(1);
+    let edges = rollup::log_edges(config.led_count as usize, first, config.log_span);
+    edges
+        .windows(2)
+        .map(|edge| {
+            let (from, to) = (now.saturating_sub(edge[1]), now.saturating_sub(edge[0]));
+            history
+                .rollup()
+                .aggregate(from, to, now)
+                .map_or(RGB8::default(), |bucket| {
+                    config.log_aggregation.colour(&bucket, &palette, brightness)
+                })
+        })
+        .collect()
+}
+
+/// How much of a bar `full` long a sample lights: one LED at the min
+/// healthy duration, the whole bar at the max, and the whole bar for a
+/// lost ping
+fn bar_length(rtt: Option<Duration>, config: &Config, full: usize) -> usize {
+    match rtt {
+        Some(rtt) => {
+            let (min, max) = (config.min_healthy_duration, config.max_healthy_duration);
+            let range = max.saturating_sub(min).as_secs_f32().max(f32::EPSILON);
+            let fraction = rtt.saturating_sub(min).as_secs_f32() / range;
+            (1 + (fraction * full.saturating_sub(1) as f32).round() as usize).min(full)
+        }
+        None => full,
+    }
+}
+
+/// The latest ping as a bar (updated every loop, not just once a sample),
+/// plus the longest bar within the last `bar_peak_hold` as a single LED,
+/// fading as it gets older
+fn bar_graph(
+    history: &History,
+    config: &Config,
+    now: Duration,
+    colour: impl Fn(Option<Duration>) -> RGB8,
+) -> Vec<RGB8> {
+    let led_count = config.led_count as usize;
+    let mut pixels = vec![RGB8::default(); led_count];
+    let Some(latest) = history.live() else {
+        return pixels;
+    };
+    let lit = bar_length(latest.rtt, config, led_count);
+    pixels[..lit].fill(colour(latest.rtt));
+
+    let hold = config.bar_peak_hold;
+    let peak = history
+        .iter()
+        .take_while(|s| now.saturating_sub(s.at) < hold)
+        .chain(history.peak().filter(|s| now.saturating_sub(s.at) < hold))
+        .map(|s| (bar_length(s.rtt, config, led_count), s))
+        .max_by_key(|(length, s)| (*length, s.at));
+    if let Some((length, sample)) = peak.filter(|(length, _)| *length > lit) {
+        let age = now.saturating_sub(sample.at).as_secs_f32();
+        let fade = 1.0 - age / hold.as_secs_f32();
+        let peak = colour(sample.rtt);
+        let scale = |c: u8| (c as f32 * fade).round() as u8;
+        pixels[length - 1] = RGB8::new(scale(peak.r), scale(peak.g), scale(peak.b));
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod test_render {
+    use super::*;
+    use crate::dial::DialSpan;
+    use crate::matrix::Wiring;
+    use crate::rollup::Aggregation;
+    use crate::status::Indicator;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    fn config(mode: Mode) -> Config {
+        Config {
+            led_mode: mode,
+            led_count: 5,
+            min_healthy_duration: Duration::from_millis(10),
+            max_healthy_duration: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    fn history(rtts: &[Option<Duration>]) -> History {
+        let mut history = History::new(5);
+        for rtt in rtts {
+            history.push(Duration::ZERO, *rtt);
+        }
+        history
+    }
+
+    #[test]
+    fn bar_grows_with_rtt() {
+        let config = config(Mode::BarGraph);
+        let lit = |rtt| {
+            render(&history(&[rtt]), &config, 255, Duration::ZERO)
+                .iter()
+                .filter(|p| **p != RGB8::default())
+                .count()
+        };
+        assert_eq!(lit(ms(5)), 1);
+        assert_eq!(lit(ms(30)), 3);
+        assert_eq!(lit(ms(50)), 5);
+        assert_eq!(lit(ms(500)), 5);
+        assert_eq!(lit(None), 5);
+        assert_eq!(
+            render(&History::new(5), &config, 255, Duration::ZERO),
+            vec![RGB8::default(); 5]
+        );
+        let pixels = render(&history(&[ms(30)]), &config, 255, Duration::ZERO);
+        let expected = rgb::ms2rgb(
+            ms(30),
+            config.min_healthy_duration,
+            config.max_healthy_duration,
+            255,
+        );
+        assert_eq!(pixels[0], expected);
+    }
+
+    #[test]
+    fn holds_peak() {
+        let config = Config {
+            bar_peak_hold: Duration::from_secs(100),
+            ..config(Mode::BarGraph)
+        };
+        let mut history = History::new(5);
+        history.push(Duration::from_secs(0), ms(50));
+        history.push(Duration::from_secs(50), ms(20));
+        let colour = |rtt| {
+            rgb::ms2rgb(
+                rtt,
+                config.min_healthy_duration,
+                config.max_healthy_duration,
+                255,
+            )
+        };
+        // Bar of 2, and the peak from 50s ago, half faded
+        let pixels = render(&history, &config, 255, Duration::from_secs(50));
+        let (peak, half) = (colour(ms(50)), |c: u8| (c as f32 * 0.5).round() as u8);
+        assert_eq!(
+            pixels,
+            vec![
+                colour(ms(20)),
+                colour(ms(20)),
+                RGB8::default(),
+                RGB8::default(),
+                RGB8::new(half(peak.r), half(peak.g), half(peak.b)),
+            ]
+        );
+        // Gone once it is older than the hold time
+        let pixels = render(&history, &config, 255, Duration::from_secs(101));
+        assert_eq!(pixels[2..], [RGB8::default(); 3]);
+        // Hidden by a longer bar
+        history.push(Duration::from_secs(60), None);
+        let pixels = render(&history, &config, 255, Duration::from_secs(60));
+        assert_eq!(pixels, vec![colour(None); 5]);
+    }
+
+    #[test]
+    fn bar_follows_pings_between_samples() {
+        let config = Config {
+            bar_peak_hold: Duration::from_secs(100),
+            ..config(Mode::BarGraph)
+        };
+        let hold = config.bar_peak_hold;
+        let colour = |rtt| {
+            rgb::ms2rgb(
+                rtt,
+                config.min_healthy_duration,
+                config.max_healthy_duration,
+                255,
+            )
+        };
+        let mut history = History::new(5);
+        history.push(Duration::ZERO, ms(5));
+        history.record_live(Duration::from_secs(1), ms(50), hold);
+        let pixels = render(&history, &config, 255, Duration::from_secs(1));
+        assert_eq!(pixels, vec![colour(ms(50)); 5]);
+        // Back to fast, with the slow ping held as the peak
+        history.record_live(Duration::from_secs(2), ms(5), hold);
+        let pixels = render(&history, &config, 255, Duration::from_secs(2));
+        assert_eq!(pixels[0], colour(ms(5)));
+        assert_eq!(pixels[1..4], [RGB8::default(); 3]);
+        assert_ne!(pixels[4], RGB8::default());
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn status_pixels_shrink_the_bar() {
+        let config = Config {
+            status_pixels: vec![Indicator::Wifi, Indicator::Time],
+            ..config(Mode::BarGraph)
+        };
+        let pixels = render(&history(&[None]), &config, 255, Duration::ZERO);
+        let red = rgb::ms2rgb(
+            None,
+            config.min_healthy_duration,
+            config.max_healthy_duration,
+            255,
+        );
+        assert_eq!(pixels, [red, red, red, RGB8::default(), RGB8::default()]);
+    }
+
+    #[test]
+    fn other_modes() {
+        let slow = history(&[ms(5), ms(80)]);
+        let fast = history(&[ms(80), ms(5)]);
+        let solid = render(&slow, &config(Mode::Solid), 255, Duration::ZERO);
+        assert_eq!(solid, vec![solid[0]; 5]);
+        assert_ne!(solid[0], RGB8::default());
+
+        let off = config(Mode::OffWhenHealthy);
+        assert_eq!(
+            render(&fast, &off, 255, Duration::ZERO),
+            vec![RGB8::default(); 5]
+        );
+        assert_eq!(
+            render(&slow, &off, 255, Duration::ZERO),
+            rgb::strip_pixels(&slow, &off, 255)
+        );
+
+        let pattern = render(&fast, &config(Mode::TestPattern), 255, Duration::ZERO);
+        assert_eq!(pattern[0], TEST_PATTERN[0]);
+        assert_eq!(pattern[4], TEST_PATTERN[0]);
+        assert_eq!(pattern[3], TEST_PATTERN[3]);
+    }
+
+    #[test]
+    fn dial_waits_for_the_clock() {
+        let config = Config {
+            dial_span: DialSpan::Day,
+            ..config(Mode::Dial)
+        };
+        let mut history = History::new(5);
+        history.set_dial(config.dial_span, 5, 0);
+        history.push(Duration::from_secs(10), ms(30));
+        let now = Duration::from_secs(20);
+        assert_eq!(
+            render(&history, &config, 255, now),
+            rgb::strip_pixels(&history, &config, 255)
+        );
+        // Booted at 2024-01-01 00:00 UTC, so the hand is on LED 0
+        history.set_boot_time(Duration::from_secs(1_704_067_200));
+        history.push(Duration::from_secs(20), ms(30));
+        let palette = Palette::new(&config, history.baseline());
+        let wall = history.wall_clock(now).expect("clock is set");
+        assert_eq!(
+            render(&history, &config, 255, now),
+            history.dial().pixels(wall, &palette, 255)
+        );
+        assert_eq!(history.dial().slots(wall)[0].map(|s| s.sent), Some(1));
+    }
+
+    #[test]
+    fn log_time_shows_detail_and_history() {
+        let mut config = Config {
+            led_strip_duration: Duration::from_secs(5 * 60),
+            log_span: Duration::from_secs(24 * 60 * 60),
+            ..config(Mode::LogTime)
+        };
+        let mut history = History::new(5);
+        // A ping a minute for a day: lost an hour in, slow for the last
+        // two minutes
+        let day = 24 * 60;
+        for minute in 0..day {
+            let rtt = match minute {
+                60 => None,
+                m if m >= day - 2 => ms(40),
+                _ => ms(5),
+            };
+            history.push(Duration::from_secs(minute * 60 + 30), rtt);
+        }
+        let now = Duration::from_secs(day * 60);
+        let palette = Palette::new(&config, history.baseline());
+        let (fast, slow, lost) = (
+            palette.colour(ms(5), 255),
+            palette.colour(ms(40), 255),
+            palette.colour(None, 255),
+        );
+        let pixels = render(&history, &config, 255, now);
+        assert_eq!(pixels[..2], [slow, slow]);
+        assert_eq!(pixels[2..4], [fast, fast]);
+        assert_eq!(pixels[4], lost);
+
+        // One loss in the hundreds of pings behind the last LED barely
+        // shows
+        config.log_aggregation = Aggregation::LossWeighted;
+        let pixels = render(&history, &config, 255, now);
+        assert_ne!(pixels[4], lost);
+        assert_eq!(pixels[0], slow);
+    }
+
+    /// A key for `Framebuffer::to_text`: dark, lost, and fast, medium and
+    /// slow as used by the matrix tests
+    fn key(config: &Config) -> impl Fn(RGB8) -> char {
+        let palette = Palette::new(config, None);
+        let colours = [
+            (RGB8::default(), '.'),
+            (palette.colour(None, 255), 'x'),
+            (palette.colour(ms(5), 255), 'g'),
+            (palette.colour(ms(30), 255), 'y'),
+            (palette.colour(ms(50), 255), 'r'),
+        ];
+        move |pixel| {
+            colours
+                .iter()
+                .find(|(colour, _)| *colour == pixel)
+                .map_or('?', |(_, c)| *c)
+        }
+    }
+
+    #[test]
+    fn sparkline_matches_image() {
+        let config = Config {
+            led_count: 32,
+            matrix_width: 8,
+            led_strip_duration: Duration::from_secs(8 * 60),
+            ..config(Mode::Sparkline)
+        };
+        let mut history = History::new(32);
+        // A ping a minute, bar the 7th
+        for (minute, rtt) in [ms(5), ms(5), ms(30), None, ms(50), ms(5)]
+            .into_iter()
+            .enumerate()
+            .chain([(7, ms(30))])
+        {
+            history.push(Duration::from_secs(minute as u64 * 60 + 30), rtt);
+        }
+        let now = Duration::from_secs(8 * 60);
+        let image = sparkline(&history, &config, 255, now, 8, 4);
+        assert_eq!(
+            image.to_text(key(&config)),
+            include_str!("../testdata/sparkline.txt")
+        );
+        let pixels = render(&history, &config, 255, now);
+        assert_eq!(pixels, image.to_strip(Wiring::Serpentine));
+        // Status pixels go over the last pixels rather than taking a row
+        let with_status = Config {
+            status_pixels: vec![Indicator::Wifi],
+            ..config.clone()
+        };
+        assert_eq!(with_status.history_led_count(), 32);
+        assert_eq!(render(&history, &with_status, 255, now), pixels);
+        // Not a matrix: the history instead
+        let strip = Config {
+            matrix_width: 0,
+            ..config.clone()
+        };
+        assert_eq!(
+            render(&history, &strip, 255, now),
+            rgb::strip_pixels(&history, &strip, 255)
+        );
+    }
+
+    #[test]
+    fn heatmap_matches_image() {
+        let config = Config {
+            led_count: 24 * 8,
+            matrix_width: 24,
+            ..config(Mode::Heatmap)
+        };
+        let mut history = History::new(5);
+        // A ping every half hour for two and a half days: slow at 9am on
+        // the 2nd day, and one lost at 6pm on the 1st
+        for i in 0..120 {
+            let rtt = match i {
+                66 | 67 => ms(50),
+                36 => None,
+                _ => ms(5),
+            };
+            history.push(Duration::from_secs(i * 30 * 60 + 60), rtt);
+        }
+        let now = Duration::from_secs(60 * 60 * 60);
+        // Without the time, the top row is the last 24 hours
+        let image = heatmap(&history, &config, 255, now, 24, 8);
+        assert_eq!(
+            image.get(21, 1),
+            Some(Palette::new(&config, None).colour(ms(50), 255))
+        );
+
+        // Booted at midnight, so rows are calendar days; the first day's
+        // morning has gone from the hourly tier, so is shown 6 hours at a
+        // time
+        history.set_boot_time(Duration::from_secs(1_704_067_200));
+        let image = heatmap(&history, &config, 255, now, 24, 8);
+        assert_eq!(
+            image.to_text(key(&config)),
+            include_str!("../testdata/heatmap.txt")
+        );
+    }
+
+    #[test]
+    fn effects_select_modes_and_drop_colour() {
+        let mut config = Config {
+            led_colour: Some(RGB8::new(1, 2, 3)),
+            ..config(Mode::History)
+        };
+        assert_eq!(
+            render(&history(&[ms(5)]), &config, 255, Duration::ZERO),
+            vec![RGB8::new(1, 2, 3); 5]
+        );
+        assert_eq!(effect(&config), Effect::Static);
+
+        assert!(set_light_effect(&mut config, "bar_graph"));
+        assert_eq!((config.led_mode, config.led_colour), (Mode::BarGraph, None));
+        assert_eq!(light_effect(&config), "bar_graph");
+        assert!(set_light_effect(&mut config, "breathe"));
+        assert_eq!(
+            (config.led_mode, config.led_effect),
+            (Mode::History, Effect::Breathe)
+        );
+        assert_eq!(light_effect(&config), "breathe");
+        assert_eq!(effect(&config), Effect::Breathe);
+        assert!(!set_light_effect(&mut config, "disco"));
+
+        let effects = light_effects();
+        assert!(effects.contains(&"static") && effects.contains(&"test_pattern"));
+        assert!(!effects.contains(&"history"));
+    }
+}
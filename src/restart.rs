@@ -0,0 +1,144 @@
+use esp_idf_svc::{hal::delay::FreeRtos, nvs::EspDefaultNvsPartition};
+use esp_ping_leds::crash::{self, CrashReport};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::storage::Storage;
+use crate::system;
+use crate::updater;
+
+const RESTART_SECONDS: u32 = 3;
+/// A freshly installed firmware is rolled back unless one of its first few
+/// probes gets a reply
+const OTA_VERIFY_PROBES: u32 = 5;
+
+/// Restart after a short delay, so the log has a chance to be read
+pub fn restart() -> ! {
+    log::info!("Restarting in {}s...", RESTART_SECONDS);
+    FreeRtos::delay_ms(RESTART_SECONDS * 1000);
+    unsafe { esp_idf_svc::sys::esp_restart() };
+}
+
+/// Save a crash report for `error` so it can be published after the
+/// restart, then restart
+pub fn restart_after_error(storage: &Mutex<Storage>, error: &anyhow::Error) -> ! {
+    log::error!("Fatal error: {:#}", error);
+    match storage.lock() {
+        Ok(mut storage) => {
+            let chain: Vec<String> = error.chain().map(|e| e.to_string()).collect();
+            let boot = storage.boot_count().unwrap_or(0);
+            let report = CrashReport::error(&chain, boot, system::uptime());
+            if let Err(e) = storage.set_crash_report(&report) {
+                log::warn!("Failed to save crash report: {}", e);
+            }
+        }
+        Err(_) => log::warn!("Storage lock poisoned, not saving crash report"),
+    }
+    restart()
+}
+
+/// Save a crash report for any panic, before the default handler aborts.
+/// This uses its own storage handle, so it works even if the panic happened
+/// while the shared one was locked.
+pub fn install_panic_hook(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let storage = Mutex::new(Storage::new(partition)?);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()));
+        if let Ok(mut storage) = storage.try_lock() {
+            let boot = storage.boot_count().unwrap_or(0);
+            let report = CrashReport::panic(&message, location, boot, system::uptime());
+            if let Err(e) = storage.set_crash_report(&report) {
+                log::warn!("Failed to save crash report: {}", e);
+            }
+        }
+        default_hook(info);
+    }));
+    Ok(())
+}
+
+/// Keeps track of whether this boot is healthy: boots which restart before
+/// becoming stable are counted to detect crash loops, and freshly installed
+/// firmware is confirmed once a probe succeeds
+pub struct BootHealth {
+    storage: Arc<Mutex<Storage>>,
+    safe_mode: bool,
+    stable: bool,
+    ota_unverified: bool,
+    unverified_probes: u32,
+}
+
+impl BootHealth {
+    /// Count this boot as unstable until it has been up for a while, and
+    /// decide whether we are in a crash loop
+    pub fn start(storage: Arc<Mutex<Storage>>, ota_unverified: bool) -> Self {
+        let safe_mode = match storage.lock() {
+            Ok(mut storage) => {
+                let previous = crash::previous_unstable_boots(
+                    storage.unstable_boots().unwrap_or(0),
+                    system::reset_reason(),
+                );
+                if let Err(e) = storage.set_unstable_boots(previous + 1) {
+                    log::warn!("Failed to record unstable boot: {}", e);
+                }
+                log::info!("{} unstable boots before this one", previous);
+                crash::is_crash_loop(previous)
+            }
+            Err(_) => false,
+        };
+        Self {
+            storage,
+            safe_mode,
+            stable: false,
+            ota_unverified,
+            unverified_probes: 0,
+        }
+    }
+
+    pub fn safe_mode(&self) -> bool {
+        self.safe_mode
+    }
+
+    /// Call regularly; once we have been up long enough, this boot no
+    /// longer counts towards a crash loop
+    pub fn tick(&mut self) {
+        if !self.stable && system::uptime() >= crash::STABLE_UPTIME {
+            self.stable = true;
+            log::info!("Boot is stable");
+            clear_unstable_boots(&self.storage);
+        }
+    }
+
+    /// Record a probe result, confirming or rolling back new firmware
+    pub fn probe(&mut self, rtt: Option<Duration>) -> anyhow::Result<()> {
+        if self.ota_unverified {
+            self.unverified_probes += 1;
+            if rtt.is_some() {
+                updater::mark_valid()?;
+                self.ota_unverified = false;
+            } else if self.unverified_probes >= OTA_VERIFY_PROBES {
+                return Err(updater::rollback());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forget about previous unstable boots, eg before a deliberate restart
+pub fn clear_unstable_boots(storage: &Mutex<Storage>) {
+    if let Ok(mut storage) = storage.lock() {
+        if let Err(e) = storage.set_unstable_boots(0) {
+            log::warn!("Failed to clear unstable boot count: {}", e);
+        }
+    }
+}
@@ -1,8 +1,95 @@
+use crate::colour::{self, ColourStop, Scale};
+use crate::config::Config;
+use crate::history::History;
 use smart_leds::hsv::{hsv2rgb, Hsv};
-use smart_leds::RGB;
+use smart_leds::{RGB, RGB8};
 use std::time::Duration;
 
-/// Converts a given value in milliseconds to an RGB color value.
+/// A fully saturated, full brightness hue (0-255)
+fn hue(hue: u8) -> RGB8 {
+    hsv2rgb(Hsv {
+        hue,
+        sat: 255,
+        val: 255,
+    })
+}
+
+/// The original colour scale: solid green up to `min`, then green through
+/// yellow to red at `max`, and magenta for anything slower
+pub fn default_stops(min: Duration, max: Duration) -> Vec<ColourStop> {
+    vec![
+        ColourStop::new(min, hue(80)),
+        ColourStop::new((min + max) / 2, hue(40)),
+        ColourStop::new(max, hue(0)),
+        ColourStop::new(max, hue(210)),
+    ]
+}
+
+/// Scale a full brightness colour down to `val` (0-255)
+fn dim(colour: RGB8, val: u8) -> RGB8 {
+    let scale = |c: u8| (c as u16 * val as u16 / 255) as u8;
+    RGB8::new(scale(colour.r), scale(colour.g), scale(colour.b))
+}
+
+/// Everything needed to colour samples: the stops, the scale and (for
+/// `Scale::Baseline`) how to adjust for the baseline
+#[derive(Debug, Clone)]
+pub struct Palette {
+    stops: Vec<ColourStop>,
+    scale: Scale,
+    /// RTTs are multiplied by this before looking up their colour
+    factor: f64,
+}
+
+impl Palette {
+    /// The palette for `config`, given the current baseline RTT (see
+    /// `History::baseline`); with no baseline yet, the baseline scale
+    /// works like the log one
+    pub fn new(config: &Config, baseline: Option<Duration>) -> Self {
+        let stops = config.colour_stops.clone().unwrap_or_else(|| {
+            default_stops(config.min_healthy_duration, config.max_healthy_duration)
+        });
+        let factor = match (config.colour_scale, baseline) {
+            (Scale::Baseline, Some(baseline)) if !baseline.is_zero() => {
+                config.min_healthy_duration.as_secs_f64() / baseline.as_secs_f64()
+            }
+            _ => 1.0,
+        };
+        Self {
+            stops,
+            scale: config.colour_scale,
+            factor,
+        }
+    }
+
+    /// The colour for a sample, or dark blue for "timeout"
+    pub fn colour(&self, sample: Option<Duration>, brightness: u8) -> RGB8 {
+        match sample {
+            None => hsv2rgb(Hsv {
+                hue: 170,
+                sat: 255,
+                val: brightness / 2,
+            }),
+            Some(d) => {
+                let colour = match self.scale {
+                    Scale::Linear => colour::colour_at(&self.stops, d),
+                    Scale::Log => colour::colour_at_log(&self.stops, d),
+                    Scale::Baseline => colour::colour_at_log(&self.stops, d.mul_f64(self.factor)),
+                };
+                dim(colour, brightness / 2)
+            }
+        }
+    }
+}
+
+/// Halfway from `colour` to a white of `white` brightness
+fn lighten(colour: RGB8, white: u8) -> RGB8 {
+    let channel = |c: u8| ((c as u16 + white as u16) / 2) as u8;
+    RGB8::new(channel(colour.r), channel(colour.g), channel(colour.b))
+}
+
+/// Converts a given value in milliseconds to an RGB color value, using the
+/// default colour stops.
 ///
 /// # Arguments
 ///
@@ -14,46 +101,37 @@ use std::time::Duration;
 ///
 /// An RGB<u8> value representing the converted color.
 pub fn ms2rgb(sample: Option<Duration>, min: Duration, max: Duration, brightness: u8) -> RGB<u8> {
-    let min = min.as_millis() as u32;
-    let max = max.as_millis() as u32;
-    let hsv = match sample {
-        // offline: dark blue
-        None => Hsv {
-            hue: 170,
-            sat: 255,
-            val: brightness / 2,
-        },
-        Some(d) => {
-            let ms = d.as_millis() as u32;
-            // <min: solid green
-            if ms < min {
-                Hsv {
-                    hue: 80,
-                    sat: 255,
-                    val: brightness / 2,
-                }
-            }
-            // >max: magenta
-            else if ms > max {
-                Hsv {
-                    hue: 210,
-                    sat: 255,
-                    val: brightness / 2,
-                }
-            }
-            // min-max: spectrum green(80)-yellow(40)-red(0)
-            else {
-                let frac = 1.0 - ((ms - min) as f32 / (max - min) as f32);
-                Hsv {
-                    hue: (80.0 * frac) as u8,
-                    sat: 255,
-                    val: brightness / 2,
-                }
-            }
-        }
+    let config = Config {
+        min_healthy_duration: min,
+        max_healthy_duration: max,
+        ..Default::default()
     };
+    Palette::new(&config, None).colour(sample, brightness)
+}
 
-    hsv2rgb(hsv)
+/// The colours for the whole strip: one pixel per sample (newest first),
+/// padded with dim blue for "no data yet". Samples where an anomaly started
+/// are lightened halfway to white, keeping a tint of their latency colour.
+pub fn strip_pixels(history: &History, config: &Config, brightness: u8) -> Vec<RGB8> {
+    let led_count = config.led_count as usize;
+    if !config.led_enabled {
+        return vec![RGB::new(0, 0, 0); led_count];
+    }
+    let palette = Palette::new(config, history.baseline());
+    history
+        .iter()
+        .take(led_count)
+        .map(|sample| {
+            let colour = palette.colour(sample.rtt, brightness);
+            if sample.anomaly {
+                lighten(colour, brightness / 2)
+            } else {
+                colour
+            }
+        })
+        .chain(std::iter::repeat(RGB8::new(0, 0, brightness / 4)))
+        .take(led_count)
+        .collect()
 }
 
 #[cfg(test)]
@@ -122,3 +200,139 @@ mod test_ms2rgb {
         assert_eq!(result.b, TEST_BRIGHTNESS / 2);
     }
 }
+
+#[cfg(test)]
+mod test_strip_pixels {
+    use super::*;
+
+    #[test]
+    fn pads_with_no_data_colour() {
+        let config = Config {
+            led_count: 4,
+            ..Default::default()
+        };
+        let mut history = History::new(4);
+        history.push(Duration::ZERO, None);
+        let pixels = strip_pixels(&history, &config, 100);
+        assert_eq!(pixels.len(), 4);
+        assert_eq!(
+            pixels[0],
+            ms2rgb(
+                None,
+                config.min_healthy_duration,
+                config.max_healthy_duration,
+                100
+            )
+        );
+        assert_eq!(pixels[1..], [RGB8::new(0, 0, 25); 3]);
+    }
+
+    #[test]
+    fn uses_configured_stops() {
+        let config = Config {
+            led_count: 2,
+            colour_stops: Some(vec![ColourStop::new(
+                Duration::from_millis(10),
+                RGB8::new(0, 0, 255),
+            )]),
+            ..Default::default()
+        };
+        let mut history = History::new(2);
+        history.push(Duration::ZERO, Some(Duration::from_millis(500)));
+        history.push(Duration::ZERO, Some(Duration::from_millis(1)));
+        assert_eq!(
+            strip_pixels(&history, &config, 255),
+            vec![RGB8::new(0, 0, 127); 2]
+        );
+    }
+
+    #[test]
+    fn baseline_scale_is_relative() {
+        let config = Config {
+            colour_scale: Scale::Baseline,
+            ..Default::default()
+        };
+        let ms = |n| Some(Duration::from_millis(n));
+        let linear = |n| {
+            ms2rgb(
+                ms(n),
+                config.min_healthy_duration,
+                config.max_healthy_duration,
+                255,
+            )
+        };
+        // With the default 10ms min and 50ms max, a slow but steady link
+        // looks healthy...
+        let palette = Palette::new(&config, Some(Duration::from_millis(400)));
+        assert_eq!(palette.colour(ms(400), 255), linear(0));
+        // ...until it is five times slower than usual
+        assert_ne!(palette.colour(ms(800), 255), linear(0));
+        assert_eq!(palette.colour(ms(2000), 255), linear(50));
+        assert_eq!(palette.colour(ms(2100), 255), linear(51));
+        // Same as log until there is a baseline
+        let log = Palette::new(
+            &Config {
+                colour_scale: Scale::Log,
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(
+            Palette::new(&config, None).colour(ms(25), 255),
+            log.colour(ms(25), 255)
+        );
+    }
+
+    #[test]
+    fn default_stops_keep_original_colours() {
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(100));
+        let colour = |ms| ms2rgb(Some(Duration::from_millis(ms)), min, max, 255);
+        let hsv = |hue| {
+            hsv2rgb(Hsv {
+                hue,
+                sat: 255,
+                val: 127,
+            })
+        };
+        assert_eq!(colour(5), hsv(80));
+        assert_eq!(colour(100), hsv(0));
+        assert_eq!(colour(101), hsv(210));
+        // Yellow in the middle, rather than the old muddy band
+        let middle = colour(55);
+        assert!(middle.r > 100 && middle.g > 100 && middle.b < 20);
+    }
+
+    #[test]
+    fn marks_anomalies() {
+        let config = Config {
+            led_count: 3,
+            ..Default::default()
+        };
+        let mut history = History::new(3);
+        history.push(Duration::ZERO, Some(Duration::from_millis(5)));
+        history.push(Duration::ZERO, Some(Duration::from_millis(5)));
+        history.mark_anomaly();
+        history.push(Duration::ZERO, Some(Duration::from_millis(5)));
+        let pixels = strip_pixels(&history, &config, 200);
+        assert_eq!(pixels[0], pixels[2]);
+        // Pale green: still the colour of a fast ping, but lighter
+        assert_eq!(pixels[1], lighten(pixels[0], 100));
+        assert_ne!(pixels[1], RGB8::new(100, 100, 100));
+        assert!(pixels[1].g > pixels[1].r && pixels[1].r > pixels[0].r);
+    }
+
+    #[test]
+    fn disabled_is_dark() {
+        let config = Config {
+            led_enabled: false,
+            led_count: 3,
+            ..Default::default()
+        };
+        let mut history = History::new(3);
+        history.push(Duration::ZERO, None);
+        assert_eq!(
+            strip_pixels(&history, &config, 100),
+            vec![RGB8::new(0, 0, 0); 3]
+        );
+    }
+}
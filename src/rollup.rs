@@ -0,0 +1,335 @@
+use crate::rgb::Palette;
+use smart_leds::RGB8;
+use std::time::Duration;
+
+/// Bucket length and count of each tier, finest first: the last hour by
+/// minute, 6 hours by 10 minutes, 2 days by hour and a week by 6 hours
+pub const TIERS: [(Duration, usize); 4] = [
+    (Duration::from_secs(60), 60),
+    (Duration::from_secs(10 * 60), 36),
+    (Duration::from_secs(60 * 60), 48),
+    (Duration::from_secs(6 * 60 * 60), 28),
+];
+
+/// How the pings in a stretch of time are combined into one colour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The slowest ping, or the lost colour if any were lost
+    Worst,
+    /// The mean RTT, blended towards the lost colour by the share lost
+    LossWeighted,
+}
+
+impl Aggregation {
+    pub const ALL: [Aggregation; 2] = [Aggregation::Worst, Aggregation::LossWeighted];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aggregation::Worst => "worst",
+            Aggregation::LossWeighted => "loss_weighted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|a| a.name() == name)
+    }
+
+    /// The RTT to show for `bucket`, eg as a bar's height; None (lost) if
+    /// there isn't one
+    pub fn rtt(&self, bucket: &Bucket) -> Option<Duration> {
+        match self {
+            Aggregation::Worst if bucket.lost > 0 => None,
+            Aggregation::Worst => bucket.worst,
+            Aggregation::LossWeighted => bucket.mean(),
+        }
+    }
+
+    /// The colour for `bucket`
+    pub fn colour(&self, bucket: &Bucket, palette: &Palette, brightness: u8) -> RGB8 {
+        let colour = palette.colour(self.rtt(bucket), brightness);
+        match self {
+            Aggregation::Worst => colour,
+            Aggregation::LossWeighted => {
+                let lost = palette.colour(None, brightness);
+                let t = bucket.loss();
+                let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+                RGB8::new(
+                    lerp(colour.r, lost.r),
+                    lerp(colour.g, lost.g),
+                    lerp(colour.b, lost.b),
+                )
+            }
+        }
+    }
+}
+
+/// Pings within a stretch of time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bucket {
+    pub sent: u32,
+    pub lost: u32,
+    /// Slowest successful ping
+    pub worst: Option<Duration>,
+    /// Sum of the successful pings' RTTs
+    pub total: Duration,
+}
+
+impl Bucket {
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        self.sent += 1;
+        match rtt {
+            Some(rtt) => {
+                self.worst = self.worst.max(Some(rtt));
+                self.total += rtt;
+            }
+            None => self.lost += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Bucket) {
+        self.sent += other.sent;
+        self.lost += other.lost;
+        self.worst = self.worst.max(other.worst);
+        self.total += other.total;
+    }
+
+    /// Mean RTT of the successful pings
+    pub fn mean(&self) -> Option<Duration> {
+        let received = self.sent - self.lost;
+        (received > 0).then(|| self.total / received)
+    }
+
+    /// Share of pings lost (0-1)
+    pub fn loss(&self) -> f32 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.lost as f32 / self.sent as f32
+        }
+    }
+}
+
+/// Fixed length buckets in a ring, each remembering which slot (counted
+/// from boot) it holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tier {
+    length: Duration,
+    buckets: Vec<(u64, Bucket)>,
+}
+
+impl Tier {
+    fn slot(&self, at: Duration) -> u64 {
+        (at.as_millis() / self.length.as_millis()) as u64
+    }
+
+    /// The bucket for `slot`, if it is still held
+    fn get(&self, slot: u64) -> Option<&Bucket> {
+        let (held, bucket) = &self.buckets[(slot % self.buckets.len() as u64) as usize];
+        (*held == slot).then_some(bucket)
+    }
+
+    /// When the oldest bucket still held at `now` starts
+    fn oldest(&self, now: Duration) -> Duration {
+        let first = (self.slot(now) + 1).saturating_sub(self.buckets.len() as u64);
+        self.length * first as u32
+    }
+}
+
+/// Ping results over the last week in bounded memory, as buckets which get
+/// coarser the older they are (see `TIERS`). Times are since boot, as for
+/// samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rollup {
+    tiers: Vec<Tier>,
+}
+
+impl Default for Rollup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rollup {
+    pub fn new() -> Self {
+        let tiers = TIERS
+            .iter()
+            .map(|(length, count)| Tier {
+                length: *length,
+                buckets: vec![(u64::MAX, Bucket::default()); *count],
+            })
+            .collect();
+        Self { tiers }
+    }
+
+    /// Count a ping taken at `at`
+    pub fn record(&mut self, at: Duration, rtt: Option<Duration>) {
+        for tier in &mut self.tiers {
+            let slot = tier.slot(at);
+            let len = tier.buckets.len();
+            let (held, bucket) = &mut tier.buckets[(slot % len as u64) as usize];
+            if *held != slot {
+                *held = slot;
+                *bucket = Bucket::default();
+            }
+            bucket.record(rtt);
+        }
+    }
+
+    /// The pings taken from `from` up to `to`, using the finest tier which
+    /// still reaches back to `from`. Buckets count if their middle is in
+    /// the range; for a range shorter than a bucket, the bucket holding its
+    /// middle is used. None if nothing was recorded.
+    pub fn aggregate(&self, from: Duration, to: Duration, now: Duration) -> Option<Bucket> {
+        if to <= from {
+            return None;
+        }
+        let tier = self
+            .tiers
+            .iter()
+            .find(|t| t.oldest(now) <= from)
+            .or(self.tiers.last())?;
+        let first = tier.slot(from).max(tier.slot(tier.oldest(now)));
+        let last = tier.slot(to - Duration::from_nanos(1));
+        let mut total = Bucket::default();
+        for slot in first..=last {
+            let middle = tier.length * slot as u32 + tier.length / 2;
+            if (from..to).contains(&middle) {
+                if let Some(bucket) = tier.get(slot) {
+                    total.merge(bucket);
+                }
+            }
+        }
+        if total.sent == 0 && to - from < tier.length {
+            if let Some(bucket) = tier.get(tier.slot(from + (to - from) / 2)) {
+                total = *bucket;
+            }
+        }
+        (total.sent > 0).then_some(total)
+    }
+}
+
+/// Where each of `led_count` LEDs starts and ends, as time before now:
+/// the first covers `first`, and each after it a fixed multiple longer, so
+/// that together they cover `total`. Evenly spaced if `total` is no more
+/// than `first` per LED.
+pub fn log_edges(led_count: usize, first: Duration, total: Duration) -> Vec<Duration> {
+    let n = led_count as i32;
+    let target = total.as_secs_f32() / first.as_secs_f32().max(f32::EPSILON);
+    let ratio = if led_count == 0 || target <= led_count as f32 {
+        1.0
+    } else {
+        // Solve 1 + r + ... + r^(n-1) = target for r
+        let sum = |r: f32| (r.powi(n) - 1.0) / (r - 1.0);
+        let (mut low, mut high) = (1.0 + f32::EPSILON, target.max(2.0));
+        for _ in 0..40 {
+            let middle = (low + high) / 2.0;
+            if sum(middle) < target {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        (low + high) / 2.0
+    };
+    let mut edges = Vec::with_capacity(led_count + 1);
+    let mut edge = 0.0;
+    let mut width = if ratio == 1.0 {
+        total.as_secs_f32() / led_count.max(1) as f32
+    } else {
+        first.as_secs_f32()
+    };
+    for _ in 0..led_count {
+        edges.push(Duration::from_secs_f32(edge));
+        edge += width;
+        width *= ratio;
+    }
+    edges.push(total);
+    edges
+}
+
+#[cfg(test)]
+mod test_rollup {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn aggregates_from_the_finest_tier_available() {
+        let mut rollup = Rollup::new();
+        // A ping every 30s for 3 hours, slow at the start
+        for i in 0..360 {
+            let rtt = if i < 20 { ms(200) } else { ms(10) };
+            rollup.record(secs(i * 30), if i == 5 { None } else { rtt });
+        }
+        let now = secs(3 * 60 * 60);
+        // The last 2 minutes, by minute
+        let recent = rollup.aggregate(now - secs(120), now, now);
+        assert_eq!(
+            recent,
+            Some(Bucket {
+                sent: 4,
+                lost: 0,
+                worst: ms(10),
+                total: Duration::from_millis(40),
+            })
+        );
+        // The first 10 minutes have gone from the minute tier, so come from
+        // the 10 minute tier
+        let start = rollup.aggregate(secs(0), secs(600), now).expect("recorded");
+        assert_eq!((start.sent, start.lost, start.worst), (20, 1, ms(200)));
+        assert_eq!(start.mean(), Some(Duration::from_millis(200)));
+        assert_eq!(start.loss(), 0.05);
+        assert_eq!(
+            rollup.aggregate(secs(0), now, now).map(|b| b.sent),
+            Some(360)
+        );
+    }
+
+    #[test]
+    fn short_ranges_use_the_bucket_around_them() {
+        let mut rollup = Rollup::new();
+        rollup.record(secs(65), ms(20));
+        let now = secs(100);
+        assert_eq!(
+            rollup.aggregate(secs(90), secs(100), now).map(|b| b.worst),
+            Some(ms(20))
+        );
+        assert_eq!(rollup.aggregate(secs(10), secs(40), now), None);
+        // Later pings in the same ring position replace old ones
+        rollup.record(secs(65 + 3600), None);
+        let now = secs(3700);
+        assert_eq!(
+            rollup
+                .aggregate(secs(3660), secs(3670), now)
+                .map(|b| b.lost),
+            Some(1)
+        );
+        // The first minute has gone, but is still in its 10 minutes
+        assert_eq!(
+            rollup.aggregate(secs(60), secs(70), now).map(|b| b.worst),
+            Some(ms(20))
+        );
+    }
+
+    #[test]
+    fn edges_grow_geometrically() {
+        let edges = log_edges(4, secs(60), secs(15 * 60));
+        // 1, 2, 4 and 8 minutes
+        let minutes: Vec<f32> = edges.iter().map(|e| e.as_secs_f32() / 60.0).collect();
+        for (edge, expected) in minutes.iter().zip([0.0, 1.0, 3.0, 7.0, 15.0]) {
+            assert!((edge - expected).abs() < 0.01, "{:?}", minutes);
+        }
+        // Too short to grow: evenly spaced
+        assert_eq!(
+            log_edges(3, secs(60), secs(90)),
+            vec![secs(0), secs(30), secs(60), secs(90)]
+        );
+        assert_eq!(log_edges(0, secs(60), secs(90)), vec![secs(90)]);
+    }
+}
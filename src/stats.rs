@@ -0,0 +1,394 @@
+use std::time::Duration;
+
+/// Weight of each new sample in the learnt baseline; about the last 200
+/// samples count, so several hours at the default strip speed
+const BASELINE_ALPHA: f32 = 0.005;
+/// Weight of each new sample in the recent mean; about the last 10 samples
+const RECENT_ALPHA: f32 = 0.1;
+/// Samples needed before the baseline is trusted
+pub const WARMUP_SAMPLES: u32 = 100;
+/// How many standard deviations above the baseline the recent mean must be
+/// to start an anomaly...
+const START_DEVIATION: f32 = 4.0;
+/// ...and must fall back under to end it, so it doesn't flap
+const END_DEVIATION: f32 = 2.0;
+/// Samples the baseline stays frozen for (about 10 hours at the default
+/// strip speed) before a lasting change is accepted as the new normal
+pub const RELEARN_SAMPLES: u32 = 500;
+/// Smallest standard deviation assumed, as a fraction of the mean, so a
+/// very steady link doesn't alarm at every jitter
+const MIN_RELATIVE_STD_DEV: f32 = 0.05;
+/// Fraction of the current estimate each quantile step moves
+const QUANTILE_RATE: f32 = 0.01;
+
+/// Exponentially weighted moving mean and variance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ewma {
+    alpha: f32,
+    mean: f32,
+    variance: f32,
+    count: u32,
+}
+
+impl Ewma {
+    /// `alpha` is the weight of each new value (0-1)
+    pub fn new(alpha: f32) -> Self {
+        Self {
+            alpha,
+            mean: 0.0,
+            variance: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn update(&mut self, value: f32) {
+        if self.count == 0 {
+            self.mean = value;
+        } else {
+            let diff = value - self.mean;
+            let increment = self.alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - self.alpha) * (self.variance + diff * increment);
+        }
+        self.count = self.count.saturating_add(1);
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.mean
+    }
+
+    pub fn std_dev(&self) -> f32 {
+        self.variance.sqrt()
+    }
+
+    /// Values seen so far
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+/// A streaming estimate of a quantile in constant memory ("frugal"
+/// streaming): the estimate steps up when a value is above it and down
+/// when below, with the steps weighted so it settles where `quantile` of
+/// the values are below it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantile {
+    quantile: f32,
+    estimate: Option<f32>,
+}
+
+impl Quantile {
+    /// `quantile` is 0-1, eg 0.95 for the 95th percentile
+    pub fn new(quantile: f32) -> Self {
+        Self {
+            quantile,
+            estimate: None,
+        }
+    }
+
+    pub fn update(&mut self, value: f32) {
+        let estimate = match self.estimate {
+            None => value,
+            Some(estimate) => {
+                let step = (estimate.abs() * QUANTILE_RATE).max(f32::EPSILON);
+                if value > estimate {
+                    estimate + step * self.quantile
+                } else if value < estimate {
+                    estimate - step * (1.0 - self.quantile)
+                } else {
+                    estimate
+                }
+            }
+        };
+        self.estimate = Some(estimate);
+    }
+
+    pub fn estimate(&self) -> Option<f32> {
+        self.estimate
+    }
+}
+
+/// How far recent RTTs are from normal for a target
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deviation {
+    pub target: String,
+    /// Mean of the last few RTTs
+    pub recent: Duration,
+    /// Learnt mean RTT
+    pub baseline: Duration,
+    /// Learnt median and 95th percentile RTTs
+    pub median: Duration,
+    pub p95: Duration,
+    /// How many (learnt) standard deviations `recent` is above `baseline`
+    pub deviations: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnomalyEvent {
+    Started(Deviation),
+    Ended(Deviation),
+}
+
+impl AnomalyEvent {
+    /// Name used for the Home Assistant event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnomalyEvent::Started(_) => "anomaly_started",
+            AnomalyEvent::Ended(_) => "anomaly_ended",
+        }
+    }
+
+    pub fn deviation(&self) -> &Deviation {
+        match self {
+            AnomalyEvent::Started(deviation) | AnomalyEvent::Ended(deviation) => deviation,
+        }
+    }
+}
+
+/// Streaming RTT statistics for a single target, in ms
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetStatistics {
+    baseline: Ewma,
+    recent: Ewma,
+    median: Quantile,
+    p95: Quantile,
+    anomalous: bool,
+    /// Samples since the baseline last learnt
+    frozen: u32,
+}
+
+impl Default for TargetStatistics {
+    fn default() -> Self {
+        Self {
+            baseline: Ewma::new(BASELINE_ALPHA),
+            recent: Ewma::new(RECENT_ALPHA),
+            median: Quantile::new(0.5),
+            p95: Quantile::new(0.95),
+            anomalous: false,
+            frozen: 0,
+        }
+    }
+}
+
+impl TargetStatistics {
+    /// Learn from a successful probe, returning whether an anomaly started
+    /// (true) or ended (false). Only slowdowns count, and losses are left
+    /// to the loss figures, so only RTTs are given here.
+    fn update(&mut self, rtt: Duration) -> Option<bool> {
+        let ms = rtt.as_secs_f32() * 1000.0;
+        self.recent.update(ms);
+        self.median.update(ms);
+        self.p95.update(ms);
+        // The baseline doesn't learn from suspect samples, or a slow creep
+        // would be absorbed into it (and its variance) without ever being
+        // flagged
+        let deviations = self.deviations();
+        if deviations.map_or(true, |d| d <= END_DEVIATION) {
+            self.baseline.update(ms);
+            self.frozen = 0;
+        } else {
+            self.frozen += 1;
+            if self.frozen >= RELEARN_SAMPLES {
+                self.baseline = Ewma::new(BASELINE_ALPHA);
+                self.frozen = 0;
+                let ended = self.anomalous;
+                self.anomalous = false;
+                return ended.then_some(false);
+            }
+        }
+
+        let deviations = deviations?;
+        if !self.anomalous && deviations > START_DEVIATION {
+            self.anomalous = true;
+            Some(true)
+        } else if self.anomalous && deviations < END_DEVIATION {
+            self.anomalous = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Standard deviations of the recent mean above the baseline, once
+    /// the baseline is trusted
+    fn deviations(&self) -> Option<f32> {
+        if self.baseline.count() < WARMUP_SAMPLES {
+            return None;
+        }
+        let std_dev = self
+            .baseline
+            .std_dev()
+            .max(self.baseline.mean() * MIN_RELATIVE_STD_DEV)
+            .max(f32::EPSILON);
+        Some((self.recent.mean() - self.baseline.mean()) / std_dev)
+    }
+
+    pub fn is_anomalous(&self) -> bool {
+        self.anomalous
+    }
+
+    fn deviation(&self, target: &str) -> Deviation {
+        let duration = |ms: f32| Duration::from_secs_f32(ms.max(0.0) / 1000.0);
+        Deviation {
+            target: target.to_string(),
+            recent: duration(self.recent.mean()),
+            baseline: duration(self.baseline.mean()),
+            median: duration(self.median.estimate().unwrap_or_default()),
+            p95: duration(self.p95.estimate().unwrap_or_default()),
+            deviations: self.deviations().unwrap_or_default(),
+        }
+    }
+}
+
+/// Learns what normal looks like for the target we ping, and flags when
+/// the last few RTTs are well above it. Only the current target is kept:
+/// changing it starts learning again.
+#[derive(Debug, Clone, Default)]
+pub struct AnomalyDetector {
+    current: Option<(String, TargetStatistics)>,
+}
+
+impl AnomalyDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Learn from a probe result, returning an event if an anomaly started
+    /// or ended
+    pub fn record(&mut self, target: &str, rtt: Option<Duration>) -> Option<AnomalyEvent> {
+        if !matches!(&self.current, Some((name, _)) if name == target) {
+            self.current = Some((target.to_string(), TargetStatistics::default()));
+        }
+        let (_, stats) = self.current.as_mut()?;
+        let started = stats.update(rtt?)?;
+        let deviation = stats.deviation(target);
+        Some(if started {
+            AnomalyEvent::Started(deviation)
+        } else {
+            AnomalyEvent::Ended(deviation)
+        })
+    }
+
+    pub fn target(&self, target: &str) -> Option<&TargetStatistics> {
+        match &self.current {
+            Some((name, stats)) if name == target => Some(stats),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+
+    /// A steady RTT with a little deterministic jitter
+    fn jittery(base: f32, n: u32) -> Duration {
+        let jitter = [0.0, 1.0, -1.0, 2.0, -0.5, 0.5, -2.0, 1.5][n as usize % 8];
+        Duration::from_secs_f32((base + jitter) / 1000.0)
+    }
+
+    #[test]
+    fn ewma_tracks_mean_and_spread() {
+        let mut ewma = Ewma::new(0.1);
+        ewma.update(10.0);
+        assert_eq!((ewma.mean(), ewma.std_dev()), (10.0, 0.0));
+        for n in 0..1000 {
+            ewma.update(if n % 2 == 0 { 8.0 } else { 12.0 });
+        }
+        assert!((ewma.mean() - 10.0).abs() < 0.2);
+        assert!((ewma.std_dev() - 2.0).abs() < 0.2);
+        assert_eq!(ewma.count(), 1001);
+    }
+
+    #[test]
+    fn quantiles_converge() {
+        let (mut median, mut p95) = (Quantile::new(0.5), Quantile::new(0.95));
+        assert_eq!(median.estimate(), None);
+        // 1 to 100 over and over, in a scrambled order
+        for n in 0..20_000u32 {
+            let value = ((n * 37) % 100 + 1) as f32;
+            median.update(value);
+            p95.update(value);
+        }
+        let (median, p95) = (
+            median.estimate().expect("has values"),
+            p95.estimate().expect("has values"),
+        );
+        assert!((median - 50.0).abs() < 5.0, "median {}", median);
+        assert!((p95 - 95.0).abs() < 5.0, "p95 {}", p95);
+    }
+
+    #[test]
+    fn steady_trace_has_no_anomalies() {
+        let mut detector = AnomalyDetector::new();
+        for n in 0..2000 {
+            assert_eq!(detector.record("gw", Some(jittery(20.0, n))), None);
+            // Losses don't count
+            assert_eq!(detector.record("gw", None), None);
+        }
+        let stats = detector.target("gw").expect("target was recorded");
+        let deviation = stats.deviation("gw");
+        assert!((deviation.baseline.as_secs_f32() * 1000.0 - 20.0).abs() < 0.5);
+        assert!(!stats.is_anomalous());
+    }
+
+    #[test]
+    fn flags_gradual_degradation() {
+        let mut detector = AnomalyDetector::new();
+        let mut events = Vec::new();
+        for n in 0..500 {
+            detector.record("gw", Some(jittery(20.0, n)));
+        }
+        // Creeps up by 0.1ms per sample: too slow to trip a jump detector,
+        // and well within the default 100ms healthy maximum at first
+        let mut started_at = None;
+        for n in 0..400 {
+            let rtt = jittery(20.0 + n as f32 * 0.1, n);
+            if let Some(event) = detector.record("gw", Some(rtt)) {
+                started_at.get_or_insert(n);
+                events.push(event);
+            }
+        }
+        let started_at = started_at.expect("should have been flagged");
+        assert!(started_at < 100, "flagged after {} samples", started_at);
+        match &events[0] {
+            AnomalyEvent::Started(deviation) => {
+                assert_eq!(deviation.target, "gw");
+                assert!(deviation.recent > deviation.baseline);
+                assert!(deviation.deviations > START_DEVIATION);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        assert_eq!(events[0].name(), "anomaly_started");
+
+        // Recovery ends it
+        let recovered = (0..100)
+            .filter_map(|n| detector.record("gw", Some(jittery(20.0, n))))
+            .last();
+        assert!(matches!(recovered, Some(AnomalyEvent::Ended(_))));
+        // Another target is learnt from scratch, forgetting the old one
+        assert_eq!(detector.target("dns"), None);
+        assert_eq!(detector.record("dns", Some(jittery(80.0, 0))), None);
+        assert!(detector.target("gw").is_none());
+        let dns = detector.target("dns").expect("target was recorded");
+        assert_eq!(dns.baseline.count(), 1);
+    }
+
+    #[test]
+    fn lasting_change_becomes_normal() {
+        let mut detector = AnomalyDetector::new();
+        for n in 0..500 {
+            detector.record("gw", Some(jittery(20.0, n)));
+        }
+        // A new route, twice as slow
+        let events: Vec<(u32, AnomalyEvent)> = (0..2000)
+            .filter_map(|n| Some((n, detector.record("gw", Some(jittery(40.0, n)))?)))
+            .collect();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert!(matches!(events[0], (n, AnomalyEvent::Started(_)) if n < 20));
+        assert!(matches!(
+            events[1],
+            (n, AnomalyEvent::Ended(_)) if (RELEARN_SAMPLES..RELEARN_SAMPLES + 20).contains(&n)
+        ));
+    }
+}
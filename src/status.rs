@@ -0,0 +1,255 @@
+use smart_leds::RGB8;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Most pixels which can be given over to indicators
+pub const MAX_INDICATORS: usize = 4;
+
+const GREEN: RGB8 = RGB8::new(0, 255, 0);
+const YELLOW: RGB8 = RGB8::new(255, 160, 0);
+const ORANGE: RGB8 = RGB8::new(255, 60, 0);
+const RED: RGB8 = RGB8::new(255, 0, 0);
+const BLUE: RGB8 = RGB8::new(0, 0, 255);
+const AMBER: RGB8 = RGB8::new(255, 100, 0);
+const WHITE: RGB8 = RGB8::new(255, 255, 255);
+const OFF: RGB8 = RGB8::new(0, 0, 0);
+
+/// Weakest WiFi signal shown in each colour, strongest first
+const RSSI_TIERS: [(i32, RGB8); 3] = [(-60, GREEN), (-70, YELLOW), (-80, ORANGE)];
+
+/// Something about the device's health shown on a pixel of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indicator {
+    /// Green, yellow, orange or red by signal strength; blinks red while
+    /// disconnected
+    Wifi,
+    /// Green while connected to the broker, blinks red while not; dark if
+    /// MQTT isn't set up
+    Mqtt,
+    /// Blue once SNTP has set the clock, blinks amber until then
+    Time,
+    /// Flashes white while a settings change is waiting to be published,
+    /// otherwise dark
+    StatePublish,
+}
+
+impl Indicator {
+    pub const ALL: [Indicator; 4] = [
+        Indicator::Wifi,
+        Indicator::Mqtt,
+        Indicator::Time,
+        Indicator::StatePublish,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Indicator::Wifi => "wifi",
+            Indicator::Mqtt => "mqtt",
+            Indicator::Time => "time",
+            Indicator::StatePublish => "state_publish",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|i| i.name() == name)
+    }
+
+    /// The colour at `now` (any steadily increasing time, eg since boot)
+    pub fn colour(&self, status: &DeviceStatus, now: Duration) -> RGB8 {
+        let (colour, period) = match self {
+            Indicator::Wifi => match status.wifi_rssi {
+                Some(rssi) => (wifi_colour(rssi), None),
+                None => (RED, Some(Duration::from_millis(1000))),
+            },
+            Indicator::Mqtt => match status.mqtt_connected {
+                Some(true) => (GREEN, None),
+                Some(false) => (RED, Some(Duration::from_millis(2000))),
+                None => (OFF, None),
+            },
+            Indicator::Time if status.time_synced => (BLUE, None),
+            Indicator::Time => (AMBER, Some(Duration::from_millis(2000))),
+            Indicator::StatePublish if status.publish_pending => {
+                (WHITE, Some(Duration::from_millis(400)))
+            }
+            Indicator::StatePublish => (OFF, None),
+        };
+        match period {
+            // Lit for the first half of each period
+            Some(period) if now.as_millis() % period.as_millis() >= period.as_millis() / 2 => OFF,
+            _ => colour,
+        }
+    }
+}
+
+fn wifi_colour(rssi: i32) -> RGB8 {
+    RSSI_TIERS
+        .iter()
+        .find(|(weakest, _)| rssi >= *weakest)
+        .map_or(RED, |(_, colour)| *colour)
+}
+
+/// Parse a comma separated list of indicators, eg "wifi,mqtt"
+pub fn parse_indicators(s: &str) -> Option<Vec<Indicator>> {
+    let indicators = s
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(Indicator::from_name)
+        .collect::<Option<Vec<_>>>()?;
+    (indicators.len() <= MAX_INDICATORS).then_some(indicators)
+}
+
+pub fn format_indicators(indicators: &[Indicator]) -> String {
+    indicators
+        .iter()
+        .map(|i| i.name())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// What the indicators show, gathered by the firmware each loop
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /// Signal strength, or None while the WiFi is disconnected
+    pub wifi_rssi: Option<i32>,
+    /// Whether we are connected to the broker, or None without MQTT
+    pub mqtt_connected: Option<bool>,
+    pub time_synced: bool,
+    /// Whether a settings change hasn't been published to MQTT yet, read
+    /// before `periodic_publish` so each change shows for at least a loop.
+    /// Settings kept across restarts are stored before the change is
+    /// flagged, so there is no save to wait for.
+    pub publish_pending: bool,
+}
+
+/// Show `indicators` on the last pixels of `pixels`, in order, after the
+/// area `render` left for the history; only the first ones fit if there
+/// are more indicators than pixels
+pub fn overlay(
+    indicators: &[Indicator],
+    status: &DeviceStatus,
+    now: Duration,
+    pixels: &mut [RGB8],
+) {
+    let first = pixels.len().saturating_sub(indicators.len());
+    for (pixel, indicator) in pixels[first..].iter_mut().zip(indicators) {
+        *pixel = indicator.colour(status, now);
+    }
+}
+
+/// Whether `pending` is set, for `DeviceStatus::publish_pending`
+pub fn is_pending(pending: &Mutex<bool>) -> bool {
+    pending.lock().is_ok_and(|pending| *pending)
+}
+
+/// Run `publish` if `pending` is set, clearing it only if that succeeds so
+/// the change is tried again (and stays on the indicator) otherwise. The
+/// flag isn't held while publishing, as handlers set it with other locks
+/// held.
+pub fn publish_if_pending<E>(
+    pending: &Mutex<bool>,
+    publish: impl FnOnce() -> Result<(), E>,
+) -> Result<(), E> {
+    match pending.lock() {
+        Ok(mut pending) if *pending => *pending = false,
+        _ => return Ok(()),
+    }
+    publish().inspect_err(|_| {
+        if let Ok(mut pending) = pending.lock() {
+            *pending = true;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test_status {
+    use super::*;
+
+    #[test]
+    fn wifi_tiers_and_blinking() {
+        let at = |rssi| DeviceStatus {
+            wifi_rssi: rssi,
+            ..Default::default()
+        };
+        let now = Duration::ZERO;
+        assert_eq!(Indicator::Wifi.colour(&at(Some(-55)), now), GREEN);
+        assert_eq!(Indicator::Wifi.colour(&at(Some(-60)), now), GREEN);
+        assert_eq!(Indicator::Wifi.colour(&at(Some(-75)), now), ORANGE);
+        assert_eq!(Indicator::Wifi.colour(&at(Some(-90)), now), RED);
+        let offline = at(None);
+        assert_eq!(Indicator::Wifi.colour(&offline, now), RED);
+        assert_eq!(
+            Indicator::Wifi.colour(&offline, Duration::from_millis(600)),
+            OFF
+        );
+        assert_eq!(
+            Indicator::Wifi.colour(&offline, Duration::from_millis(1200)),
+            RED
+        );
+    }
+
+    #[test]
+    fn overlays_the_last_pixels() {
+        let status = DeviceStatus {
+            wifi_rssi: Some(-65),
+            mqtt_connected: None,
+            time_synced: true,
+            publish_pending: true,
+        };
+        let mut pixels = vec![WHITE; 5];
+        let indicators = [Indicator::Mqtt, Indicator::Time, Indicator::Wifi];
+        overlay(&indicators, &status, Duration::ZERO, &mut pixels);
+        assert_eq!(pixels, [WHITE, WHITE, OFF, BLUE, YELLOW]);
+        // More indicators than pixels shows the first ones
+        let mut pixels = vec![OFF; 1];
+        overlay(&indicators[1..], &status, Duration::ZERO, &mut pixels);
+        assert_eq!(pixels, [BLUE]);
+        let mut pixels = vec![OFF; 1];
+        overlay(
+            &[Indicator::StatePublish],
+            &status,
+            Duration::from_millis(300),
+            &mut pixels,
+        );
+        assert_eq!(pixels, [OFF]);
+    }
+
+    #[test]
+    fn shows_changes_until_published() {
+        let pending = Mutex::new(false);
+        let flash = |pending: &Mutex<bool>| {
+            let status = DeviceStatus {
+                publish_pending: is_pending(pending),
+                ..Default::default()
+            };
+            Indicator::StatePublish.colour(&status, Duration::ZERO)
+        };
+        assert_eq!(publish_if_pending(&pending, || Err("unreachable")), Ok(()));
+        assert_eq!(flash(&pending), OFF);
+        // As in the main loop, the status is read before publishing
+        *pending.lock().expect("lock") = true;
+        assert_eq!(flash(&pending), WHITE);
+        assert_eq!(
+            publish_if_pending(&pending, || Err("offline")),
+            Err("offline")
+        );
+        assert_eq!(flash(&pending), WHITE);
+        assert_eq!(publish_if_pending(&pending, || Ok::<_, ()>(())), Ok(()));
+        assert_eq!(flash(&pending), OFF);
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(
+            parse_indicators(" wifi, state_publish"),
+            Some(vec![Indicator::Wifi, Indicator::StatePublish])
+        );
+        assert_eq!(parse_indicators(""), Some(vec![]));
+        assert_eq!(parse_indicators("wifi,battery"), None);
+        assert_eq!(parse_indicators("wifi,wifi,wifi,wifi,wifi"), None);
+        assert_eq!(
+            format_indicators(&[Indicator::Time, Indicator::Mqtt]),
+            "time,mqtt"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_ping_leds::config::{Config, ConfigError, ConfigKey};
+use esp_ping_leds::crash::CrashReport;
+use esp_ping_leds::provisioning::ProvisioningSettings;
+use esp_ping_leds::wifi::{self, WifiNetwork};
+
+const NAMESPACE: &str = "ping_leds";
+const KEY_WIFI_NETWORKS: &str = "wifi_networks";
+const KEY_WIFI_FAILURES: &str = "wifi_failures";
+const KEY_MQTT_URL: &str = "mqtt_url";
+const KEY_PING_HOST: &str = "ping_host";
+const KEY_LED_COUNT: &str = "led_count";
+const KEY_OTA_LATEST_TOPIC: &str = "ota_topic";
+const KEY_BOOT_COUNT: &str = "boot_count";
+const KEY_CRASH_REPORT: &str = "crash_report";
+const KEY_CRASH_SENT: &str = "crash_sent";
+const KEY_UNSTABLE_BOOTS: &str = "unstable_boots";
+const KEY_COLOUR_STOPS: &str = "colour_stops";
+const KEY_LED_OFFSET: &str = "led_offset";
+const KEY_LED_REVERSE: &str = "led_reverse";
+const KEY_LED_ROTATION: &str = "led_rotation";
+const KEY_LED_SKIP: &str = "led_skip";
+const KEY_LED_SEGMENTS: &str = "led_segments";
+const KEY_STATUS_PIXELS: &str = "status_pixels";
+const KEY_POWER_BUDGET: &str = "power_budget";
+const KEY_MATRIX_WIDTH: &str = "matrix_width";
+const KEY_MATRIX_WIRING: &str = "matrix_wiring";
+const KEY_DIAL_SPAN: &str = "dial_span";
+const KEY_UTC_OFFSET: &str = "utc_offset";
+
+/// Settings changed over MQTT or HTTP which are kept across restarts, with
+/// their NVS keys; the LED count comes before the layout and matrix, which
+/// depend on it
+const PERSISTED: [(ConfigKey, &str); 14] = [
+    (ConfigKey::PingHost, KEY_PING_HOST),
+    (ConfigKey::LedCount, KEY_LED_COUNT),
+    (ConfigKey::ColourStops, KEY_COLOUR_STOPS),
+    (ConfigKey::LedOffset, KEY_LED_OFFSET),
+    (ConfigKey::LedReverse, KEY_LED_REVERSE),
+    (ConfigKey::LedRotation, KEY_LED_ROTATION),
+    (ConfigKey::LedSkip, KEY_LED_SKIP),
+    (ConfigKey::LedSegments, KEY_LED_SEGMENTS),
+    (ConfigKey::StatusPixels, KEY_STATUS_PIXELS),
+    (ConfigKey::PowerBudget, KEY_POWER_BUDGET),
+    (ConfigKey::MatrixWidth, KEY_MATRIX_WIDTH),
+    (ConfigKey::MatrixWiring, KEY_MATRIX_WIRING),
+    (ConfigKey::DialSpan, KEY_DIAL_SPAN),
+    (ConfigKey::UtcOffset, KEY_UTC_OFFSET),
+];
+
+/// Settings which persist across reboots, stored in NVS
+pub struct Storage {
+    nvs: EspNvs<NvsDefault>,
+    safe_mode: bool,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+            safe_mode: false,
+        })
+    }
+
+    fn get_string(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match self.nvs.str_len(key)? {
+            Some(len) => {
+                let mut buf = vec![0u8; len];
+                Ok(self.nvs.get_str(key, &mut buf)?.map(|s| s.to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_optional_string(&mut self, key: &str, value: Option<&str>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => self.nvs.set_str(key, value)?,
+            None => {
+                self.nvs.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Known WiFi networks, in priority order
+    pub fn wifi_networks(&self) -> anyhow::Result<Vec<WifiNetwork>> {
+        match self.get_string(KEY_WIFI_NETWORKS)? {
+            Some(data) => Ok(wifi::decode_networks(&data)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn set_wifi_networks(&mut self, networks: &[WifiNetwork]) -> anyhow::Result<()> {
+        self.nvs
+            .set_str(KEY_WIFI_NETWORKS, &wifi::encode_networks(networks))?;
+        Ok(())
+    }
+
+    /// Number of boots in a row which failed to connect to WiFi
+    pub fn wifi_failures(&self) -> anyhow::Result<u32> {
+        Ok(self.nvs.get_u32(KEY_WIFI_FAILURES)?.unwrap_or(0))
+    }
+
+    pub fn set_wifi_failures(&mut self, failures: u32) -> anyhow::Result<()> {
+        self.nvs.set_u32(KEY_WIFI_FAILURES, failures)?;
+        Ok(())
+    }
+
+    pub fn mqtt_url(&self) -> anyhow::Result<Option<String>> {
+        self.get_string(KEY_MQTT_URL)
+    }
+
+    pub fn ping_host(&self) -> anyhow::Result<Option<String>> {
+        self.get_string(KEY_PING_HOST)
+    }
+
+    pub fn led_count(&self) -> anyhow::Result<Option<u32>> {
+        Ok(self.nvs.get_u32(KEY_LED_COUNT)?)
+    }
+
+    /// MQTT topic where the newest firmware manifest is published
+    pub fn ota_latest_topic(&self) -> anyhow::Result<Option<String>> {
+        self.get_string(KEY_OTA_LATEST_TOPIC)
+    }
+
+    pub fn set_ota_latest_topic(&mut self, topic: Option<&str>) -> anyhow::Result<()> {
+        self.set_optional_string(KEY_OTA_LATEST_TOPIC, topic)
+    }
+
+    /// Number of times we have booted, including this one
+    pub fn boot_count(&self) -> anyhow::Result<u32> {
+        Ok(self.nvs.get_u32(KEY_BOOT_COUNT)?.unwrap_or(0))
+    }
+
+    /// Count this boot, returning its number
+    pub fn increment_boot_count(&mut self) -> anyhow::Result<u32> {
+        let count = self.boot_count()?.wrapping_add(1);
+        self.nvs.set_u32(KEY_BOOT_COUNT, count)?;
+        Ok(count)
+    }
+
+    /// The most recent crash, if there has been one
+    pub fn crash_report(&self) -> anyhow::Result<Option<CrashReport>> {
+        Ok(self
+            .get_string(KEY_CRASH_REPORT)?
+            .and_then(|data| CrashReport::decode(&data)))
+    }
+
+    pub fn set_crash_report(&mut self, report: &CrashReport) -> anyhow::Result<()> {
+        self.nvs.set_str(KEY_CRASH_REPORT, &report.encode())?;
+        Ok(())
+    }
+
+    /// Boot number of the last crash report which was published, so each
+    /// one is only published once
+    pub fn crash_report_sent(&self) -> anyhow::Result<Option<u32>> {
+        Ok(self.nvs.get_u32(KEY_CRASH_SENT)?)
+    }
+
+    pub fn set_crash_report_sent(&mut self, boot: u32) -> anyhow::Result<()> {
+        self.nvs.set_u32(KEY_CRASH_SENT, boot)?;
+        Ok(())
+    }
+
+    /// Number of boots in a row which restarted before becoming stable
+    pub fn unstable_boots(&self) -> anyhow::Result<u32> {
+        Ok(self.nvs.get_u32(KEY_UNSTABLE_BOOTS)?.unwrap_or(0))
+    }
+
+    pub fn set_unstable_boots(&mut self, boots: u32) -> anyhow::Result<()> {
+        self.nvs.set_u32(KEY_UNSTABLE_BOOTS, boots)?;
+        Ok(())
+    }
+
+    /// In safe mode the running config is left at the defaults, and changes
+    /// only edit the stored settings, which apply after the next restart
+    pub fn set_safe_mode(&mut self, safe_mode: bool) {
+        self.safe_mode = safe_mode;
+    }
+
+    fn stored_setting(&self, key: ConfigKey, nvs_key: &str) -> anyhow::Result<Option<String>> {
+        match key {
+            // Stored as a number by the provisioning portal
+            ConfigKey::LedCount => Ok(self.led_count()?.map(|count| count.to_string())),
+            _ => self.get_string(nvs_key),
+        }
+    }
+
+    fn store_setting(&mut self, key: ConfigKey, nvs_key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            ConfigKey::LedCount => self.nvs.set_u32(nvs_key, value.parse()?)?,
+            _ => self.nvs.set_str(nvs_key, value)?,
+        }
+        Ok(())
+    }
+
+    /// Apply the stored settings on top of `config`, skipping any which are
+    /// no longer valid
+    pub fn load_config(&self, config: &mut Config) {
+        for (key, nvs_key) in PERSISTED {
+            match self.stored_setting(key, nvs_key) {
+                Ok(Some(value)) => {
+                    if let Err(e) = config.set(key, &value) {
+                        log::warn!("Ignoring stored {}: {}", key.name(), e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Failed to load {}: {}", key.name(), e),
+            }
+        }
+    }
+
+    /// Apply settings changed over MQTT or HTTP, storing the ones which are
+    /// kept across restarts. Only settings whose value actually changed are
+    /// written, so defaults such as the gateway ping host never end up in
+    /// NVS.
+    pub fn update_config<K: AsRef<str>, V: AsRef<str>>(
+        &mut self,
+        config: &mut Config,
+        values: &[(K, V)],
+    ) -> Result<(), ConfigError> {
+        let before = if self.safe_mode {
+            let mut stored = Config::default();
+            self.load_config(&mut stored);
+            stored
+        } else {
+            config.clone()
+        };
+        let mut updated = before.clone();
+        updated.set_all(values)?;
+        for (key, nvs_key) in PERSISTED {
+            let value = updated.get(key);
+            if value != before.get(key) {
+                if let Err(e) = self.store_setting(key, nvs_key, &value) {
+                    log::error!("Failed to store {}: {}", key.name(), e);
+                }
+            }
+        }
+        if !self.safe_mode {
+            *config = updated;
+        }
+        Ok(())
+    }
+
+    /// Everything which can be set from the provisioning portal
+    pub fn provisioning_settings(&self) -> anyhow::Result<ProvisioningSettings> {
+        Ok(ProvisioningSettings {
+            networks: self.wifi_networks()?,
+            mqtt_url: self.mqtt_url()?,
+            ping_host: self.ping_host()?,
+            led_count: self.led_count()?,
+        })
+    }
+
+    pub fn set_provisioning_settings(
+        &mut self,
+        settings: &ProvisioningSettings,
+    ) -> anyhow::Result<()> {
+        self.set_wifi_networks(&settings.networks)?;
+        self.set_optional_string(KEY_MQTT_URL, settings.mqtt_url.as_deref())?;
+        self.set_optional_string(KEY_PING_HOST, settings.ping_host.as_deref())?;
+        match settings.led_count {
+            Some(count) => self.nvs.set_u32(KEY_LED_COUNT, count)?,
+            None => {
+                self.nvs.remove(KEY_LED_COUNT)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use esp_idf_svc::sys::{
+    esp, esp_chip_info, esp_chip_info_t, esp_get_free_heap_size, esp_get_minimum_free_heap_size,
+    esp_netif_get_handle_from_ifkey, esp_netif_get_ip_info, esp_netif_ip_info_t, esp_reset_reason,
+    esp_timer_get_time, esp_wifi_sta_get_ap_info, uxTaskGetStackHighWaterMark, wifi_ap_record_t,
+};
+use esp_ping_leds::crash::CrashReport;
+use esp_ping_leds::diagnostics::{Diagnostics, ResetReason};
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Firmware version, as compared with the "latest" release
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short commit hash of the build, from build.rs
+const GIT_HASH: &str = env!("GIT_HASH");
+const MCU: Option<&str> = std::option_env!("MCU");
+/// 2024-01-01; the clock starts at 1970 until SNTP sets it
+const EARLIEST_WALL_CLOCK: Duration = Duration::from_secs(1_704_067_200);
+
+/// Version plus commit, eg "0.2.0 (1a2b3c4)"
+pub fn sw_version() -> String {
+    format!("{} ({})", VERSION, GIT_HASH)
+}
+
+/// Chip model and revision, eg "ESP32C3 rev v0.4"
+pub fn hw_version() -> String {
+    let mut info: esp_chip_info_t = Default::default();
+    unsafe { esp_chip_info(&mut info) };
+    format!(
+        "{} rev v{}.{}",
+        MCU.unwrap_or("ESP32").to_uppercase(),
+        info.revision / 100,
+        info.revision % 100
+    )
+}
+
+/// Details of the access point we are connected to, or None if the station
+/// isn't connected
+fn wifi_ap_info() -> Option<wifi_ap_record_t> {
+    let mut ap_info: wifi_ap_record_t = Default::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+    Some(ap_info)
+}
+
+/// A NUL-padded SSID from an access point record
+fn ssid(raw: &[u8]) -> String {
+    let len = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..len]).into_owned()
+}
+
+/// Signal strength of the access point we are connected to
+pub fn wifi_rssi() -> Option<i32> {
+    wifi_ap_info().map(|ap_info| ap_info.rssi as i32)
+}
+
+/// Our address on the WiFi network, if we have one
+fn wifi_ip() -> Option<Ipv4Addr> {
+    let netif = unsafe { esp_netif_get_handle_from_ifkey(c"WIFI_STA_DEF".as_ptr()) };
+    if netif.is_null() {
+        return None;
+    }
+    let mut ip_info: esp_netif_ip_info_t = Default::default();
+    esp!(unsafe { esp_netif_get_ip_info(netif, &mut ip_info) }).ok()?;
+    // Stored in network byte order
+    Some(Ipv4Addr::from(ip_info.ip.addr.to_le_bytes())).filter(|ip| !ip.is_unspecified())
+}
+
+pub fn free_heap() -> u32 {
+    unsafe { esp_get_free_heap_size() }
+}
+
+/// Time since boot
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+}
+
+/// Time since the Unix epoch, or None if SNTP hasn't set the clock yet
+pub fn wall_clock() -> Option<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .filter(|now| *now >= EARLIEST_WALL_CLOCK)
+}
+
+pub fn reset_reason() -> ResetReason {
+    ResetReason::from_code(unsafe { esp_reset_reason() })
+}
+
+/// Collect device health; the stack figure is for the calling task, so
+/// this should be called from the main loop
+pub fn diagnostics(
+    boot_count: u32,
+    last_crash: Option<&CrashReport>,
+    safe_mode: bool,
+    led_current: Option<u32>,
+) -> Diagnostics {
+    let ap_info = wifi_ap_info();
+    Diagnostics {
+        uptime: uptime(),
+        wifi_ssid: ap_info.map(|ap_info| ssid(&ap_info.ssid)),
+        wifi_rssi: ap_info.map(|ap_info| ap_info.rssi as i32),
+        wifi_bssid: ap_info.map(|ap_info| ap_info.bssid),
+        wifi_channel: ap_info.map(|ap_info| ap_info.primary),
+        ip_address: wifi_ip(),
+        free_heap: free_heap(),
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        // ESP-IDF measures stacks in bytes rather than words
+        stack_high_water: unsafe { uxTaskGetStackHighWaterMark(std::ptr::null_mut()) },
+        reset_reason: reset_reason(),
+        boot_count,
+        last_crash: last_crash.map(|report| report.summary()),
+        safe_mode,
+        led_current,
+    }
+}
@@ -0,0 +1,349 @@
+use crate::history::History;
+use crate::matrix::{Framebuffer, Wiring};
+use smart_leds::RGB8;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Height of every glyph
+pub const FONT_HEIGHT: usize = 5;
+/// How fast text moves across the matrix, in pixels a second
+const SCROLL_SPEED: f32 = 16.0;
+/// Longest message kept; anything after is cut off
+pub const MAX_MESSAGE_LEN: usize = 64;
+/// Most messages waiting to be shown; older ones are dropped
+pub const MAX_QUEUED: usize = 4;
+const TEXT_COLOUR: RGB8 = RGB8::new(255, 255, 255);
+
+/// A small bitmap font, upper case only: each glyph is its rows, top
+/// first, with '#' for lit pixels
+const GLYPHS: [(char, [&str; FONT_HEIGHT]); 47] = [
+    ('0', ["###", "#.#", "#.#", "#.#", "###"]),
+    ('1', [".#.", "##.", ".#.", ".#.", "###"]),
+    ('2', ["###", "..#", "###", "#..", "###"]),
+    ('3', ["###", "..#", ".##", "..#", "###"]),
+    ('4', ["#.#", "#.#", "###", "..#", "..#"]),
+    ('5', ["###", "#..", "###", "..#", "###"]),
+    ('6', ["###", "#..", "###", "#.#", "###"]),
+    ('7', ["###", "..#", "..#", ".#.", ".#."]),
+    ('8', ["###", "#.#", "###", "#.#", "###"]),
+    ('9', ["###", "#.#", "###", "..#", "###"]),
+    ('A', [".#.", "#.#", "###", "#.#", "#.#"]),
+    ('B', ["##.", "#.#", "##.", "#.#", "##."]),
+    ('C', [".##", "#..", "#..", "#..", ".##"]),
+    ('D', ["##.", "#.#", "#.#", "#.#", "##."]),
+    ('E', ["###", "#..", "##.", "#..", "###"]),
+    ('F', ["###", "#..", "##.", "#..", "#.."]),
+    ('G', [".##", "#..", "#.#", "#.#", ".##"]),
+    ('H', ["#.#", "#.#", "###", "#.#", "#.#"]),
+    ('I', ["###", ".#.", ".#.", ".#.", "###"]),
+    ('J', ["..#", "..#", "..#", "#.#", ".#."]),
+    ('K', ["#.#", "#.#", "##.", "#.#", "#.#"]),
+    ('L', ["#..", "#..", "#..", "#..", "###"]),
+    ('M', ["#...#", "##.##", "#.#.#", "#...#", "#...#"]),
+    ('N', ["#..#", "##.#", "#.##", "#..#", "#..#"]),
+    ('O', [".#.", "#.#", "#.#", "#.#", ".#."]),
+    ('P', ["##.", "#.#", "##.", "#..", "#.."]),
+    ('Q', [".#.", "#.#", "#.#", "##.", ".##"]),
+    ('R', ["##.", "#.#", "##.", "#.#", "#.#"]),
+    ('S', [".##", "#..", ".#.", "..#", "##."]),
+    ('T', ["###", ".#.", ".#.", ".#.", ".#."]),
+    ('U', ["#.#", "#.#", "#.#", "#.#", "###"]),
+    ('V', ["#.#", "#.#", "#.#", "#.#", ".#."]),
+    ('W', ["#...#", "#...#", "#.#.#", "##.##", "#...#"]),
+    ('X', ["#.#", "#.#", ".#.", "#.#", "#.#"]),
+    ('Y', ["#.#", "#.#", ".#.", ".#.", ".#."]),
+    ('Z', ["###", "..#", ".#.", "#..", "###"]),
+    (' ', ["..", "..", "..", "..", ".."]),
+    ('.', [".", ".", ".", ".", "#"]),
+    (',', ["..", "..", "..", ".#", "#."]),
+    (':', [".", "#", ".", "#", "."]),
+    ('!', ["#", "#", "#", ".", "#"]),
+    ('?', ["###", "..#", ".#.", "...", ".#."]),
+    ('-', ["...", "...", "###", "...", "..."]),
+    ('+', ["...", ".#.", "###", ".#.", "..."]),
+    ('%', ["#.#", "..#", ".#.", "#..", "#.#"]),
+    ('/', ["..#", "..#", ".#.", "#..", "#.."]),
+    ('\'', ["#", "#", ".", ".", "."]),
+];
+
+/// The glyph for `c`, ignoring case; '?' for characters the font doesn't
+/// have
+fn glyph(c: char) -> &'static [&'static str; FONT_HEIGHT] {
+    let c = c.to_ascii_uppercase();
+    GLYPHS
+        .iter()
+        .find(|(g, _)| *g == c)
+        .or_else(|| GLYPHS.iter().find(|(g, _)| *g == '?'))
+        .map(|(_, rows)| rows)
+        .expect("font has '?'")
+}
+
+/// Width of `text` in pixels, with a blank column between glyphs
+pub fn text_width(text: &str) -> usize {
+    let glyphs: usize = text.chars().map(|c| glyph(c)[0].len()).sum();
+    glyphs + text.chars().count().saturating_sub(1)
+}
+
+/// Draw `text` with its top left at (`x`, `y`), which may be off the
+/// image; pixels outside it are clipped
+pub fn draw_text(image: &mut Framebuffer, text: &str, x: i32, y: i32, colour: RGB8) {
+    let mut left = x;
+    for c in text.chars() {
+        let rows = glyph(c);
+        for (dy, row) in rows.iter().enumerate() {
+            for (dx, pixel) in row.chars().enumerate() {
+                let (px, py) = (left + dx as i32, y + dy as i32);
+                if pixel == '#' && px >= 0 && py >= 0 {
+                    image.set(px as usize, py as usize, colour);
+                }
+            }
+        }
+        left += rows[0].len() as i32 + 1;
+    }
+}
+
+/// Eg "35s", "4m" or "2h"
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+/// A short summary of the latest pings: how long the outage has lasted if
+/// the latest was lost, otherwise the latest RTT, plus the share lost
+/// across the history if any were
+pub fn status_text(history: &History, now: Duration) -> Option<String> {
+    let latest = history.latest()?;
+    if latest.rtt.is_none() {
+        // Since the last reply, or as far back as we know
+        let since = history
+            .iter()
+            .find(|s| s.rtt.is_some())
+            .or_else(|| history.iter().last())
+            .map_or(latest.at, |s| s.at);
+        return Some(format!("OFFLINE {}", format_age(now.saturating_sub(since))));
+    }
+    let rtt = latest.rtt.map_or(0, |rtt| rtt.as_millis());
+    let (sent, lost) = history.iter().fold((0, 0), |(sent, lost), s| {
+        (sent + 1, lost + s.rtt.is_none() as usize)
+    });
+    Some(if lost == 0 {
+        format!("{}ms", rtt)
+    } else {
+        // Any loss at all shows as at least 1%
+        format!("{}ms LOSS {}%", rtt, (lost * 100 / sent).max(1))
+    })
+}
+
+/// Text scrolling across a matrix, over whatever it shows: messages pushed
+/// from outside, and every so often `status_text`
+#[derive(Debug, Clone, Default)]
+pub struct TextLayer {
+    queue: VecDeque<String>,
+    /// The text being shown and when it started
+    current: Option<(String, Duration)>,
+    /// When the status was last shown
+    status_at: Option<Duration>,
+}
+
+impl TextLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a message, to show after the current text
+    pub fn push(&mut self, message: &str) {
+        let message: String = message.trim().chars().take(MAX_MESSAGE_LEN).collect();
+        if message.is_empty() {
+            return;
+        }
+        if self.queue.len() == MAX_QUEUED {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(message);
+    }
+
+    /// Whether text is scrolling, so frames should be drawn often
+    pub fn is_active(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Finish text once it has scrolled off a matrix `width` wide, and
+    /// start the next: queued messages first, then the status if it is due
+    /// (never, if `status_interval` is zero)
+    pub fn update(
+        &mut self,
+        now: Duration,
+        width: usize,
+        history: &History,
+        status_interval: Duration,
+    ) {
+        if let Some((text, started)) = &self.current {
+            let distance = (width + text_width(text)) as f32;
+            if now.saturating_sub(*started).as_secs_f32() * SCROLL_SPEED < distance {
+                return;
+            }
+            self.current = None;
+        }
+        if let Some(message) = self.queue.pop_front() {
+            self.current = Some((message, now));
+            return;
+        }
+        let due = self
+            .status_at
+            .map_or(true, |at| now.saturating_sub(at) >= status_interval);
+        if !status_interval.is_zero() && due {
+            if let Some(status) = status_text(history, now) {
+                self.current = Some((status, now));
+                self.status_at = Some(now);
+            }
+        }
+    }
+
+    /// Draw the current text onto `pixels`, a matrix `width` wide wired as
+    /// `wiring`, on a darkened band so it stands out from what's behind
+    pub fn composite(&self, now: Duration, pixels: &mut [RGB8], width: usize, wiring: Wiring) {
+        let Some((text, started)) = &self.current else {
+            return;
+        };
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        if height < FONT_HEIGHT {
+            return;
+        }
+        let mut image = Framebuffer::from_strip(&pixels[..width * height], width, wiring);
+        let top = (height - FONT_HEIGHT) / 2;
+        let band = top.saturating_sub(1)..(top + FONT_HEIGHT + 1).min(height);
+        for y in band {
+            for x in 0..width {
+                if let Some(pixel) = image.get(x, y) {
+                    image.set(x, y, RGB8::new(pixel.r / 4, pixel.g / 4, pixel.b / 4));
+                }
+            }
+        }
+        let scrolled = (now.saturating_sub(*started).as_secs_f32() * SCROLL_SPEED) as i32;
+        draw_text(
+            &mut image,
+            text,
+            width as i32 - scrolled,
+            top as i32,
+            TEXT_COLOUR,
+        );
+        pixels[..width * height].copy_from_slice(&image.to_strip(wiring));
+    }
+}
+
+#[cfg(test)]
+mod test_text {
+    use super::*;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn lit(pixel: RGB8) -> char {
+        if pixel == TEXT_COLOUR {
+            '#'
+        } else {
+            '.'
+        }
+    }
+
+    #[test]
+    fn draws_clipped_text() {
+        let mut image = Framebuffer::new(10, 5);
+        draw_text(&mut image, "23ms", 0, 0, TEXT_COLOUR);
+        assert_eq!(text_width("23ms"), 17);
+        assert_eq!(
+            image.to_text(lit),
+            "###.###.#.\n\
+             ..#...#.##\n\
+             ###..##.#.\n\
+             #.....#.#.\n\
+             ###.###.#.\n"
+        );
+        let mut image = Framebuffer::new(4, 5);
+        draw_text(&mut image, "é1", -2, 0, TEXT_COLOUR);
+        assert_eq!(image.to_text(lit), "#..#\n#.##\n...#\n...#\n..##\n");
+    }
+
+    #[test]
+    fn summarises_status() {
+        let mut history = History::new(10);
+        assert_eq!(status_text(&history, secs(0)), None);
+        history.push(secs(0), ms(23));
+        assert_eq!(status_text(&history, secs(0)).as_deref(), Some("23ms"));
+        for i in 1..8 {
+            history.push(secs(i * 10), if i == 4 { None } else { ms(40) });
+        }
+        assert_eq!(
+            status_text(&history, secs(70)).as_deref(),
+            Some("40ms LOSS 12%")
+        );
+        history.push(secs(80), None);
+        history.push(secs(90), None);
+        assert_eq!(
+            status_text(&history, secs(310)).as_deref(),
+            Some("OFFLINE 4m")
+        );
+    }
+
+    #[test]
+    fn scrolls_messages_then_status() {
+        let mut history = History::new(4);
+        history.push(secs(0), ms(7));
+        let mut layer = TextLayer::new();
+        layer.push("  hi ");
+        layer.update(secs(0), 8, &history, secs(60));
+        assert!(layer.is_active());
+
+        // Starts just off the right, then moves left
+        let mut pixels = vec![RGB8::new(40, 40, 40); 8 * 9];
+        layer.composite(secs(0), &mut pixels, 8, Wiring::Progressive);
+        let image = Framebuffer::from_strip(&pixels, 8, Wiring::Progressive);
+        assert_eq!(image.get(0, 0), Some(RGB8::new(40, 40, 40)));
+        assert_eq!(image.get(0, 1), Some(RGB8::new(10, 10, 10)));
+        assert!(!pixels.contains(&TEXT_COLOUR));
+        let mut pixels = vec![RGB8::default(); 8 * 9];
+        layer.composite(
+            secs(0) + Duration::from_millis(500),
+            &mut pixels,
+            8,
+            Wiring::Progressive,
+        );
+        let image = Framebuffer::from_strip(&pixels, 8, Wiring::Progressive);
+        assert_eq!(
+            image.to_text(lit),
+            "........\n\
+             ........\n\
+             #.#.###.\n\
+             #.#..#..\n\
+             ###..#..\n\
+             #.#..#..\n\
+             #.#.###.\n\
+             ........\n\
+             ........\n"
+        );
+
+        // Once it has gone, the status is due
+        layer.update(secs(1), 8, &history, secs(60));
+        layer.update(secs(2), 8, &history, secs(60));
+        assert_eq!(layer.current.as_ref().map(|(t, _)| t.as_str()), Some("7ms"));
+        layer.update(secs(4), 8, &history, secs(60));
+        assert!(!layer.is_active());
+        layer.update(secs(60), 8, &history, secs(60));
+        assert!(!layer.is_active());
+        layer.update(secs(61), 8, &history, secs(60));
+        assert!(layer.is_active());
+        // Or never
+        let mut layer = TextLayer::new();
+        layer.update(secs(0), 8, &history, Duration::ZERO);
+        assert!(!layer.is_active());
+    }
+}
@@ -0,0 +1,186 @@
+use esp_idf_svc::{
+    http::{
+        client::{Configuration as HttpConfiguration, EspHttpConnection},
+        Method,
+    },
+    ota::{EspOta, SlotState},
+    sys::esp_crt_bundle_attach,
+};
+use esp_ping_leds::ota::{ImageVerifier, Manifest};
+use serde::Deserialize;
+use std::time::Duration;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest manifest we will accept
+const MAX_MANIFEST_LEN: usize = 1024;
+
+/// Where an update was requested from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtaRequest {
+    /// Fetch the manifest from this URL first
+    ManifestUrl(String),
+    /// A manifest we already have, eg from the "latest" topic
+    Manifest(Manifest),
+}
+
+/// Progress of an update, as published over MQTT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtaStatus {
+    Downloading {
+        version: String,
+        progress: u8,
+    },
+    /// Written and verified, about to reboot into the new image
+    Installed {
+        version: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl OtaStatus {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            OtaStatus::Downloading { version, progress } => serde_json::json!({
+                "state": "downloading",
+                "version": version,
+                "progress": progress,
+            }),
+            OtaStatus::Installed { version } => serde_json::json!({
+                "state": "installed",
+                "version": version,
+                "progress": 100,
+            }),
+            OtaStatus::Failed { error } => serde_json::json!({
+                "state": "failed",
+                "error": error,
+            }),
+        }
+    }
+}
+
+fn connect(url: &str) -> anyhow::Result<EspHttpConnection> {
+    let mut conn = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    conn.initiate_request(Method::Get, url, &[])?;
+    conn.initiate_response()?;
+    if conn.status() != 200 {
+        anyhow::bail!("GET {} returned HTTP {}", url, conn.status());
+    }
+    Ok(conn)
+}
+
+/// Validate a release manifest in its JSON form
+pub fn parse_manifest(json: &[u8]) -> anyhow::Result<Manifest> {
+    #[derive(Deserialize)]
+    struct ManifestJson {
+        version: String,
+        url: String,
+        size: u32,
+        sha256: String,
+    }
+
+    let json = serde_json::from_slice::<ManifestJson>(json)
+        .map_err(|e| anyhow::anyhow!("Invalid manifest: {}", e))?;
+    Ok(Manifest::new(
+        &json.version,
+        &json.url,
+        json.size,
+        &json.sha256,
+    )?)
+}
+
+/// Download and validate a release manifest
+pub fn fetch_manifest(url: &str) -> anyhow::Result<Manifest> {
+    let mut conn = connect(url)?;
+    let mut body = vec![];
+    let mut buf = [0u8; 256];
+    loop {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+        if body.len() > MAX_MANIFEST_LEN {
+            anyhow::bail!("Manifest is too large");
+        }
+    }
+    parse_manifest(&body)
+}
+
+/// Stream the image described by `manifest` into the inactive slot, and
+/// set it as the boot slot if it matches the manifest. `progress` is called
+/// with the percentage downloaded each time it changes.
+pub fn install(manifest: &Manifest, mut progress: impl FnMut(u8)) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut verifier = ImageVerifier::new(manifest);
+
+    let result = (|| -> anyhow::Result<()> {
+        let mut conn = connect(&manifest.url)?;
+        let mut buf = [0u8; 1024];
+        let mut last_progress = 0;
+        loop {
+            let n = conn.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            verifier.update(&buf[..n])?;
+            update.write(&buf[..n])?;
+            if verifier.progress_percent() != last_progress {
+                last_progress = verifier.progress_percent();
+                progress(last_progress);
+            }
+        }
+        Ok(())
+    })()
+    .and_then(|_| Ok(verifier.finish()?));
+
+    match result {
+        Ok(_) => {
+            update.complete()?;
+            log::info!("Firmware {} installed", manifest.version);
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(abort_err) = update.abort() {
+                log::warn!("Failed to abort update: {}", abort_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Whether we are running a freshly installed image which hasn't been
+/// confirmed to work yet. If we restart before confirming it, the bootloader
+/// goes back to the previous image.
+pub fn running_image_unverified() -> bool {
+    match EspOta::new().and_then(|ota| ota.get_running_slot()) {
+        Ok(slot) => slot.state == SlotState::Unverified,
+        Err(e) => {
+            log::warn!("Failed to read OTA slot state: {}", e);
+            false
+        }
+    }
+}
+
+/// Confirm that the running image works, cancelling the rollback
+pub fn mark_valid() -> anyhow::Result<()> {
+    EspOta::new()?.mark_running_slot_valid()?;
+    log::info!("Firmware marked valid");
+    Ok(())
+}
+
+/// Mark the running image as bad and reboot into the previous one; only
+/// returns if that fails
+pub fn rollback() -> anyhow::Error {
+    log::error!("Firmware failed verification, rolling back");
+    match EspOta::new() {
+        Ok(mut ota) => ota.mark_running_slot_invalid_and_reboot().into(),
+        Err(e) => e.into(),
+    }
+}
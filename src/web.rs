@@ -0,0 +1,122 @@
+use crate::config::Config;
+use crate::history::History;
+use smart_leds::RGB8;
+use std::time::Duration;
+
+/// Render the on-device status page
+///
+/// # Arguments
+///
+/// * `history` - Recent samples, newest first
+/// * `config` - Current settings
+/// * `pixels` - One colour per LED, as shown on the strip
+/// * `now` - Current time since boot, to show sample ages
+pub fn render_status_page(
+    history: &History,
+    config: &Config,
+    pixels: &[RGB8],
+    now: Duration,
+) -> String {
+    let rtt = match history.latest() {
+        Some(sample) => match sample.rtt {
+            Some(rtt) => format!("{}ms", rtt.as_millis()),
+            None => "lost".to_string(),
+        },
+        None => "no data yet".to_string(),
+    };
+    let age = match history.latest() {
+        Some(sample) => format!("{}s ago", now.saturating_sub(sample.at).as_secs()),
+        None => String::new(),
+    };
+
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <meta http-equiv=\"refresh\" content=\"10\">\
+         <title>Ping LEDs</title>\
+         <style>body{font-family:sans-serif;max-width:40em;margin:auto;padding:1em}\
+         .strip{display:flex;height:2em;background:#000;padding:2px;gap:2px}\
+         .strip div{flex:1}td{padding-right:1em}</style></head><body>\
+         <h1>Ping LEDs</h1><div class=\"strip\">",
+    );
+    for pixel in pixels {
+        html += &format!(
+            "<div style=\"background:#{:02x}{:02x}{:02x}\"></div>",
+            pixel.r, pixel.g, pixel.b
+        );
+    }
+    html += "</div><table>";
+    for (name, value) in [
+        ("Target", html_escape(&config.ping_host)),
+        ("Latest", format!("{} {}", rtt, age)),
+        ("Loss", format!("{:.1}%", history.loss_percent())),
+        (
+            "Sent / lost since boot",
+            format!("{} / {}", history.sent(), history.lost()),
+        ),
+        (
+            "Healthy",
+            format!(
+                "{}-{}ms",
+                config.min_healthy_duration.as_millis(),
+                config.max_healthy_duration.as_millis()
+            ),
+        ),
+        (
+            "Strip",
+            format!(
+                "{} LEDs over {}m",
+                config.led_count,
+                config.led_strip_duration.as_secs() / 60
+            ),
+        ),
+    ] {
+        html += &format!("<tr><td>{}</td><td>{}</td></tr>", name, value);
+    }
+    html += "</table><p><a href=\"/api/status\">status</a> \
+             <a href=\"/api/history\">history</a> \
+             <a href=\"/api/config\">config</a> \
+             <a href=\"/metrics\">metrics</a></p></body></html>";
+    html
+}
+
+pub(crate) fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod test_web {
+    use super::*;
+
+    #[test]
+    fn shows_latest_sample_and_strip() {
+        let mut history = History::new(4);
+        history.push(Duration::from_secs(10), None);
+        history.push(Duration::from_secs(70), Some(Duration::from_millis(23)));
+        let page = render_status_page(
+            &history,
+            &Config::default(),
+            &[RGB8::new(0, 255, 0), RGB8::new(0x12, 0x34, 0x56)],
+            Duration::from_secs(75),
+        );
+        assert!(page.contains("23ms 5s ago"));
+        assert!(page.contains("50.0%"));
+        assert!(page.contains("background:#00ff00"));
+        assert!(page.contains("background:#123456"));
+    }
+
+    #[test]
+    fn handles_no_data() {
+        let config = Config {
+            ping_host: "<script>".to_string(),
+            ..Default::default()
+        };
+        let page = render_status_page(&History::new(4), &config, &[], Duration::ZERO);
+        assert!(page.contains("no data yet"));
+        assert!(page.contains("&lt;script&gt;"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+
+/// Maximum number of WiFi networks that can be stored
+pub const MAX_NETWORKS: usize = 8;
+
+/// Credentials for a single known WiFi network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WifiError {
+    /// SSIDs must be 1-32 bytes
+    InvalidSsid(String),
+    /// Passwords must be empty (open network) or 8-64 bytes
+    InvalidPassword(String),
+    /// More than MAX_NETWORKS were given
+    TooManyNetworks(usize),
+    /// The stored network list could not be decoded
+    Corrupt(String),
+}
+
+impl fmt::Display for WifiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiError::InvalidSsid(ssid) => write!(f, "invalid SSID {:?}", ssid),
+            WifiError::InvalidPassword(ssid) => write!(f, "invalid password for {:?}", ssid),
+            WifiError::TooManyNetworks(n) => {
+                write!(f, "{} networks given, at most {} allowed", n, MAX_NETWORKS)
+            }
+            WifiError::Corrupt(line) => write!(f, "corrupt network entry {:?}", line),
+        }
+    }
+}
+
+impl std::error::Error for WifiError {}
+
+impl WifiNetwork {
+    /// Create a new set of credentials, checking that they fit in the
+    /// ESP-IDF client configuration
+    pub fn new(ssid: &str, password: &str) -> Result<Self, WifiError> {
+        if ssid.is_empty() || ssid.len() > 32 {
+            return Err(WifiError::InvalidSsid(ssid.to_string()));
+        }
+        if !password.is_empty() && (password.len() < 8 || password.len() > 64) {
+            return Err(WifiError::InvalidPassword(ssid.to_string()));
+        }
+        Ok(Self {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Pick the order in which known networks should be tried
+///
+/// # Arguments
+///
+/// * `known` - Known networks, in priority order
+/// * `visible` - (SSID, RSSI) pairs from a WiFi scan
+///
+/// # Returns
+///
+/// Networks which were seen in the scan, strongest signal first (ties are
+/// broken by priority), followed by networks which were not seen (they may
+/// be hidden) in priority order.
+pub fn rank_networks<'a>(known: &'a [WifiNetwork], visible: &[(&str, i8)]) -> Vec<&'a WifiNetwork> {
+    let best_rssi = |ssid: &str| {
+        visible
+            .iter()
+            .filter(|(s, _)| *s == ssid)
+            .map(|(_, rssi)| *rssi)
+            .max()
+    };
+
+    let mut seen: Vec<(i8, &WifiNetwork)> = known
+        .iter()
+        .filter_map(|n| best_rssi(&n.ssid).map(|rssi| (rssi, n)))
+        .collect();
+    // Sorting is stable, so equal signals keep their priority order
+    seen.sort_by_key(|(rssi, _)| std::cmp::Reverse(*rssi));
+
+    seen.into_iter()
+        .map(|(_, n)| n)
+        .chain(known.iter().filter(|n| best_rssi(&n.ssid).is_none()))
+        .collect()
+}
+
+/// Serialise a network list for storage, one `ssid\tpassword` line per network
+pub fn encode_networks(networks: &[WifiNetwork]) -> String {
+    networks
+        .iter()
+        .map(|n| format!("{}\t{}", escape(&n.ssid), escape(&n.password)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Parse a network list produced by `encode_networks`
+pub fn decode_networks(data: &str) -> Result<Vec<WifiNetwork>, WifiError> {
+    let networks = data
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (ssid, password) = line
+                .split_once('\t')
+                .ok_or_else(|| WifiError::Corrupt(line.to_string()))?;
+            WifiNetwork::new(&unescape(ssid), &unescape(password))
+        })
+        .collect::<Result<Vec<WifiNetwork>, WifiError>>()?;
+    if networks.len() > MAX_NETWORKS {
+        return Err(WifiError::TooManyNetworks(networks.len()));
+    }
+    Ok(networks)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test_wifi {
+    use super::*;
+
+    fn net(ssid: &str) -> WifiNetwork {
+        WifiNetwork::new(ssid, "password123").expect("valid test network")
+    }
+
+    #[test]
+    fn rejects_bad_credentials() {
+        assert!(WifiNetwork::new("", "").is_err());
+        assert!(WifiNetwork::new(&"x".repeat(33), "").is_err());
+        assert!(WifiNetwork::new("office", "short").is_err());
+        assert!(WifiNetwork::new("office", "").is_ok());
+    }
+
+    #[test]
+    fn ranks_by_signal_then_priority() {
+        let known = vec![net("home"), net("office"), net("lab"), net("hidden")];
+        let visible = [("lab", -40), ("office", -70), ("home", -70), ("cafe", -20)];
+        let ranked: Vec<&str> = rank_networks(&known, &visible)
+            .iter()
+            .map(|n| n.ssid.as_str())
+            .collect();
+        assert_eq!(ranked, vec!["lab", "home", "office", "hidden"]);
+    }
+
+    #[test]
+    fn uses_strongest_bssid_for_each_ssid() {
+        let known = vec![net("office"), net("lab")];
+        let visible = [("office", -80), ("lab", -60), ("office", -50)];
+        let ranked: Vec<&str> = rank_networks(&known, &visible)
+            .iter()
+            .map(|n| n.ssid.as_str())
+            .collect();
+        assert_eq!(ranked, vec!["office", "lab"]);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let networks = vec![
+            WifiNetwork::new("tab\tssid", "back\\slash\\").expect("valid"),
+            WifiNetwork::new("open", "").expect("valid"),
+        ];
+        let decoded = decode_networks(&encode_networks(&networks)).expect("decodes");
+        assert_eq!(decoded, networks);
+    }
+
+    #[test]
+    fn decoding_rejects_garbage() {
+        assert_eq!(
+            decode_networks("no tab here"),
+            Err(WifiError::Corrupt("no tab here".to_string()))
+        );
+        assert_eq!(decode_networks(""), Ok(vec![]));
+    }
+}